@@ -48,14 +48,41 @@ try:
     while True:
         f = urllib.request.urlopen("http://127.0.0.1:3000")
         json_data = f.read().decode("utf-8")
-        data_dict = json.loads(json_data)
+        probes = json.loads(json_data)['probes']
 
-        draw.text((x, y), "Temp: " + str(data_dict['temp']), font=font, fill="#FFFFFF")
-        coords = "X: " + str(x) + " Y: " + str(y) + " Status: " + str(data_dict['status'])
-        draw.text((0, 10), coords, font=font, fill="#FFFFFF")
-        if data_dict['s3']:
-            s3_text = "S3 Status: " + str(data_dict['s3'])
-            draw.text((0, 30), s3_text, font=font, fill="#FFFFFF")
+        # The screen only fits one probe so show the first one
+        if not probes:
+            draw.text((0, 10), "Status: discovering", font=font, fill="#FFFFFF")
+        else:
+            serial = sorted(probes)[0]
+            data_dict = probes[serial]
+            draw.text((x, y), "Temp: " + str(data_dict['temp']), font=font, fill="#FFFFFF")
+            coords = "X: " + str(x) + " Y: " + str(y) + " Status: " + str(data_dict['status'])
+            draw.text((0, 10), coords, font=font, fill="#FFFFFF")
+            if data_dict['s3']:
+                s3_text = "S3 Status: " + str(data_dict['s3'])
+                draw.text((0, 30), s3_text, font=font, fill="#FFFFFF")
+            if len(probes) > 1:
+                draw.text((0, 50), "Probe " + serial + " of " + str(len(probes)), font=font, fill="#FFFFFF")
+            link = data_dict.get('link') or {}
+            if link.get('weak'):
+                draw.text((0, 70), "Weak signal, move the Pi closer", font=font, fill="#FFFF00")
+            elif link.get('rssi') is not None:
+                draw.text((0, 70), "Signal: " + str(link['rssi']) + " dBm", font=font, fill="#FFFFFF")
+            eta = data_dict.get('eta')
+            stall = data_dict.get('stall') or {}
+            carryover = data_dict.get('carryover') or {}
+            if carryover.get('peaked'):
+                draw.text((0, 240), "Peaked at " + str(round(carryover['peak'])) + "F, rested", font=font, fill="#00FF00")
+            elif carryover.get('resting'):
+                peak = carryover.get('peak')
+                draw.text((0, 240), "Resting, peak " + (str(round(peak)) + "F" if peak is not None else "?"), font=font, fill="#FFFFFF")
+            elif stall.get('stalled'):
+                draw.text((0, 240), "Stalled " + str(stall['seconds'] // 60) + " min", font=font, fill="#FFFF00")
+            elif eta:
+                draw.text((0, 240), "Done in " + str(round(eta['seconds_remaining'] / 60)) + " min", font=font, fill="#FFFFFF")
+            if data_dict.get('battery') == 'low':
+                draw.text((0, 260), "Battery low, charge the probe", font=font, fill="#FF0000")
         display.image(image, 180)
         draw.rectangle((0, 0, 240, 320), outline=0, fill=0)
         if buttonA.value and not buttonB.value:
@@ -0,0 +1,97 @@
+// Things that happen during a cook that someone should hear about, like a probe's battery running
+// low. They're logged, kept with the probe for the status JSON and uploaded with its metadata.
+use chrono::prelude::*;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+// Only the most recent alerts are kept per probe
+const MAX_ALERTS: usize = 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AlertKind {
+    LowBattery,
+    StallStarted,
+    StallEnded,
+    RemovedFromHeat,
+    CarryoverPeaked,
+}
+
+impl std::fmt::Display for AlertKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let value = match self {
+            AlertKind::LowBattery => "low_battery",
+            AlertKind::StallStarted => "stall_started",
+            AlertKind::StallEnded => "stall_ended",
+            AlertKind::RemovedFromHeat => "removed_from_heat",
+            AlertKind::CarryoverPeaked => "carryover_peaked",
+        };
+        write!(f, "{}", value)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Alert {
+    /// When it happened, which is the time of the reading for anything worked out from readings
+    pub time: DateTime<Utc>,
+    pub kind: AlertKind,
+    pub message: String,
+    /// How long it went on for, for things that have ended
+    pub duration: Option<Duration>,
+}
+
+impl Alert {
+    pub fn new(kind: AlertKind, message: String) -> Alert {
+        Alert::at(Utc::now(), kind, message)
+    }
+
+    pub fn at(time: DateTime<Utc>, kind: AlertKind, message: String) -> Alert {
+        Alert { time, kind, message, duration: None }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "time": self.time.to_rfc3339_opts(SecondsFormat::Millis, true),
+            "kind": self.kind.to_string(),
+            "message": self.message,
+            "seconds": self.duration.map(|d| d.as_secs()),
+        })
+    }
+}
+
+/// A probe's most recent alerts, oldest first
+#[derive(Debug, Default)]
+pub struct Alerts {
+    alerts: VecDeque<Alert>,
+}
+
+impl Alerts {
+    pub fn push(&mut self, alert: Alert) {
+        if self.alerts.len() == MAX_ALERTS {
+            self.alerts.pop_front();
+        }
+        self.alerts.push_back(alert);
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        self.alerts.iter().map(Alert::to_json).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_most_recent() {
+        let mut alerts = Alerts::default();
+        for i in 0..MAX_ALERTS + 2 {
+            alerts.push(Alert::new(AlertKind::LowBattery, format!("Alert {}", i)));
+        }
+        let json = alerts.to_json();
+        let json = json.as_array().unwrap();
+        assert_eq!(json.len(), MAX_ALERTS);
+        assert_eq!(json[0]["message"], "Alert 2");
+        assert_eq!(json[MAX_ALERTS - 1]["message"], format!("Alert {}", MAX_ALERTS + 1));
+        assert_eq!(json[0]["kind"], "low_battery");
+    }
+}
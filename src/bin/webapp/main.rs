@@ -3,7 +3,7 @@ use aws_sdk_s3::Client;
 use log::{error, info};
 use chrono::prelude::*;
 use handlebars::Handlebars;
-use std::collections::BTreeMap;
+use serde_json::json;
 use std::sync::{Arc, Mutex};
 
 mod s3;
@@ -13,26 +13,134 @@ fn as_farenheit(c: f32) -> f32 {
     c * 1.8 + 32.0
 }
 
+/// Describes the probe's link from its metadata, e.g. "-70 dBm (min -78, avg -72), 5% of reads failing"
+fn describe_link(link: &serde_json::Value) -> Option<String> {
+    let rssi = link["rssi"].as_i64()?;
+    let mut description = format!("{} dBm", rssi);
+    if let (Some(min), Some(avg)) = (link["min_rssi"].as_i64(), link["avg_rssi"].as_f64()) {
+        description += &format!(" (min {}, avg {:.0})", min, avg);
+    }
+    if let Some(rate) = link["failure_rate"].as_f64() {
+        description += &format!(", {:.0}% of reads failing", rate * 100.0);
+    }
+    Some(description)
+}
+
+/// Describes the ETA from the probe's metadata, e.g. "Done in 42 minutes (35 to 55) at 14:05 UTC"
+fn describe_eta(eta: &serde_json::Value) -> Option<String> {
+    let minutes = |s: &serde_json::Value| s.as_u64().map(|s| (s + 30) / 60);
+    let remaining = minutes(&eta["seconds_remaining"])?;
+    if remaining == 0 {
+        return Some("Done".to_string());
+    }
+    let mut description = format!("Done in {} minutes", remaining);
+    if let Some(low) = minutes(&eta["seconds_remaining_low"]) {
+        match minutes(&eta["seconds_remaining_high"]) {
+            Some(high) => description += &format!(" ({} to {})", low, high),
+            None => description += &format!(" (at least {})", low),
+        }
+    }
+    if let Some(done_at) = eta["done_at"].as_str().and_then(|t| DateTime::parse_from_rfc3339(t).ok()) {
+        description += &format!(" at {}", done_at.format("%H:%M UTC"));
+    }
+    Some(description)
+}
+
+/// Describes a stall from the probe's metadata, e.g. "Stalled at 160°F for 1h05m, since 14:05 UTC"
+fn describe_stall(stall: &serde_json::Value) -> Option<String> {
+    let hm = |s: u64| format!("{}h{:02}m", s / 3600, s / 60 % 60);
+    let time = |t: &serde_json::Value| t.as_str()
+        .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+        .map(|t| t.format("%H:%M UTC").to_string())
+        .unwrap_or_default();
+    if stall["stalled"] == true {
+        return Some(format!(
+            "Stalled at {:.0}°F for {}, since {}",
+            stall["temp"].as_f64()?, hm(stall["seconds"].as_u64()?), time(&stall["started_at"]),
+        ));
+    }
+    let last = &stall["last"];
+    Some(format!("Stalled for {} from {} to {}", hm(last["seconds"].as_u64()?), time(&last["started_at"]), time(&last["ended_at"])))
+}
+
+/// Describes resting from the probe's metadata, e.g. "Off the heat at 14:05 UTC, should peak at
+/// 205°F around 14:20 UTC"
+fn describe_carryover(carryover: &serde_json::Value) -> Option<String> {
+    let time = |t: &serde_json::Value| t.as_str()
+        .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+        .map(|t| t.format("%H:%M UTC").to_string());
+    let removed_at = time(&carryover["removed_at"])?;
+    let peak = carryover["peak"].as_f64().zip(time(&carryover["peak_at"]));
+    Some(match peak {
+        Some((peak, at)) if carryover["peaked"] == true => format!("Off the heat at {}, peaked at {:.0}°F at {}, done resting", removed_at, peak, at),
+        Some((peak, at)) => format!("Off the heat at {}, should peak at {:.0}°F around {}", removed_at, peak, at),
+        None => format!("Off the heat at {}, no peak estimate yet", removed_at),
+    })
+}
+
 #[get("/")]
 async fn index(data: web::Data<Arc<Mutex<State>>>) -> actix_web::Result<HttpResponse> {
-    let update = {
+    let updates = {
         let data = data.lock().unwrap();
-        if data.last_update.is_none() {
+        if data.last_updates.is_empty() {
             return Err(error::ErrorInternalServerError(anyhow::anyhow!("No update")));
         }
-        data.last_update.clone().unwrap()
+        data.last_updates.clone()
     };
 
     let hb = Handlebars::new();
-    let mut data = BTreeMap::new();
-    data.insert("temperature".to_string(), format!("{}°F", as_farenheit(update.temp)));
-    data.insert("last_update".to_string(), update.time.to_rfc3339_opts(SecondsFormat::Millis, true));
-    data.insert("since".to_string(), format!("{} minutes ago", Utc::now().signed_duration_since(update.time).num_minutes()));
+    let probes: Vec<serde_json::Value> = updates.iter().map(|(serial, update)| {
+        let sensors: Vec<String> = update.sensors
+            .iter()
+            .enumerate()
+            .map(|(i, t)| format!("T{} {:.1}°F", i + 1, as_farenheit(*t)))
+            .collect();
+        let link = update.metadata.as_ref().map(|m| &m["link"]);
+        let battery = update.metadata.as_ref().and_then(|m| m["battery"].as_str());
+        // Newest first
+        let alerts: Vec<String> = update.metadata
+            .as_ref()
+            .and_then(|m| m["alerts"].as_array())
+            .map(|alerts| alerts
+                .iter()
+                .rev()
+                .map(|a| format!("{} {}", a["time"].as_str().unwrap_or_default(), a["message"].as_str().unwrap_or_default()))
+                .collect())
+            .unwrap_or_default();
+        // e.g. "Core T3, surface T5 120.2°F, ambient T8 225.0°F"
+        let virtual_sensors = update.virtual_sensors.map(|[core, surface, ambient]| {
+            let temp = |i: usize| update.sensors.get(i).map_or(String::new(), |t| format!(" {:.1}°F", as_farenheit(*t)));
+            format!("Core T{}, surface T{}{}, ambient T{}{}", core + 1, surface + 1, temp(surface), ambient + 1, temp(ambient))
+        });
+        let target = update.metadata.as_ref().and_then(|m| m["target"].as_f64());
+        let eta = update.metadata.as_ref().map(|m| &m["eta"]).and_then(describe_eta);
+        let stall = update.metadata.as_ref().map(|m| &m["stall"]);
+        let carryover = update.metadata.as_ref().map(|m| &m["carryover"]);
+        json!({
+            "serial": serial,
+            "virtual_sensors": virtual_sensors,
+            "target": target.map(|t| format!("{:.0}°F", t)),
+            "eta": eta,
+            "stalled": stall.is_some_and(|s| s["stalled"] == true),
+            "stall": stall.and_then(describe_stall),
+            "resting": carryover.is_some_and(|c| c["resting"] == true),
+            "carryover": carryover.and_then(describe_carryover),
+            "battery": battery,
+            "low_battery": battery == Some("low"),
+            "alerts": alerts,
+            "weak_signal": link.is_some_and(|l| l["weak"] == true),
+            "signal": link.and_then(describe_link),
+            "temperature": format!("{}°F", as_farenheit(update.temp)),
+            "sensors": sensors,
+            "last_update": update.time.to_rfc3339_opts(SecondsFormat::Millis, true),
+            "since": format!("{} minutes ago", Utc::now().signed_duration_since(update.time).num_minutes()),
+        })
+    }).collect();
     let templ = include_str!("static/index.html.tmpl");
 
     Ok(HttpResponse::Ok()
         .content_type(ContentType::html())
-        .body(hb.render_template(templ, &data).map_err(|e| error::ErrorInternalServerError(e))?))
+        .body(hb.render_template(templ, &json!({ "probes": probes })).map_err(|e| error::ErrorInternalServerError(e))?))
 }
 
 
@@ -45,12 +153,13 @@ async fn health() -> actix_web::Result<HttpResponse> {
 
 #[derive(Default, Debug)]
 struct State {
-    last_update: Option<LastUpdate>,
+    /// Latest reading per probe serial in the most recent cook
+    last_updates: Vec<(String, LastUpdate)>,
 }
 
 impl State {
-    fn set_update(&mut self, update: LastUpdate) {
-        self.last_update.replace(update);
+    fn set_updates(&mut self, updates: Vec<(String, LastUpdate)>) {
+        self.last_updates = updates;
     }
 }
 
@@ -74,11 +183,11 @@ async fn main() -> std::io::Result<()> {
             loop {
                 tokio::select! {
                     _ = interval.tick() => {
-                        let update = get_last_update(&client, &bucket).await;
-                        match update {
+                        let updates = get_last_updates(&client, &bucket).await;
+                        match updates {
                             Err(e) => error!("Error updating last temperature: {e:?}"),
                             Ok(u) => {
-                                state.lock().unwrap().set_update(u);
+                                state.lock().unwrap().set_updates(u);
                                 info!("Updated");
                             }
                         }
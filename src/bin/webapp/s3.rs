@@ -1,22 +1,53 @@
 use aws_sdk_s3::Client;
+use aws_sdk_s3::operation::get_object::GetObjectOutput;
 use anyhow::{anyhow, bail};
+use log::warn;
 use chrono::prelude::*;
 use bytes::{BytesMut};
 
 #[derive(Clone, Default, Debug)]
 pub struct LastUpdate {
+    /// The core, or T1 when the probe didn't say which sensor that is
     pub temp: f32,
     pub time: DateTime<FixedOffset>,
+    /// T1 (tip) through T8 (handle), empty for sessions recorded before all sensors were pushed
+    pub sensors: Vec<f32>,
+    /// Indexes into `sensors` of the core, surface and ambient the probe picked, if it said
+    pub virtual_sensors: Option<[usize; 3]>,
+    /// The probe's metadata.json, None for sessions recorded before there was one
+    pub metadata: Option<serde_json::Value>,
 }
 
-pub async fn get_last_update(client: &Client, bucket: &str) -> anyhow::Result<LastUpdate> {
+/// The latest reading of every probe in the most recent cook, keyed by probe serial. Sessions
+/// recorded before multiple probes were supported have a single unnamed probe.
+pub async fn get_last_updates(client: &Client, bucket: &str) -> anyhow::Result<Vec<(String, LastUpdate)>> {
     // Get the last folder which is the latest cook
     let dir = match get_dir(client, bucket).await? {
         Some(d) => d,
         None => bail!("No directories in {}", bucket)
     };
 
-    let obj = match get_last_obj(client, bucket, &dir).await? {
+    let mut probes: Vec<(String, String)> = list_dirs(client, bucket, Some(&dir))
+        .await?
+        .into_iter()
+        .map(|p| {
+            let serial = p.trim_start_matches(&dir).trim_end_matches('/').to_string();
+            (serial, p)
+        })
+        .collect();
+    if probes.is_empty() {
+        probes.push((String::new(), dir.clone()));
+    }
+
+    let mut updates = vec![];
+    for (serial, dir) in probes {
+        updates.push((serial, get_last_update(client, bucket, &dir).await?));
+    }
+    Ok(updates)
+}
+
+async fn get_last_update(client: &Client, bucket: &str, dir: &str) -> anyhow::Result<LastUpdate> {
+    let obj = match get_last_obj(client, bucket, dir).await? {
         Some(o) => o,
         None => bail!("No objects in {}/{}", bucket, dir)
     };
@@ -26,26 +57,75 @@ pub async fn get_last_update(client: &Client, bucket: &str) -> anyhow::Result<La
         .next()
         .ok_or(anyhow!("nothing in this file"))?;
     let parts: Vec<&str> = first.split(",").collect();
-    if parts.len() != 2 {
-        bail!("expected 2 parts got {}: {}", parts.len(), contents);
+    if parts.len() != 2 && parts.len() != 10 && parts.len() != 13 {
+        bail!("expected 2, 10 or 13 parts got {}: {}", parts.len(), contents);
     }
     let temp = parts[0].parse::<f32>()?;
     let dt = chrono::DateTime::parse_from_rfc3339(parts[1])?;
+    let sensors = parts[2..parts.len().min(10)]
+        .iter()
+        .map(|p| p.parse::<f32>())
+        .collect::<Result<Vec<f32>, _>>()?;
+    // Sensor numbers, 3 for T3
+    let virtual_sensors = match parts.get(10..) {
+        Some([core, surface, ambient]) => {
+            let index = |n: &str| match n.parse::<usize>() {
+                Ok(n @ 1..=8) => Ok(n - 1),
+                _ => Err(anyhow!("bad sensor number {:?}", n)),
+            };
+            Some([index(core)?, index(surface)?, index(ambient)?])
+        },
+        _ => None,
+    };
 
-    Ok(LastUpdate{temp: temp, time: dt})
+    let metadata = get_metadata(client, bucket, dir).await;
+
+    Ok(LastUpdate{temp, time: dt, sensors, virtual_sensors, metadata})
+}
+
+/// The probe's metadata.json. It's only extra detail on the page, so anything wrong with it is
+/// logged and the page goes without.
+async fn get_metadata(client: &Client, bucket: &str, dir: &str) -> Option<serde_json::Value> {
+    let key = format!("{}metadata.json", dir);
+    let response = match client.get_object().bucket(bucket.to_owned()).key(key.clone()).send().await {
+        Ok(r) => r,
+        Err(e) => {
+            let e = e.into_service_error();
+            if !e.is_no_such_key() {
+                warn!("Failed reading {}: {:?}", key, e);
+            }
+            return None;
+        }
+    };
+    let parsed = read_body(response)
+        .await
+        .and_then(|contents| Ok(serde_json::from_str(&contents)?));
+    match parsed {
+        Ok(metadata) => Some(metadata),
+        Err(e) => {
+            warn!("Ignoring unreadable {}: {:?}", key, e);
+            None
+        }
+    }
 }
 
 async fn get_dir(client: &Client, bucket: &str) -> anyhow::Result<Option<String>> {
-    // List objects in the bucket with "/" as the delimiter to find the top level directories
-    let mut dir = None;
+    // Get the last directory in the list as RFC3339 should sort these lexicographically
+    Ok(list_dirs(client, bucket, None).await?.pop())
+}
+
+/// Lists the directories directly under the prefix, or the top level ones without one
+async fn list_dirs(client: &Client, bucket: &str, prefix: Option<&str>) -> anyhow::Result<Vec<String>> {
+    // List objects in the bucket with "/" as the delimiter to find the directories
+    let mut dirs = vec![];
     let mut response = client
         .list_objects_v2()
         .bucket(bucket.to_owned())
+        .set_prefix(prefix.map(|p| p.to_owned()))
         .delimiter("/")
         .into_paginator()
         .send();
 
-    // Get the last directory in the list as RFC3339 should sort these lexicographically
     while let Some(result) = response.next().await {
         let response = match result {
             Ok(r) => r,
@@ -54,30 +134,27 @@ async fn get_dir(client: &Client, bucket: &str) -> anyhow::Result<Option<String>
             }
         };
 
-        // Common prefixes with delimiter "/" returns the top level dirs
-        let dirs = response.common_prefixes();
-        if dirs.len() == 0 {
-            break;
-        }
-        let d = &dirs[dirs.len()-1];
-        dir = d.prefix.clone();
+        // Common prefixes with delimiter "/" are the dirs
+        dirs.extend(response.common_prefixes().iter().filter_map(|d| d.prefix.clone()));
     }
 
-    Ok(dir)
+    Ok(dirs)
 }
 
 async fn get_last_obj(client: &Client, bucket: &str, dir: &str) -> anyhow::Result<Option<String>> {
-    // Get the objects in the bucket with the directory prefix
-    let mut last = None;
+    // Get the objects directly in the directory
+    let mut last: Option<(u32, String)> = None;
     let mut response = client
         .list_objects_v2()
         .bucket(bucket.to_owned())
         .prefix(dir.to_owned())
+        .delimiter("/")
         .into_paginator()
         .send();
 
 
-    // Get the last one (has the most recent update)
+    // Get the highest numbered one (has the most recent update). Keys aren't zero padded so
+    // 10.csv sorts before 9.csv.
     while let Some(result) = response.next().await {
         let response = match result {
             Ok(r) => r,
@@ -86,21 +163,32 @@ async fn get_last_obj(client: &Client, bucket: &str, dir: &str) -> anyhow::Resul
             }
         };
 
-        let objs = response.contents();
-        last = objs[objs.len()-1].key.clone();
+        for key in response.contents().iter().filter_map(|o| o.key.as_deref()) {
+            let n = key.trim_start_matches(dir)
+                .strip_suffix(".csv")
+                .and_then(|n| n.parse::<u32>().ok());
+            if let Some(n) = n {
+                if last.as_ref().is_none_or(|(l, _)| n > *l) {
+                    last = Some((n, key.to_string()));
+                }
+            }
+        }
     }
 
-    Ok(last)
+    Ok(last.map(|(_, key)| key))
 }
 
 async fn read_obj(client: &Client, bucket: &str, key: &str) -> anyhow::Result<String> {
-    let mut response = client
+    let response = client
         .get_object()
         .bucket(bucket.to_owned())
         .key(key.to_owned())
         .send()
         .await?;
+    read_body(response).await
+}
 
+async fn read_body(mut response: GetObjectOutput) -> anyhow::Result<String> {
     let mut bs = BytesMut::new();
     while let Some(bytes) = response.body.try_next().await? {
         bs.extend_from_slice(&bytes.to_vec())
@@ -0,0 +1,279 @@
+// Carryover cooking once the meat comes off the heat, when the air around it cools but the heat
+// still in the outside keeps pushing the core up for a while
+//
+// Coming off the heat shows up as the ambient sensor dropping fast, along with the surface when the
+// probe says which sensor that is. From then on how quickly the core's climb is slowing down says
+// how high it will peak and when, and the peak itself is the core turning back down.
+use chrono::prelude::*;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use crate::combustion::ProbeReading;
+use crate::fit::Line;
+
+// How far the ambient drops, and how quickly, when the meat comes off the heat. It has to climb
+// as far again to count as going back on.
+const REMOVAL_DROP_C: f32 = 20.0;
+const REMOVAL_WINDOW: Duration = Duration::from_secs(5 * 60);
+// The surface has to be cooling too, when the probe says which sensor it is
+const SURFACE_DROP_C: f32 = 1.0;
+// The peak is fitted over this much of the core's climb, and not from less than this
+const FIT_WINDOW: Duration = Duration::from_secs(5 * 60);
+const MIN_FIT_SPAN: Duration = Duration::from_secs(2 * 60);
+const MIN_FIT_SAMPLES: usize = 10;
+// Climbing slower than this is as good as peaked
+const PEAK_RATE_C_PER_MIN: f32 = 0.05;
+// Don't predict a peak further out than this
+const MAX_PEAK_AHEAD: Duration = Duration::from_secs(60 * 60);
+// How far the core falls from its highest for that to have been the peak
+const PEAK_DROP_C: f32 = 0.3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CarryoverEvent {
+    /// When the ambient started dropping, and the core then
+    Removed { at: DateTime<Utc>, core_c: f32 },
+    /// When the core was highest, and how high
+    Peaked { at: DateTime<Utc>, core_c: f32 },
+}
+
+/// Where resting is up to, all None while the meat is cooking
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CarryoverSummary {
+    pub removed_at: Option<DateTime<Utc>>,
+    /// The highest the core has been since, and when
+    pub max: Option<(DateTime<Utc>, f32)>,
+    /// When the core should peak and how high, while it's still climbing
+    pub estimate: Option<(DateTime<Utc>, f32)>,
+    /// The core has turned down, so `max` is the peak
+    pub peaked: bool,
+}
+
+impl CarryoverSummary {
+    pub fn resting(&self) -> bool {
+        self.removed_at.is_some()
+    }
+
+    pub fn to_json(self) -> serde_json::Value {
+        let time = |t: DateTime<Utc>| t.to_rfc3339_opts(SecondsFormat::Secs, true);
+        let peak = if self.peaked { self.max } else { self.estimate };
+        serde_json::json!({
+            "resting": self.resting(),
+            "removed_at": self.removed_at.map(time),
+            "peaked": self.peaked,
+            "peak": peak.map(|(_, c)| crate::as_farenheit(c)),
+            "peak_at": peak.map(|(t, _)| time(t)),
+        })
+    }
+}
+
+/// One reading's worth of what the detector looks at
+#[derive(Clone, Copy, Debug)]
+struct Sample {
+    time: DateTime<Utc>,
+    core: f32,
+    air: f32,
+    surface: Option<f32>,
+}
+
+/// Watches for the meat coming off the heat and follows the core until it peaks
+#[derive(Debug, Default)]
+pub struct CarryoverDetector {
+    /// The last REMOVAL_WINDOW of readings, in time order
+    samples: VecDeque<Sample>,
+    summary: CarryoverSummary,
+    /// The coolest the air has been since the meat came off the heat
+    min_air: f32,
+}
+
+impl CarryoverDetector {
+    pub fn summary(&self) -> CarryoverSummary {
+        self.summary
+    }
+
+    /// Adds a reading, returning whether the meat came off the heat or the core peaked with it.
+    /// Readings older than the newest, like ones backfilled from the log, are ignored.
+    pub fn add(&mut self, reading: &ProbeReading) -> Option<CarryoverEvent> {
+        if self.samples.back().is_some_and(|s| s.time >= reading.time) {
+            return None;
+        }
+        let sample = Sample {
+            time: reading.time,
+            core: reading.core_c(),
+            air: reading.air_c(),
+            surface: reading.virtual_sensors.map(|_| reading.surface_c()),
+        };
+        self.samples.push_back(sample);
+        let window = REMOVAL_WINDOW.max(FIT_WINDOW);
+        while self.samples.front().is_some_and(|s| (sample.time - s.time).to_std().is_ok_and(|age| age > window)) {
+            self.samples.pop_front();
+        }
+
+        let removed_at = match self.summary.removed_at {
+            Some(removed_at) => removed_at,
+            None => return self.check_removed(sample),
+        };
+
+        // Back on the heat, start over
+        self.min_air = self.min_air.min(sample.air);
+        if sample.air - self.min_air >= REMOVAL_DROP_C {
+            self.summary = CarryoverSummary::default();
+            return None;
+        }
+        if self.summary.peaked {
+            return None;
+        }
+
+        let (max_at, max) = self.summary.max.unwrap_or((sample.time, sample.core));
+        if sample.core > max {
+            self.summary.max = Some((sample.time, sample.core));
+        } else if max - sample.core >= PEAK_DROP_C {
+            self.summary.peaked = true;
+            self.summary.estimate = None;
+            return Some(CarryoverEvent::Peaked { at: max_at, core_c: max });
+        }
+        self.summary.estimate = self.fit_peak(removed_at);
+        None
+    }
+
+    /// Whether the air dropped far enough over REMOVAL_WINDOW, with the surface cooling as well
+    fn check_removed(&mut self, sample: Sample) -> Option<CarryoverEvent> {
+        let recent = self.samples.iter().filter(|s| (sample.time - s.time).to_std().is_ok_and(|age| age <= REMOVAL_WINDOW));
+        let hottest = recent.clone().max_by(|a, b| a.air.total_cmp(&b.air))?;
+        if hottest.air - sample.air < REMOVAL_DROP_C {
+            return None;
+        }
+        let surface_max = recent.filter_map(|s| s.surface).reduce(f32::max);
+        if let (Some(max), Some(now)) = (surface_max, sample.surface) {
+            if max - now < SURFACE_DROP_C {
+                return None;
+            }
+        }
+
+        let removed_at = hottest.time;
+        let (max_at, max) = self.samples
+            .iter()
+            .filter(|s| s.time >= removed_at)
+            .map(|s| (s.time, s.core))
+            .max_by(|a, b| a.1.total_cmp(&b.1))?;
+        self.min_air = sample.air;
+        self.summary = CarryoverSummary {
+            removed_at: Some(removed_at),
+            max: Some((max_at, max)),
+            estimate: None,
+            peaked: false,
+        };
+        Some(CarryoverEvent::Removed { at: removed_at, core_c: hottest.core })
+    }
+
+    /// Works out the peak from how fast the core's climb is slowing down. The climb slows roughly
+    /// exponentially, so the slopes over the older and newer halves of the readings since removal
+    /// give its time constant, and from that how much further the core goes before it's climbing
+    /// slower than PEAK_RATE_C_PER_MIN, which is as good as peaked.
+    fn fit_peak(&self, removed_at: DateTime<Utc>) -> Option<(DateTime<Utc>, f32)> {
+        let newest = self.samples.back()?.time;
+        // Minutes before the newest reading
+        let points: Vec<(f32, f32)> = self.samples
+            .iter()
+            .filter(|s| s.time >= removed_at && (newest - s.time).to_std().is_ok_and(|age| age <= FIT_WINDOW))
+            .map(|s| (-((newest - s.time).num_milliseconds() as f32) / 60_000.0, s.core))
+            .collect();
+        let span = -points.first()?.0;
+        if points.len() < MIN_FIT_SAMPLES || span < MIN_FIT_SPAN.as_secs_f32() / 60.0 {
+            return None;
+        }
+
+        let (older, newer) = points.split_at(points.len() / 2);
+        let (older_line, newer_line) = (Line::fit(older)?, Line::fit(newer)?);
+        // The slope of each half is the rate at its mean time
+        let ((t1, r1), (t2, r2)) = ((older_line.mean_t, older_line.slope), (newer_line.mean_t, newer_line.slope));
+        // Still climbing, and slowing down
+        if r2 <= 0.0 || r2 >= r1 {
+            return None;
+        }
+        let tau = (t2 - t1) / (r1 / r2).ln();
+        let rate = r2 * (t2 / tau).exp();
+        let core = newer.iter().map(|(_, c)| c).sum::<f32>() / newer.len() as f32 + r2 * -t2;
+        if rate <= PEAK_RATE_C_PER_MIN {
+            return Some((newest, core));
+        }
+        let ahead = Duration::try_from_secs_f32(tau * (rate / PEAK_RATE_C_PER_MIN).ln() * 60.0).ok().filter(|d| *d <= MAX_PEAK_AHEAD)?;
+        Some((newest + ahead, core + tau * (rate - PEAK_RATE_C_PER_MIN)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::combustion::{test_start, VirtualSensors, NUM_SENSORS};
+
+    // Off the heat an hour in. The core then climbs to about 58.6°C at a little over 10 minutes
+    // after.
+    const PULL_MINUTES: f32 = 60.0;
+
+    fn reading(minutes: f32) -> ProbeReading {
+        let (core, surface, air) = if minutes < PULL_MINUTES {
+            (20.0 + 35.0 * minutes / PULL_MINUTES, 90.0, 120.0)
+        } else {
+            let t = minutes - PULL_MINUTES;
+            let air = 25.0 + 95.0 * (-t).exp();
+            (55.0 + 5.0 * (1.0 - (-t / 4.0).exp()) - 0.1 * t, 90.0 - 3.0 * t, air)
+        };
+        let mut temps = [core; NUM_SENSORS];
+        temps[5] = surface;
+        temps[7] = air;
+        ProbeReading {
+            virtual_sensors: Some(VirtualSensors { core: 2, surface: 5, ambient: 7 }),
+            ..ProbeReading::at(minutes * 60.0, temps)
+        }
+    }
+
+    #[test]
+    fn follows_the_core_to_its_peak() {
+        let start = test_start();
+        let at = |m: f32| start + Duration::from_secs_f32(m * 60.0);
+        let mut carryover = CarryoverDetector::default();
+        let mut events = vec![];
+        let mut estimate = None;
+        let mut minutes = 0.0;
+        while minutes <= PULL_MINUTES + 20.0 {
+            events.extend(carryover.add(&reading(minutes)));
+            if minutes == PULL_MINUTES + 6.0 {
+                estimate = carryover.summary().estimate;
+            }
+            minutes += 0.25;
+        }
+
+        assert_eq!(events.len(), 2, "{:?}", events);
+        assert!(matches!(events[0], CarryoverEvent::Removed { at: removed, .. } if removed == at(PULL_MINUTES)));
+        match events[1] {
+            CarryoverEvent::Peaked { at: peak_at, core_c } => {
+                assert!(peak_at >= at(PULL_MINUTES + 9.5) && peak_at <= at(PULL_MINUTES + 11.0), "{}", peak_at);
+                assert!((core_c - 58.6).abs() < 0.1, "{}", core_c);
+            },
+            e => panic!("{:?}", e),
+        }
+
+        // Six minutes in the estimate is close to the peak
+        let (peak_at, peak_c) = estimate.unwrap();
+        assert!((peak_c - 58.6).abs() < 0.5, "{}", peak_c);
+        assert!(peak_at >= at(PULL_MINUTES + 8.0) && peak_at <= at(PULL_MINUTES + 13.0), "{}", peak_at);
+
+        let summary = carryover.summary();
+        assert!(summary.resting() && summary.peaked);
+        assert_eq!(summary.estimate, None);
+        assert_eq!(summary.to_json()["peak_at"], at(PULL_MINUTES + 10.0).to_rfc3339_opts(SecondsFormat::Secs, true));
+    }
+
+    #[test]
+    fn ignores_a_lid_opening_while_the_surface_stays_hot() {
+        let mut carryover = CarryoverDetector::default();
+        for i in 0..40 {
+            let mut reading = reading(i as f32);
+            if i >= 30 {
+                reading.temps_c[7] = 70.0;
+            }
+            assert_eq!(carryover.add(&reading), None);
+        }
+        assert!(!carryover.summary().resting());
+    }
+}
@@ -0,0 +1,170 @@
+// Decoder for the manufacturer specific data Combustion devices advertise
+//
+// The data under the Combustion company ID starts with the product type and the device's serial
+// number, which is how we tell probes apart, followed by the same temperature, mode/ID and battery
+// fields as Probe Status. That's enough to follow a cook without ever connecting.
+
+use crate::combustion::probe_status::{decode_battery, decode_mode_id, decode_raw_temps};
+use crate::combustion::{BatteryStatus, InvalidVirtualCore, ProbeColor, ProbeMode, ProbeReading, VirtualSensors, NUM_SENSORS};
+
+/// Bluetooth SIG company identifier for Combustion Inc.
+pub const COMBUSTION_ID: u16 = 0x09C7;
+
+const SERIAL_OFFSET: usize = 1;
+const RAW_TEMPS_OFFSET: usize = SERIAL_OFFSET + 4;
+const MODE_ID_OFFSET: usize = RAW_TEMPS_OFFSET + 13;
+const BATTERY_OFFSET: usize = MODE_ID_OFFSET + 1;
+
+/// Product type, serial, temperatures, mode/ID and battery. Network info and the overheating
+/// flags that follow aren't used.
+pub const MIN_ADVERTISEMENT_LEN: usize = BATTERY_OFFSET + 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AdvertisementError {
+    TooShort { len: usize, expected: usize },
+    InvalidVirtualCore(u8),
+}
+
+impl std::fmt::Display for AdvertisementError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AdvertisementError::TooShort { len, expected } => write!(f, "advertisement too short: got {} bytes, need at least {}", len, expected),
+            AdvertisementError::InvalidVirtualCore(v) => write!(f, "invalid virtual core sensor {}", v),
+        }
+    }
+}
+
+impl std::error::Error for AdvertisementError {}
+
+impl From<InvalidVirtualCore> for AdvertisementError {
+    fn from(e: InvalidVirtualCore) -> AdvertisementError {
+        AdvertisementError::InvalidVirtualCore(e.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProductType {
+    Unknown,
+    Probe,
+    Display,
+    Booster,
+}
+
+impl From<u8> for ProductType {
+    fn from(v: u8) -> ProductType {
+        match v {
+            1 => ProductType::Probe,
+            2 => ProductType::Display,
+            3 => ProductType::Booster,
+            _ => ProductType::Unknown,
+        }
+    }
+}
+
+/// A probe's serial number, printed in hex like the vendor app does
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ProbeSerial(pub u32);
+
+impl std::fmt::Display for ProbeSerial {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:08X}", self.0)
+    }
+}
+
+impl std::str::FromStr for ProbeSerial {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<ProbeSerial, Self::Err> {
+        u32::from_str_radix(s, 16).map(ProbeSerial)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Advertisement {
+    pub product_type: ProductType,
+    pub serial: ProbeSerial,
+    pub raw_temps: [u16; NUM_SENSORS],
+    pub mode: ProbeMode,
+    /// 1-8, printed on the probe
+    pub id: u8,
+    pub color: ProbeColor,
+    pub battery: BatteryStatus,
+    pub virtual_sensors: VirtualSensors,
+    /// Signal strength it was received at in dBm, filled in by the backend if it knows
+    pub rssi: Option<i16>,
+}
+
+impl Advertisement {
+    /// Decodes the manufacturer data that follows the company ID
+    pub fn decode(data: &[u8]) -> Result<Advertisement, AdvertisementError> {
+        if data.len() < MIN_ADVERTISEMENT_LEN {
+            return Err(AdvertisementError::TooShort { len: data.len(), expected: MIN_ADVERTISEMENT_LEN });
+        }
+        let (mode, color, id) = decode_mode_id(data[MODE_ID_OFFSET]);
+        let (battery, virtual_sensors) = decode_battery(data[BATTERY_OFFSET])?;
+        Ok(Advertisement {
+            product_type: data[0].into(),
+            serial: ProbeSerial(u32::from_le_bytes(data[SERIAL_OFFSET..RAW_TEMPS_OFFSET].try_into().expect("4"))),
+            raw_temps: decode_raw_temps(&data[RAW_TEMPS_OFFSET..MODE_ID_OFFSET]),
+            mode,
+            id,
+            color,
+            battery,
+            virtual_sensors,
+            rssi: None,
+        })
+    }
+
+    /// The advertised temperatures. Advertisements don't carry a log sequence number or prediction.
+    pub fn reading(&self) -> ProbeReading {
+        ProbeReading {
+            battery: Some(self.battery),
+            virtual_sensors: Some(self.virtual_sensors),
+            ..ProbeReading::from_raw(self.raw_temps)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A probe with serial 12345678, the temperatures from extra/repr.c, normal mode, red, ID 2,
+    // low battery, core T3, surface T5, ambient T8, then network info and overheating
+    const ADVERTISEMENT: [u8; 22] = [
+        0x01,
+        0x78, 0x56, 0x34, 0x12,
+        0x37, 0xc3, 0x64, 0x74, 0x8c, 0x8a, 0xf1, 0x30, 0x10, 0x06, 0xc2, 0x20, 0x18,
+        0x28,
+        0xd5,
+        0x00,
+        0x00,
+    ];
+
+    #[test]
+    fn probe_advertisement() {
+        let adv = Advertisement::decode(&ADVERTISEMENT).unwrap();
+        assert_eq!(adv.product_type, ProductType::Probe);
+        assert_eq!(adv.serial, ProbeSerial(0x12345678));
+        assert_eq!(adv.serial.to_string(), "12345678");
+        assert_eq!("12345678".parse::<ProbeSerial>(), Ok(adv.serial));
+        assert_eq!(adv.raw_temps, [823, 806, 797, 789, 783, 776, 776, 772]);
+        assert_eq!(adv.mode, ProbeMode::Normal);
+        assert_eq!(adv.color, ProbeColor::Red);
+        assert_eq!(adv.id, 2);
+        assert_eq!(adv.battery, BatteryStatus::Low);
+        assert_eq!(adv.virtual_sensors, VirtualSensors { core: 2, surface: 4, ambient: 7 });
+        assert!((adv.reading().t1() - 21.15).abs() < 0.001);
+        assert_eq!(adv.reading().battery, Some(BatteryStatus::Low));
+        assert!((adv.reading().core_c() - 19.85).abs() < 0.001);
+    }
+
+    #[test]
+    fn malformed() {
+        assert_eq!(Advertisement::decode(&ADVERTISEMENT[..5]), Err(AdvertisementError::TooShort { len: 5, expected: 20 }));
+
+        let mut bad_core = ADVERTISEMENT;
+        bad_core[BATTERY_OFFSET] = 0x06 << 1;
+        assert_eq!(Advertisement::decode(&bad_core), Err(AdvertisementError::InvalidVirtualCore(6)));
+    }
+}
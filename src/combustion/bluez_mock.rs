@@ -0,0 +1,476 @@
+// A scripted stand in for BlueZ on a private bus, so the Linux backend can be tested without a Pi
+//
+// Starts its own dbus-daemon, points DBUS_SYSTEM_BUS_ADDRESS at it and serves the parts of the
+// org.bluez object tree the backend uses: an adapter, devices with manufacturer data, and once a
+// device is connected the Probe Status, UART and Device Information GATT services. Tests that use
+// it run one at a time since the bus address is process wide.
+use dbus::arg::{PropMap, RefArg, Variant};
+use dbus::blocking::stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged;
+use dbus::blocking::SyncConnection;
+use dbus::channel::{Channel, MatchingReceiver, Sender};
+use dbus::message::{MatchRule, SignalArgs};
+use dbus::{MethodErr, Path};
+use dbus_crossroads::{Context, Crossroads, IfaceToken};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use crate::combustion::{uart, COMBUSTION_ID};
+
+const ADAPTER_PATH: &str = "/org/bluez/hci0";
+const ADAPTER_INTERFACE: &str = "org.bluez.Adapter1";
+const DEVICE_INTERFACE: &str = "org.bluez.Device1";
+const SERVICE_INTERFACE: &str = "org.bluez.GattService1";
+const CHARACTERISTIC_INTERFACE: &str = "org.bluez.GattCharacteristic1";
+
+const PROBE_STATUS_SERVICE: (&str, &str) = ("service0010", "00000100-caab-3792-3d44-97ae51c1407a");
+const PROBE_STATUS_CHARACTERISTIC: (&str, &str) = ("service0010/char0011", "00000101-caab-3792-3d44-97ae51c1407a");
+const UART_SERVICE: (&str, &str) = ("service0020", "6e400001-b5a3-f393-e0a9-e50e24dcca9e");
+const UART_RX_CHARACTERISTIC: (&str, &str) = ("service0020/char0021", "6e400002-b5a3-f393-e0a9-e50e24dcca9e");
+const UART_TX_CHARACTERISTIC: (&str, &str) = ("service0020/char0023", "6e400003-b5a3-f393-e0a9-e50e24dcca9e");
+// Device Information without a hardware revision, and a NUL padded firmware revision
+const DEVICE_INFORMATION_SERVICE: (&str, &str) = ("service0030", "0000180a-0000-1000-8000-00805f9b34fb");
+const DEVICE_INFORMATION: [((&str, &str), &[u8]); 4] = [
+    (("service0030/char0031", "00002a29-0000-1000-8000-00805f9b34fb"), b"Combustion Inc."),
+    (("service0030/char0033", "00002a24-0000-1000-8000-00805f9b34fb"), b"Predictive Thermometer"),
+    (("service0030/char0035", "00002a25-0000-1000-8000-00805f9b34fb"), b"12345678"),
+    (("service0030/char0037", "00002a26-0000-1000-8000-00805f9b34fb"), b"v1.4.2\0\0"),
+];
+
+/// Answers the UART requests the backend writes
+pub type UartScript = Box<dyn FnMut(uart::Request) -> Vec<uart::Response> + Send + Sync>;
+
+struct AdapterData {
+    powered: bool,
+    discovering: bool,
+    scanned: bool,
+    /// Probes ConnectDevice can reach without BlueZ knowing them, with their Probe Status
+    in_range: HashMap<String, Vec<u8>>,
+}
+
+struct DeviceData {
+    address: String,
+    manufacturer_data: Option<Vec<u8>>,
+    uuids: Vec<String>,
+    connected: bool,
+    /// Probe Status, served once connected
+    status: Vec<u8>,
+    uart: Option<UartScript>,
+    connects: u32,
+}
+
+struct ServiceData {
+    uuid: String,
+}
+
+struct CharacteristicData {
+    uuid: String,
+    flags: Vec<String>,
+    value: Vec<u8>,
+    notifying: bool,
+    writes: Vec<Vec<u8>>,
+}
+
+#[derive(Clone, Copy)]
+struct Tokens {
+    device: IfaceToken<DeviceData>,
+    service: IfaceToken<ServiceData>,
+    characteristic: IfaceToken<CharacteristicData>,
+}
+
+fn device_path(address: &str) -> Path<'static> {
+    format!("{}/dev_{}", ADAPTER_PATH, address.replace(':', "_")).into()
+}
+
+fn child_path(device: &Path, child: &str) -> Path<'static> {
+    format!("{}/{}", device, child).into()
+}
+
+fn properties_changed(interface: &str, changed: Vec<(&str, Box<dyn RefArg>)>) -> PropertiesPropertiesChanged {
+    PropertiesPropertiesChanged {
+        interface_name: interface.to_string(),
+        changed_properties: changed.into_iter().map(|(k, v)| (k.to_string(), Variant(v))).collect::<PropMap>(),
+        invalidated_properties: vec![],
+    }
+}
+
+/// ManufacturerData as BlueZ has it, keyed by company ID
+fn manufacturer_data(data: Vec<u8>) -> HashMap<u16, Variant<Box<dyn RefArg>>> {
+    let mut md: HashMap<u16, Variant<Box<dyn RefArg>>> = HashMap::new();
+    md.insert(COMBUSTION_ID, Variant(Box::new(data)));
+    md
+}
+
+/// Sets a characteristic's value, returning the notification if something subscribed
+fn set_value(cr: &mut Crossroads, path: &Path<'static>, value: Vec<u8>) -> Option<dbus::Message> {
+    let c = cr.data_mut::<CharacteristicData>(path)?;
+    c.value = value.clone();
+    c.notifying.then(|| properties_changed(CHARACTERISTIC_INTERFACE, vec![("Value", Box::new(value))]).to_emit_message(path))
+}
+
+/// Connecting resolves the probe's GATT services, disconnecting takes them away again
+fn set_connected(
+    cr: &mut Crossroads,
+    service: IfaceToken<ServiceData>,
+    characteristic: IfaceToken<CharacteristicData>,
+    path: &Path<'static>,
+    connected: bool,
+) -> Vec<dbus::Message> {
+    let status = match cr.data_mut::<DeviceData>(path) {
+        Some(d) if d.connected != connected => {
+            d.connected = connected;
+            d.connects += connected as u32;
+            d.status.clone()
+        },
+        _ => return vec![],
+    };
+    if connected {
+        for (child, uuid) in [PROBE_STATUS_SERVICE, UART_SERVICE, DEVICE_INFORMATION_SERVICE] {
+            cr.insert(child_path(path, child), &[service], ServiceData { uuid: uuid.to_string() });
+        }
+        let mut characteristics = vec![
+            (PROBE_STATUS_CHARACTERISTIC, vec!["read", "notify"], status),
+            (UART_RX_CHARACTERISTIC, vec!["write"], vec![]),
+            (UART_TX_CHARACTERISTIC, vec!["notify"], vec![]),
+        ];
+        characteristics.extend(DEVICE_INFORMATION.map(|(c, value)| (c, vec!["read"], value.to_vec())));
+        for ((child, uuid), flags, value) in characteristics {
+            cr.insert(child_path(path, child), &[characteristic], CharacteristicData {
+                uuid: uuid.to_string(),
+                flags: flags.into_iter().map(String::from).collect(),
+                value,
+                notifying: false,
+                writes: vec![],
+            });
+        }
+    } else {
+        for (child, _) in [PROBE_STATUS_CHARACTERISTIC, UART_RX_CHARACTERISTIC, UART_TX_CHARACTERISTIC] {
+            cr.remove::<CharacteristicData>(&child_path(path, child));
+        }
+        for ((child, _), _) in DEVICE_INFORMATION {
+            cr.remove::<CharacteristicData>(&child_path(path, child));
+        }
+        for (child, _) in [PROBE_STATUS_SERVICE, UART_SERVICE, DEVICE_INFORMATION_SERVICE] {
+            cr.remove::<ServiceData>(&child_path(path, child));
+        }
+    }
+    let changed = properties_changed(DEVICE_INTERFACE, vec![
+        ("Connected", Box::new(connected)),
+        ("ServicesResolved", Box::new(connected)),
+    ]);
+    vec![changed.to_emit_message(path)]
+}
+
+/// Feeds a write to the UART RX characteristic through the device's script and notifies the
+/// responses on TX
+fn uart_write(cr: &mut Crossroads, rx: &Path<'static>, value: Vec<u8>) -> Result<Vec<dbus::Message>, MethodErr> {
+    cr.data_mut::<CharacteristicData>(rx).ok_or_else(|| MethodErr::no_path(rx))?.writes.push(value.clone());
+    let device: Path<'static> = rx.rsplitn(3, '/').nth(2).unwrap_or_default().to_string().into();
+    let (request, _) = uart::Request::decode(&value).map_err(|e| MethodErr::failed(&e))?;
+    let responses = match cr.data_mut::<DeviceData>(&device).and_then(|d| d.uart.as_mut()) {
+        Some(script) => script(request),
+        None => vec![],
+    };
+    let tx = child_path(&device, UART_TX_CHARACTERISTIC.0);
+    Ok(responses.into_iter().filter_map(|r| set_value(cr, &tx, r.encode())).collect())
+}
+
+/// Registers the BlueZ interfaces and adds the object manager and the adapter
+fn register(cr: &mut Crossroads) -> Tokens {
+    let service = cr.register(SERVICE_INTERFACE, |b| {
+        b.property("UUID").get(|_, s: &mut ServiceData| Ok(s.uuid.clone()));
+        b.property("Primary").get(|_, _: &mut ServiceData| Ok(true));
+        b.property("Includes").get(|_, _: &mut ServiceData| Ok(Vec::<Path<'static>>::new()));
+    });
+
+    let characteristic = cr.register(CHARACTERISTIC_INTERFACE, |b| {
+        b.property("UUID").get(|_, c: &mut CharacteristicData| Ok(c.uuid.clone()));
+        b.property("Flags").get(|_, c: &mut CharacteristicData| Ok(c.flags.clone()));
+        b.property("Value").get(|_, c: &mut CharacteristicData| Ok(c.value.clone()));
+        b.property("Notifying").get(|_, c: &mut CharacteristicData| Ok(c.notifying));
+        b.property("MTU").get(|_, _: &mut CharacteristicData| Ok(247u16));
+        b.method("ReadValue", ("options",), ("value",), |_, c: &mut CharacteristicData, _: (PropMap,)| Ok((c.value.clone(),)));
+        b.method("StartNotify", (), (), |_, c: &mut CharacteristicData, _: ()| {
+            c.notifying = true;
+            Ok(())
+        });
+        b.method("StopNotify", (), (), |_, c: &mut CharacteristicData, _: ()| {
+            c.notifying = false;
+            Ok(())
+        });
+        b.method_with_cr("WriteValue", ("value", "options"), (), |ctx: &mut Context, cr, (value, _): (Vec<u8>, PropMap)| {
+            for msg in uart_write(cr, &ctx.path().clone(), value)? {
+                ctx.push_msg(msg);
+            }
+            Ok(())
+        });
+    });
+
+    let device = cr.register(DEVICE_INTERFACE, |b| {
+        b.property("Address").get(|_, d: &mut DeviceData| Ok(d.address.clone()));
+        b.property("AddressType").get(|_, _: &mut DeviceData| Ok("random".to_string()));
+        b.property("Connected").get(|_, d: &mut DeviceData| Ok(d.connected));
+        b.property("ServicesResolved").get(|_, d: &mut DeviceData| Ok(d.connected));
+        b.property("UUIDs").get(|_, d: &mut DeviceData| Ok(d.uuids.clone()));
+        b.property("RSSI").get(|_, _: &mut DeviceData| Ok(-60i16));
+        // Missing rather than empty without any, like BlueZ
+        b.property("ManufacturerData").get(|_, d: &mut DeviceData| {
+            let data = d.manufacturer_data.clone().ok_or_else(|| MethodErr::invalid_arg("ManufacturerData"))?;
+            Ok(manufacturer_data(data))
+        });
+        b.method_with_cr("Connect", (), (), move |ctx: &mut Context, cr, _: ()| {
+            for msg in set_connected(cr, service, characteristic, &ctx.path().clone(), true) {
+                ctx.push_msg(msg);
+            }
+            Ok(())
+        });
+        b.method_with_cr("Disconnect", (), (), move |ctx: &mut Context, cr, _: ()| {
+            for msg in set_connected(cr, service, characteristic, &ctx.path().clone(), false) {
+                ctx.push_msg(msg);
+            }
+            Ok(())
+        });
+    });
+
+    let adapter = cr.register(ADAPTER_INTERFACE, |b| {
+        b.property("Address").get(|_, _: &mut AdapterData| Ok("00:11:22:33:44:55".to_string()));
+        b.property("AddressType").get(|_, _: &mut AdapterData| Ok("public".to_string()));
+        b.property("Name").get(|_, _: &mut AdapterData| Ok("mock".to_string()));
+        b.property("Powered").get(|_, a: &mut AdapterData| Ok(a.powered)).set(|_, a, powered| {
+            a.powered = powered;
+            Ok(Some(powered))
+        });
+        b.property("Discovering").get(|_, a: &mut AdapterData| Ok(a.discovering));
+        b.method("SetDiscoveryFilter", ("filter",), (), |_, _: &mut AdapterData, _: (PropMap,)| Ok(()));
+        b.method("StartDiscovery", (), (), |_, a: &mut AdapterData, _: ()| {
+            a.discovering = true;
+            a.scanned = true;
+            Ok(())
+        });
+        b.method("StopDiscovery", (), (), |_, a: &mut AdapterData, _: ()| {
+            a.discovering = false;
+            Ok(())
+        });
+        // Creates and connects a device that's in range, like BlueZ does without a scan
+        b.method_with_cr("ConnectDevice", ("properties",), ("device",), move |ctx: &mut Context, cr, (properties,): (PropMap,)| {
+            let address = properties.get("Address").and_then(|a| a.0.as_str()).ok_or_else(|| MethodErr::invalid_arg("Address"))?.to_string();
+            let path = device_path(&address);
+            if cr.data_mut::<DeviceData>(&path).is_some() {
+                return Err(MethodErr::failed("Already Exists"));
+            }
+            let status = cr.data_mut::<AdapterData>(&ADAPTER_PATH.into())
+                .and_then(|a| a.in_range.get(&address).cloned())
+                .ok_or_else(|| MethodErr::failed("Page Timeout"))?;
+            cr.insert(path.clone(), &[device], DeviceData {
+                address,
+                manufacturer_data: None,
+                uuids: vec![PROBE_STATUS_SERVICE.1.to_string(), UART_SERVICE.1.to_string()],
+                connected: false,
+                status,
+                uart: None,
+                connects: 0,
+            });
+            for msg in set_connected(cr, service, characteristic, &path, true) {
+                ctx.push_msg(msg);
+            }
+            Ok((path,))
+        });
+        b.method_with_cr("RemoveDevice", ("device",), (), |_, cr, (device,): (Path<'static>,)| {
+            cr.remove::<DeviceData>(&device).ok_or_else(|| MethodErr::failed("Does Not Exist"))?;
+            Ok(())
+        });
+    });
+
+    cr.insert("/", &[cr.object_manager::<()>()], ());
+    cr.insert(ADAPTER_PATH, &[adapter], AdapterData { powered: false, discovering: false, scanned: false, in_range: HashMap::new() });
+    Tokens { device, service, characteristic }
+}
+
+/// BlueZ on a private bus. Dropping it stops the bus.
+pub struct MockBluez {
+    daemon: Child,
+    socket: PathBuf,
+    conn: Arc<SyncConnection>,
+    cr: Arc<Mutex<Crossroads>>,
+    tokens: Tokens,
+    stop: Arc<AtomicBool>,
+    _exclusive: tokio::sync::OwnedMutexGuard<()>,
+}
+
+impl MockBluez {
+    /// Starts the bus. Panics when there's no dbus-daemon to run rather than letting the tests
+    /// pass without testing anything.
+    pub async fn start() -> MockBluez {
+        static EXCLUSIVE: OnceLock<Arc<tokio::sync::Mutex<()>>> = OnceLock::new();
+        let exclusive = EXCLUSIVE.get_or_init(Default::default).clone().lock_owned().await;
+
+        // libdbus only reads the address once, so every mock in the process listens on the same socket
+        let socket = std::env::temp_dir().join(format!("rustbustion-bluez-{}", std::process::id()));
+        let _ = std::fs::remove_file(&socket);
+        let daemon = std::env::var("DBUS_DAEMON").unwrap_or_else(|_| "dbus-daemon".to_string());
+        let mut daemon = match Command::new(&daemon)
+            .args(["--session", "--nofork", "--print-address"])
+            .arg(format!("--address=unix:path={}", socket.display()))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+        {
+            Ok(daemon) => daemon,
+            Err(e) => panic!(
+                "Couldn't start {} for the mock BlueZ: {}. Install dbus, point DBUS_DAEMON at a dbus-daemon, or build with --no-default-features to leave out the BlueZ backend and its tests.",
+                daemon, e,
+            ),
+        };
+        // Printed once it's listening. The address it prints includes its GUID, which libdbus would
+        // hold the next mock to.
+        BufReader::new(daemon.stdout.take().expect("stdout")).read_line(&mut String::new()).expect("bus address");
+        let address = format!("unix:path={}", socket.display());
+        std::env::set_var("DBUS_SYSTEM_BUS_ADDRESS", &address);
+
+        let mut channel = Channel::open_private(&address).expect("connect to mock bus");
+        channel.register().expect("register on mock bus");
+        let conn = Arc::new(SyncConnection::from(channel));
+        conn.request_name("org.bluez", false, true, true).expect("own org.bluez");
+
+        let mut cr = Crossroads::new();
+        cr.set_object_manager_support(Some(conn.clone()));
+        let tokens = register(&mut cr);
+        let cr = Arc::new(Mutex::new(cr));
+
+        let stop = Arc::new(AtomicBool::new(false));
+        conn.start_receive(MatchRule::new_method_call(), Box::new({
+            let cr = cr.clone();
+            move |msg, conn| {
+                let _ = cr.lock().unwrap().handle_message(msg, conn);
+                true
+            }
+        }));
+        std::thread::spawn({
+            let conn = conn.clone();
+            let stop = stop.clone();
+            move || {
+                while !stop.load(Ordering::Relaxed) {
+                    if conn.process(Duration::from_millis(20)).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        MockBluez { daemon, socket, conn, cr, tokens, stop, _exclusive: exclusive }
+    }
+
+    fn send(&self, messages: Vec<dbus::Message>) {
+        for msg in messages {
+            self.conn.send(msg).expect("send signal");
+        }
+    }
+
+    /// Makes a device appear as if a scan found it, with manufacturer data under the Combustion ID
+    /// if there is any
+    pub fn add_device(&self, address: &str, manufacturer_data: Option<Vec<u8>>) {
+        self.cr.lock().unwrap().insert(device_path(address), &[self.tokens.device], DeviceData {
+            address: address.to_string(),
+            manufacturer_data,
+            uuids: vec![],
+            connected: false,
+            status: vec![],
+            uart: None,
+            connects: 0,
+        });
+    }
+
+    /// Makes a device look like BlueZ cached it after a connection on an earlier run: service
+    /// UUIDs resolved but no manufacturer data
+    pub fn add_cached_probe(&self, address: &str) {
+        self.add_device(address, None);
+        if let Some(d) = self.cr.lock().unwrap().data_mut::<DeviceData>(&device_path(address)) {
+            d.uuids = vec![PROBE_STATUS_SERVICE.1.to_string(), UART_SERVICE.1.to_string()];
+        }
+    }
+
+    /// Changes a device's manufacturer data as a new advertisement would
+    pub fn set_manufacturer_data(&self, address: &str, data: Vec<u8>) {
+        let path = device_path(address);
+        if let Some(d) = self.cr.lock().unwrap().data_mut::<DeviceData>(&path) {
+            d.manufacturer_data = Some(data.clone());
+        }
+        let changed = properties_changed(DEVICE_INTERFACE, vec![("ManufacturerData", Box::new(manufacturer_data(data)))]);
+        self.send(vec![changed.to_emit_message(&path)]);
+    }
+
+    /// Forgets a device as BlueZ does once it's been out of range a while
+    pub fn remove_device(&self, address: &str) {
+        self.cr.lock().unwrap().remove::<DeviceData>(&device_path(address));
+    }
+
+    /// Puts a probe BlueZ doesn't know in range of ConnectDevice, serving `status` once connected
+    pub fn put_in_range(&self, address: &str, status: Vec<u8>) {
+        if let Some(a) = self.cr.lock().unwrap().data_mut::<AdapterData>(&ADAPTER_PATH.into()) {
+            a.in_range.insert(address.to_string(), status);
+        }
+    }
+
+    /// Whether a scan was ever started
+    pub fn scanned(&self) -> bool {
+        self.cr.lock().unwrap().data_mut::<AdapterData>(&ADAPTER_PATH.into()).is_some_and(|a| a.scanned)
+    }
+
+    pub fn has_device(&self, address: &str) -> bool {
+        self.cr.lock().unwrap().data_mut::<DeviceData>(&device_path(address)).is_some()
+    }
+
+    pub fn is_connected(&self, address: &str) -> bool {
+        self.cr.lock().unwrap().data_mut::<DeviceData>(&device_path(address)).is_some_and(|d| d.connected)
+    }
+
+    /// How many times the device has been connected to
+    pub fn connects(&self, address: &str) -> u32 {
+        self.cr.lock().unwrap().data_mut::<DeviceData>(&device_path(address)).map_or(0, |d| d.connects)
+    }
+
+    /// Sets the device's Probe Status, notifying it if something subscribed
+    pub fn set_status(&self, address: &str, status: Vec<u8>) {
+        let path = device_path(address);
+        let mut cr = self.cr.lock().unwrap();
+        if let Some(d) = cr.data_mut::<DeviceData>(&path) {
+            d.status = status.clone();
+        }
+        let notification = set_value(&mut cr, &child_path(&path, PROBE_STATUS_CHARACTERISTIC.0), status);
+        drop(cr);
+        self.send(notification.into_iter().collect());
+    }
+
+    /// Answers the device's UART requests with `script`
+    pub fn set_uart(&self, address: &str, script: UartScript) {
+        if let Some(d) = self.cr.lock().unwrap().data_mut::<DeviceData>(&device_path(address)) {
+            d.uart = Some(script);
+        }
+    }
+
+    /// Everything written to the device's UART RX characteristic
+    pub fn uart_writes(&self, address: &str) -> Vec<Vec<u8>> {
+        let path = child_path(&device_path(address), UART_RX_CHARACTERISTIC.0);
+        self.cr.lock().unwrap().data_mut::<CharacteristicData>(&path).map_or(vec![], |c| c.writes.clone())
+    }
+
+    /// Drops the connection as if the probe went out of range
+    pub fn drop_connection(&self, address: &str) {
+        let messages = set_connected(&mut self.cr.lock().unwrap(), self.tokens.service, self.tokens.characteristic, &device_path(address), false);
+        self.send(messages);
+    }
+}
+
+impl Drop for MockBluez {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+        let _ = std::fs::remove_file(&self.socket);
+    }
+}
+
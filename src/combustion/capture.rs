@@ -0,0 +1,164 @@
+// Capture files of raw BLE payloads, for replaying a real cook through the decoders
+//
+// One event per line: microseconds since the capture started, what the payload is, the device's
+// address and the payload in hex, e.g.
+//
+//     1520000 status C2:71:04:90:3B:0E 01000000341200003...
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    /// Manufacturer data under the Combustion company ID
+    Advertisement,
+    /// A Probe Status value, notified or read
+    Status,
+    /// A notification on the UART TX characteristic
+    Uart,
+}
+
+impl EventKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::Advertisement => "adv",
+            EventKind::Status => "status",
+            EventKind::Uart => "uart",
+        }
+    }
+}
+
+impl std::str::FromStr for EventKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<EventKind> {
+        match s {
+            "adv" => Ok(EventKind::Advertisement),
+            "status" => Ok(EventKind::Status),
+            "uart" => Ok(EventKind::Uart),
+            _ => anyhow::bail!("Unknown event kind {}", s),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Event {
+    /// Time since the capture started
+    pub elapsed: Duration,
+    pub kind: EventKind,
+    pub address: String,
+    pub data: Vec<u8>,
+}
+
+impl std::fmt::Display for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} {} {} ", self.elapsed.as_micros(), self.kind.as_str(), self.address)?;
+        for b in &self.data {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for Event {
+    type Err = anyhow::Error;
+
+    fn from_str(line: &str) -> anyhow::Result<Event> {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.len() != 3 && parts.len() != 4 {
+            anyhow::bail!("Expected 4 fields: {}", line);
+        }
+        let hex = parts.get(3).copied().unwrap_or_default();
+        if hex.len() % 2 != 0 {
+            anyhow::bail!("Odd number of hex digits: {}", line);
+        }
+        let data = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()?;
+        Ok(Event {
+            elapsed: Duration::from_micros(parts[0].parse()?),
+            kind: parts[1].parse()?,
+            address: parts[2].to_string(),
+            data,
+        })
+    }
+}
+
+/// Parses a capture, skipping blank lines and `#` comments
+pub fn parse_capture(contents: &str) -> anyhow::Result<Vec<Event>> {
+    contents
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| l.parse())
+        .collect()
+}
+
+pub fn load_capture(path: &Path) -> anyhow::Result<Vec<Event>> {
+    let contents = std::fs::read_to_string(path)?;
+    parse_capture(&contents).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))
+}
+
+/// Appends events to a capture file. Clones share the file and the clock.
+#[derive(Clone)]
+pub struct Recorder {
+    file: Arc<Mutex<BufWriter<File>>>,
+    started: Instant,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> anyhow::Result<Recorder> {
+        Ok(Recorder {
+            file: Arc::new(Mutex::new(BufWriter::new(File::create(path)?))),
+            started: Instant::now(),
+        })
+    }
+
+    /// Writes the event straight through so a capture survives the daemon being killed
+    pub fn record(&self, kind: EventKind, address: &str, data: &[u8]) {
+        let event = Event {
+            elapsed: self.started.elapsed(),
+            kind,
+            address: address.to_string(),
+            data: data.to_vec(),
+        };
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = writeln!(file, "{}", event).and_then(|_| file.flush()) {
+            log::warn!("Couldn't record {:?}: {}", kind, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let event = Event {
+            elapsed: Duration::from_micros(1_520_000),
+            kind: EventKind::Status,
+            address: "C2:71:04:90:3B:0E".to_string(),
+            data: vec![0x01, 0x00, 0xab, 0xff],
+        };
+        let line = event.to_string();
+        assert_eq!(line, "1520000 status C2:71:04:90:3B:0E 0100abff");
+        assert_eq!(line.parse::<Event>().unwrap(), event);
+
+        let empty = Event { kind: EventKind::Uart, data: vec![], ..event };
+        assert_eq!(empty.to_string().parse::<Event>().unwrap(), empty);
+    }
+
+    #[test]
+    fn bad_lines() {
+        assert!("1 status".parse::<Event>().is_err());
+        assert!("1 bogus AA 00".parse::<Event>().is_err());
+        assert!("1 status AA 0".parse::<Event>().is_err());
+        assert!("1 status AA zz".parse::<Event>().is_err());
+        assert!("x status AA 00".parse::<Event>().is_err());
+        assert_eq!(parse_capture("# comment\n\n1 adv AA 01\n").unwrap().len(), 1);
+    }
+}
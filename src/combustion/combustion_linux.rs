@@ -1,73 +1,308 @@
-#[cfg(target_os="linux")]
+#[cfg(all(target_os="linux", feature="bluer"))]
 pub mod linux {
-    use bluer::{Address, gatt::remote::{Service}, Device};
-    use futures::{pin_mut, StreamExt};
-    use log::{error, info, trace, warn};
-    use modular_bitfield::prelude::*;
+    use bluer::{Address, AddressType, gatt::remote::{Characteristic, Service}, Device, DeviceEvent, DeviceProperty, UuidExt};
+    use futures::{pin_mut, stream, StreamExt};
+    use log::{info, trace, warn};
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
     use std::time::Duration;
-    use tokio::time::sleep;
-    use tokio::sync::oneshot::{Receiver};
+    use tokio::time::{sleep, timeout};
+    use tokio::sync::{mpsc, watch};
+    use tokio::task::JoinSet;
+
+    use crate::combustion::{uart, Advertisement, DeviceInfo, EventKind, KnownProbes, ProbeFilter, Recorder, Thermometer, ThermometerSource, PredictionMode, ProbeReading, ProbeSerial, ProbeStatus, ProductType, ReadMode, ReadingStream, Disconnected, COMBUSTION_ID};
 
-    const COMBUSTION_ID: u16 = 0x09C7;
     const PROBE_STATUS_SERVICE_UUID: &str = "00000100-CAAB-3792-3D44-97AE51C1407A";
+    const PROBE_STATUS_CHARACTERISTIC_UUID: &str = "00000101-CAAB-3792-3D44-97AE51C1407A";
     const UART_SERVICE_UUID: &str = "6E400001-B5A3-F393-E0A9-E50E24DCCA9E";
+    // We write requests to RX and the probe notifies responses on TX
+    const UART_RX_CHARACTERISTIC_UUID: &str = "6E400002-B5A3-F393-E0A9-E50E24DCCA9E";
+    const UART_TX_CHARACTERISTIC_UUID: &str = "6E400003-B5A3-F393-E0A9-E50E24DCCA9E";
+    // The standard Device Information service and the strings we keep from it
+    const DEVICE_INFORMATION_SERVICE: u16 = 0x180A;
+    const MANUFACTURER_NAME_CHARACTERISTIC: u16 = 0x2A29;
+    const MODEL_NUMBER_CHARACTERISTIC: u16 = 0x2A24;
+    const SERIAL_NUMBER_CHARACTERISTIC: u16 = 0x2A25;
+    const FIRMWARE_REVISION_CHARACTERISTIC: u16 = 0x2A26;
+    const HARDWARE_REVISION_CHARACTERISTIC: u16 = 0x2A27;
+    // Give up on a log download if the probe goes quiet for this long
+    const UART_TIMEOUT: Duration = Duration::from_secs(5);
+    // Give up on connecting straight to a remembered probe after this long
+    const DIRECT_CONNECT_TIMEOUT: Duration = Duration::from_secs(20);
+    // How often to try remembered probes that haven't connected, or that BlueZ forgot
+    const DIRECT_RETRY_PERIOD: Duration = Duration::from_secs(30);
+    // Give up waiting for a new device to show manufacturer data or services after this long
+    const ADVERTISEMENT_TIMEOUT: Duration = Duration::from_secs(5);
 
     pub struct CombustionFinder {
         adapter: bluer::Adapter,
+        recorder: Option<Recorder>,
+        /// Probes seen advertising, this run or earlier ones, so they're still recognised once
+        /// BlueZ has cached them without their advertisement
+        known: Arc<Mutex<KnownProbes>>,
+        /// Probes that may be connected to, anything else is logged and skipped
+        filter: Arc<ProbeFilter>,
     }
 
     impl CombustionFinder {
-        pub async fn new() -> anyhow::Result<CombustionFinder> {
+        /// Uses the adapter named `adapter`, e.g. hci1, or the default one. Only probes `filter`
+        /// allows are handed over, and probes in `known` are connected to directly while scanning.
+        /// Everything read off the air is written to `recorder` if there is one.
+        pub async fn new(adapter: Option<&str>, filter: ProbeFilter, known: KnownProbes, recorder: Option<Recorder>) -> anyhow::Result<CombustionFinder> {
             info!("Creating bluetooth session");
             let session = bluer::Session::new().await?;
 
-            info!("Getting default adapter");
-            let adapter = session.default_adapter().await?;
+            let adapter = match adapter {
+                Some(name) => {
+                    info!("Getting adapter {}", name);
+                    let names = session.adapter_names().await?;
+                    if !names.iter().any(|n| n == name) {
+                        anyhow::bail!("No adapter {}, there's {:?}", name, names);
+                    }
+                    session.adapter(name)?
+                },
+                None => {
+                    info!("Getting default adapter");
+                    session.default_adapter().await?
+                },
+            };
 
             info!("Setting powered");
             adapter.set_powered(true).await?;
 
-            Ok(CombustionFinder{
-                adapter
-            })
+            let finder = CombustionFinder{
+                adapter,
+                recorder,
+                known: Arc::new(Mutex::new(known)),
+                filter: Arc::new(filter),
+            };
+            finder.evict_stale().await?;
+            Ok(finder)
+        }
+
+        /// Removes probes BlueZ cached on an earlier run without their advertisement, which a scan
+        /// would otherwise never match again
+        async fn evict_stale(&self) -> anyhow::Result<()> {
+            for addr in self.adapter.device_addresses().await? {
+                let device = self.adapter.device(addr)?;
+                if device.manufacturer_data().await?.is_some() {
+                    continue;
+                }
+                if let Sighting::Stale = cached_sighting(&device, &self.known).await? {
+                    evict(&self.adapter, addr).await;
+                }
+            }
+            Ok(())
+        }
+
+        /// Connects to all of `probes` at once, handing over the ones that answer within
+        /// DIRECT_CONNECT_TIMEOUT and returning their addresses
+        async fn connect_remembered(&self, probes: &[(Address, ProbeSerial)], found: &mpsc::Sender<Combustion>) -> anyhow::Result<Vec<Address>> {
+            let attempts = probes.iter().map(|&(addr, serial)| async move {
+                match timeout(DIRECT_CONNECT_TIMEOUT, connect_direct(&self.adapter, addr)).await {
+                    Ok(Ok(device)) => Some((device, addr, serial)),
+                    Ok(Err(e)) => {
+                        info!("Remembered probe {} at {} didn't connect: {}", serial, addr, e);
+                        None
+                    },
+                    Err(_) => {
+                        info!("Remembered probe {} at {} didn't connect within {:?}", serial, addr, DIRECT_CONNECT_TIMEOUT);
+                        None
+                    },
+                }
+            });
+            let mut answered = vec![];
+            for (device, addr, serial) in futures::future::join_all(attempts).await.into_iter().flatten() {
+                info!("Connected straight to remembered probe {} at {}", serial, addr);
+                found.send(Combustion::new(device, self.adapter.clone(), addr, serial, self.recorder.clone())).await?;
+                answered.push(addr);
+            }
+            Ok(answered)
+        }
+
+        /// Connects to the remembered probes, then every DIRECT_RETRY_PERIOD to the ones that
+        /// haven't connected yet or that BlueZ has forgotten since. Runs until dropped.
+        async fn retry_remembered(&self, remembered: &[(Address, ProbeSerial)], found: &mpsc::Sender<Combustion>) -> anyhow::Result<()> {
+            let mut connected: Vec<Address> = vec![];
+            let mut retry = tokio::time::interval(DIRECT_RETRY_PERIOD);
+            loop {
+                retry.tick().await;
+                let present = self.adapter.device_addresses().await?;
+                let probes: Vec<_> = remembered.iter()
+                    .filter(|(addr, _)| !connected.contains(addr) || !present.contains(addr))
+                    .copied()
+                    .collect();
+                for addr in self.connect_remembered(&probes, found).await? {
+                    if !connected.contains(&addr) {
+                        connected.push(addr);
+                    }
+                }
+            }
         }
 
-        pub async fn discover(&self, mut done: &mut Receiver<bool>) -> anyhow::Result<Combustion> {
+        /// Scans until shutdown, handing over a Combustion each time BlueZ adds a probe. A probe
+        /// that dropped out of BlueZ and came back is handed over again. Devices are looked at
+        /// concurrently so a crowd of other devices doesn't hold up the probes.
+        async fn scan(&self, found: mpsc::Sender<Combustion>, mut shutdown: watch::Receiver<bool>) -> anyhow::Result<()> {
             let discover = self.adapter.discover_devices().await?;
             pin_mut!(discover);
+            // Dropped on return, which abandons the devices still being looked at
+            let mut candidates = JoinSet::new();
             loop {
                 tokio::select! {
                     evt = discover.next() => {
-                        if evt.is_none() {
-                            return Err(anyhow::anyhow!("Couldn't find device".to_string()));
+                        match evt {
+                            None => return Err(anyhow::anyhow!("Discovery stopped".to_string())),
+                            Some(bluer::AdapterEvent::DeviceAdded(addr)) => {
+                                let device = self.adapter.device(addr)?;
+                                let (known, filter, recorder) = (self.known.clone(), self.filter.clone(), self.recorder.clone());
+                                candidates.spawn(async move {
+                                    let sighting = identify(&device, &known, &filter, &recorder).await;
+                                    (device, sighting)
+                                });
+                            },
+                            Some(evt) => trace!("Event: {:?}", evt),
                         }
-                        let evt = evt.unwrap();
+                    }
+                    Some(candidate) = candidates.join_next() => {
+                        let (device, sighting) = match candidate {
+                            Ok(candidate) => candidate,
+                            Err(e) => {
+                                warn!("Device inspection failed: {:?}", e);
+                                continue;
+                            }
+                        };
+                        let addr = device.address();
+                        let serial = match sighting {
+                            Ok(Sighting::Probe(adv)) => adv.serial,
+                            Ok(Sighting::Remembered(serial)) => {
+                                info!("Reusing probe {} BlueZ cached at {}", serial, addr);
+                                serial
+                            },
+                            Ok(Sighting::Stale) => {
+                                evict(&self.adapter, addr).await;
+                                continue;
+                            },
+                            Ok(Sighting::NotAProbe) => continue,
+                            Err(e) => {
+                                warn!("Couldn't inspect device {}: {:?}", addr, e);
+                                continue;
+                            }
+                        };
+                        info!("Found probe {} at {} address type: {:?}", serial, addr, device.address_type().await?);
+                        found.send(Combustion::new(
+                                device,
+                                self.adapter.clone(),
+                                addr,
+                                serial,
+                                self.recorder.clone(),
+                        )).await?;
+                    }
+                    _ = shutdown.changed() => {
+                        info!("Got done signal");
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    impl ThermometerSource for CombustionFinder {
+        type Thermometer = Combustion;
+
+        /// Connects straight to the probes used on earlier runs while scanning for the rest, so
+        /// remembered probes don't wait on the scan and new ones can join at any time. Remembered
+        /// probes that didn't answer or that BlueZ has since forgotten are retried every
+        /// DIRECT_RETRY_PERIOD and handed over again once they connect.
+        async fn discover(&self, found: mpsc::Sender<Combustion>, mut shutdown: watch::Receiver<bool>) -> anyhow::Result<()> {
+            let remembered: Vec<(Address, ProbeSerial)> = self.known.lock().unwrap().probes()
+                .into_iter()
+                .filter_map(|(addr, serial)| match addr.parse() {
+                    Ok(addr) if allowed(&self.filter, addr, serial) => Some((addr, serial)),
+                    Ok(_) => None,
+                    Err(e) => {
+                        warn!("Ignoring remembered probe {} with a bad address {}: {}", serial, addr, e);
+                        None
+                    },
+                })
+                .collect();
+            if remembered.is_empty() {
+                return self.scan(found, shutdown).await;
+            }
+
+            info!("Connecting to {} remembered probes", remembered.len());
+            // Dropping whichever is still running abandons any connect in flight, so shutdown
+            // doesn't wait out DIRECT_CONNECT_TIMEOUT
+            tokio::select! {
+                result = self.scan(found.clone(), shutdown.clone()) => result,
+                result = self.retry_remembered(&remembered, &found) => result,
+                _ = shutdown.changed() => {
+                    info!("Got done signal");
+                    Ok(())
+                }
+            }
+        }
+
+        /// Scans until shutdown without ever connecting, handing over every advertisement from
+        /// every probe as it changes
+        async fn advertisements(&self, found: mpsc::Sender<Advertisement>, mut shutdown: watch::Receiver<bool>) -> anyhow::Result<()> {
+            let discover = self.adapter.discover_devices().await?;
+            pin_mut!(discover);
+            // Dropped on return, which stops the watchers
+            let mut watchers = JoinSet::new();
+            loop {
+                tokio::select! {
+                    evt = discover.next() => {
                         match evt {
-                            bluer::AdapterEvent::DeviceAdded(addr) => {
+                            None => return Err(anyhow::anyhow!("Discovery stopped".to_string())),
+                            Some(bluer::AdapterEvent::DeviceAdded(addr)) => {
                                 let device = self.adapter.device(addr)?;
-                                if is_combustion(&device).await? {
-                                    info!("Address type: {:?}", device.address_type().await?);
-                                    return Ok(Combustion::new(
-                                            device,
-                                            self.adapter.clone(),
-                                            addr,
-                                    ));
-                                }
+                                let found = found.clone();
+                                let (adapter, known, filter, recorder) = (self.adapter.clone(), self.known.clone(), self.filter.clone(), self.recorder.clone());
+                                watchers.spawn(async move {
+                                    if let Err(e) = watch_advertisements(&adapter, device, &known, &filter, found, recorder).await {
+                                        warn!("Stopped listening to {}: {:?}", addr, e);
+                                    }
+                                });
                             },
-                            _ => trace!("Event: {:?}", evt)
+                            Some(evt) => trace!("Event: {:?}", evt),
                         }
                     }
-                    _ = &mut done => {
+                    Some(_) = watchers.join_next() => {}
+                    _ = shutdown.changed() => {
                         info!("Got done signal");
-                        return Err(anyhow::anyhow!("Terminated early".to_string()));
+                        return Ok(());
                     }
                 }
             }
         }
     }
 
-    async fn is_combustion(device: &Device) -> anyhow::Result<bool> {
-        sleep(Duration::from_secs(2)).await;
+    /// Waits up to ADVERTISEMENT_TIMEOUT for BlueZ to fill in the device's manufacturer data or
+    /// service UUIDs, which can arrive after it reports the device added
+    async fn settle(device: &Device) -> anyhow::Result<()> {
+        // Subscribed before looking so a change in between isn't missed
+        let events = device.events().await?;
+        if device.manufacturer_data().await?.is_some() || device.uuids().await?.is_some_and(|u| !u.is_empty()) {
+            return Ok(());
+        }
+        pin_mut!(events);
+        let appeared = timeout(ADVERTISEMENT_TIMEOUT, async {
+            while let Some(evt) = events.next().await {
+                if let DeviceEvent::PropertyChanged(DeviceProperty::ManufacturerData(_) | DeviceProperty::Uuids(_)) = evt {
+                    return;
+                }
+            }
+        }).await;
+        if appeared.is_err() {
+            trace!("Device {} showed nothing within {:?}", device.address(), ADVERTISEMENT_TIMEOUT);
+        }
+        Ok(())
+    }
+
+    /// Returns the device's latest advertisement if it's a Combustion probe
+    async fn probe_advertisement(device: &Device, recorder: &Option<Recorder>) -> anyhow::Result<Option<Advertisement>> {
+        settle(device).await?;
         let addr = device.address();
         let uuids = device.uuids().await?.unwrap_or_default();
         info!("Device {} with service UUIDs {:?}", addr, &uuids);
@@ -75,53 +310,259 @@ pub mod linux {
         info!("Manufacturer data: {:x?}", &md);
 
         if md.is_none() {
-            return Ok(false)
+            return Ok(None)
         }
 
         let md = md.unwrap();
         let data = md.get(&COMBUSTION_ID);
         if data.is_none() {
-            return Ok(false)
+            return Ok(None)
         }
         let data = data.unwrap();
         info!("Found combustion: {:x?}", data);
-        Ok(true)
+        record(recorder, EventKind::Advertisement, addr, data);
+        match Advertisement::decode(data) {
+            Ok(adv) if adv.product_type == ProductType::Probe => Ok(Some(adv)),
+            adv => {
+                info!("Rejecting Combustion device at {} that isn't a probe: {:?}", addr, adv);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Connects to a probe by address without waiting to see it advertise, asking BlueZ to create
+    /// the device if it has forgotten it
+    async fn connect_direct(adapter: &bluer::Adapter, addr: Address) -> anyhow::Result<Device> {
+        if !adapter.device_addresses().await?.contains(&addr) {
+            return Ok(adapter.connect_device(addr, AddressType::LeRandom).await?);
+        }
+        let device = adapter.device(addr)?;
+        if !device.is_connected().await? {
+            device.connect().await?;
+        }
+        Ok(device)
+    }
+
+    /// What discovery makes of a device BlueZ reported
+    enum Sighting {
+        /// Advertising as a probe
+        Probe(Advertisement),
+        /// Cached by BlueZ without its advertisement, but seen advertising earlier
+        Remembered(ProbeSerial),
+        /// Cached by BlueZ without its advertisement and never seen advertising
+        Stale,
+        NotAProbe,
+    }
+
+    /// Probes the filter rejects are NotAProbe, and are never remembered
+    async fn identify(device: &Device, known: &Mutex<KnownProbes>, filter: &ProbeFilter, recorder: &Option<Recorder>) -> anyhow::Result<Sighting> {
+        if let Some(adv) = probe_advertisement(device, recorder).await? {
+            if !allowed(filter, device.address(), adv.serial) {
+                return Ok(Sighting::NotAProbe);
+            }
+            known.lock().unwrap().remember(&device.address().to_string(), adv.serial);
+            return Ok(Sighting::Probe(adv));
+        }
+        if device.manufacturer_data().await?.is_some() {
+            return Ok(Sighting::NotAProbe);
+        }
+        match cached_sighting(device, known).await? {
+            Sighting::Remembered(serial) if !allowed(filter, device.address(), serial) => Ok(Sighting::NotAProbe),
+            sighting => Ok(sighting),
+        }
+    }
+
+    /// Whether the filter allows the probe, logging why if it doesn't
+    fn allowed(filter: &ProbeFilter, addr: Address, serial: ProbeSerial) -> bool {
+        match filter.rejection(&addr.to_string(), serial) {
+            Some(reason) => {
+                info!("Rejecting probe {} at {}: {}", serial, addr, reason);
+                false
+            },
+            None => true,
+        }
+    }
+
+    /// Works out whether a device without manufacturer data is a probe BlueZ cached, either one we
+    /// know the address of or one BlueZ resolved the Probe Status service on
+    async fn cached_sighting(device: &Device, known: &Mutex<KnownProbes>) -> anyhow::Result<Sighting> {
+        if let Some(serial) = known.lock().unwrap().get(&device.address().to_string()) {
+            return Ok(Sighting::Remembered(serial));
+        }
+        let probe_uuid = bluer::Uuid::parse_str(PROBE_STATUS_SERVICE_UUID).expect("probe uuid");
+        if device.uuids().await?.unwrap_or_default().contains(&probe_uuid) {
+            return Ok(Sighting::Stale);
+        }
+        Ok(Sighting::NotAProbe)
     }
 
-#[bitfield]
-    struct RawTempData {
-        t1: B13,
-        t2: B13,
-        t3: B13,
-        t4: B13,
-        t5: B13,
-        t6: B13,
-        t7: B13,
-        t8: B13,
+    /// Makes BlueZ forget a cached probe so the next scan finds it again with its advertisement
+    async fn evict(adapter: &bluer::Adapter, addr: Address) {
+        info!("Evicting probe cached at {} so it's rediscovered", addr);
+        if let Err(e) = adapter.remove_device(addr).await {
+            warn!("Couldn't evict {}: {}", addr, e);
+        }
+    }
+
+    /// Forwards a probe's advertisements each time BlueZ sees its manufacturer data change. Probes
+    /// BlueZ has cached without an advertisement are evicted, as there's nothing to read until
+    /// they're rediscovered.
+    async fn watch_advertisements(
+        adapter: &bluer::Adapter,
+        device: Device,
+        known: &Mutex<KnownProbes>,
+        filter: &ProbeFilter,
+        found: mpsc::Sender<Advertisement>,
+        recorder: Option<Recorder>,
+    ) -> anyhow::Result<()> {
+        let addr = device.address();
+        let adv = match identify(&device, known, filter, &recorder).await? {
+            Sighting::Probe(adv) => adv,
+            Sighting::Remembered(_) | Sighting::Stale => {
+                evict(adapter, addr).await;
+                return Ok(());
+            },
+            Sighting::NotAProbe => return Ok(()),
+        };
+        info!("Listening to probe {} at {}", adv.serial, addr);
+        found.send(Advertisement { rssi: device.rssi().await?, ..adv }).await?;
+
+        let events = device.events().await?;
+        pin_mut!(events);
+        while let Some(evt) = events.next().await {
+            if let DeviceEvent::PropertyChanged(DeviceProperty::ManufacturerData(md)) = evt {
+                let data = match md.get(&COMBUSTION_ID) {
+                    Some(data) => data,
+                    None => continue,
+                };
+                record(&recorder, EventKind::Advertisement, addr, data);
+                match Advertisement::decode(data) {
+                    Ok(adv) => found.send(Advertisement { rssi: device.rssi().await?, ..adv }).await?,
+                    Err(e) => warn!("Bad advertisement from {}: {} {:x?}", addr, e, data),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn record(recorder: &Option<Recorder>, kind: EventKind, addr: Address, data: &[u8]) {
+        if let Some(recorder) = recorder {
+            recorder.record(kind, &addr.to_string(), data);
+        }
+    }
+
+    /// Reads the strings we keep from the Device Information service, leaving out any that can't be
+    /// read rather than failing the connection over them
+    async fn read_device_info(service: &Service) -> DeviceInfo {
+        let mut info = DeviceInfo::default();
+        let characteristics = match service.characteristics().await {
+            Ok(characteristics) => characteristics,
+            Err(e) => {
+                warn!("Couldn't list device information: {}", e);
+                return info;
+            }
+        };
+        for c in characteristics {
+            let field = match c.uuid().await.ok().and_then(|u| u.as_u16()) {
+                Some(MANUFACTURER_NAME_CHARACTERISTIC) => &mut info.manufacturer,
+                Some(MODEL_NUMBER_CHARACTERISTIC) => &mut info.model,
+                Some(SERIAL_NUMBER_CHARACTERISTIC) => &mut info.serial,
+                Some(FIRMWARE_REVISION_CHARACTERISTIC) => &mut info.firmware_revision,
+                Some(HARDWARE_REVISION_CHARACTERISTIC) => &mut info.hardware_revision,
+                _ => continue,
+            };
+            match c.read().await {
+                // Strings, sometimes NUL padded
+                Ok(value) => *field = Some(String::from_utf8_lossy(&value).trim_end_matches('\0').trim().to_string()),
+                Err(e) => warn!("Couldn't read device information {}: {}", c.id(), e),
+            }
+        }
+        info
+    }
+
+    fn decode_reading(value: &[u8]) -> anyhow::Result<ProbeReading> {
+        let status = ProbeStatus::decode(value)?;
+        trace!("Probe status log range {}..={}", status.log_range.min, status.log_range.max);
+        Ok(status.reading())
     }
 
     pub struct Combustion {
         device: Device,
         adapter: bluer::Adapter,
         addr: Address,
+        serial: ProbeSerial,
         probe_service: Option<Service>,
+        probe_status: Option<Characteristic>,
         uart_service: Option<Service>,
+        uart_rx: Option<Characteristic>,
+        uart_tx: Option<Characteristic>,
+        device_info: DeviceInfo,
+        recorder: Option<Recorder>,
     }
 
     impl Combustion {
-        pub fn new(device: bluer::Device, adapter: bluer::Adapter, addr: Address) -> Combustion {
+        pub fn new(device: bluer::Device, adapter: bluer::Adapter, addr: Address, serial: ProbeSerial, recorder: Option<Recorder>) -> Combustion {
             Combustion {
                 device,
                 adapter,
                 addr,
+                serial,
+                recorder,
                 probe_service: None,
+                probe_status: None,
                 uart_service: None,
+                uart_rx: None,
+                uart_tx: None,
+                device_info: DeviceInfo::default(),
             }
         }
 
-        pub async fn connect(&mut self) -> anyhow::Result<()> {
+        /// Sends a single UART request and waits for the probe's response to it
+        async fn uart_request(&self, request: uart::Request) -> anyhow::Result<uart::Response> {
+            let rx = self.uart_rx.as_ref().ok_or(anyhow::anyhow!("No UART RX characteristic found"))?;
+            let tx = self.uart_tx.as_ref().ok_or(anyhow::anyhow!("No UART TX characteristic found"))?;
+
+            let notifications = tx.notify().await?;
+            pin_mut!(notifications);
+            let mut reassembler = uart::Reassembler::new();
+
+            rx.write(&request.encode()).await?;
+            loop {
+                let value = timeout(UART_TIMEOUT, notifications.next())
+                    .await?
+                    .ok_or(anyhow::anyhow!("UART closed waiting for {:?}", request.message_type()))?;
+                record(&self.recorder, EventKind::Uart, self.addr, &value);
+                for response in reassembler.push(&value) {
+                    match response {
+                        Ok(uart::Response::Failed(t)) if t == request.message_type() => anyhow::bail!("Probe rejected {:?}", request),
+                        Ok(r) if r.message_type() == request.message_type() => return Ok(r),
+                        Ok(r) => trace!("Ignoring UART response {:?}", r),
+                        Err(e) => warn!("Bad UART response: {}", e),
+                    }
+                }
+            }
+        }
+    }
+
+    impl Thermometer for Combustion {
+        fn serial(&self) -> ProbeSerial {
+            self.serial
+        }
+
+        async fn connect(&mut self) -> anyhow::Result<()> {
             let probe_uuid = bluer::Uuid::parse_str(PROBE_STATUS_SERVICE_UUID).expect("probe uuid");
+            let status_uuid = bluer::Uuid::parse_str(PROBE_STATUS_CHARACTERISTIC_UUID).expect("probe status uuid");
             let uart_uuid = bluer::Uuid::parse_str(UART_SERVICE_UUID).expect("uart uuid");
+            let uart_rx_uuid = bluer::Uuid::parse_str(UART_RX_CHARACTERISTIC_UUID).expect("uart rx uuid");
+            let uart_tx_uuid = bluer::Uuid::parse_str(UART_TX_CHARACTERISTIC_UUID).expect("uart tx uuid");
+
+            // Characteristics from an earlier connection may not exist anymore
+            self.device_info = DeviceInfo::default();
+            self.probe_service = None;
+            self.probe_status = None;
+            self.uart_service = None;
+            self.uart_rx = None;
+            self.uart_tx = None;
 
             sleep(Duration::from_secs(2)).await;
             if !self.device.is_connected().await? {
@@ -146,47 +587,188 @@ pub mod linux {
                 let uuid = service.uuid().await?;
                 info!("  Service UUID: {} ID: {}", &uuid, service.id());
                 if uuid == probe_uuid {
+                    for c in service.characteristics().await? {
+                        if c.uuid().await? == status_uuid {
+                            self.probe_status.replace(c);
+                        }
+                    }
                     self.probe_service.replace(service.clone());
                 } else if uuid == uart_uuid {
+                    for c in service.characteristics().await? {
+                        let uuid = c.uuid().await?;
+                        if uuid == uart_rx_uuid {
+                            self.uart_rx.replace(c);
+                        } else if uuid == uart_tx_uuid {
+                            self.uart_tx.replace(c);
+                        }
+                    }
                     self.uart_service.replace(service.clone());
+                } else if uuid == bluer::Uuid::from_u16(DEVICE_INFORMATION_SERVICE) {
+                    self.device_info = read_device_info(&service).await;
+                    info!("  Device information: {:?}", self.device_info);
                 }
                 info!("  Service data: {:?}", service.all_properties().await?);
             }
 
-            if self.probe_service.is_some() && self.uart_service.is_some() {
+            if self.probe_service.is_some() && self.probe_status.is_some() && self.uart_rx.is_some() && self.uart_tx.is_some() {
                 return Ok(());
             }
 
             Err(anyhow::anyhow!("Couldn't find required services"))
         }
 
-        pub async fn get_raw_temp(&self) -> anyhow::Result<Option<f32>> {
-            let svc = self.probe_service.as_ref().ok_or(anyhow::anyhow!("No probe service found"))?;
-            for c in svc.characteristics().await? {
-                let uuid = c.uuid().await?;
-                info!("Probe Status c: {}", &uuid);
-                // If the characteristic flags includes "read"
-                if c.flags().await?.read {
-                    // Read it
-                    let value = c.read().await?;
-
-                    let min_bytes: [u8; 4] = [value[0], value[1], value[2], value[3]];
-                    let max_bytes: [u8; 4] = [value[4], value[5], value[6], value[7]];
-                    let min = u32::from_ne_bytes(min_bytes);
-                    let max = u32::from_ne_bytes(max_bytes);
-                    info!("Min {} max {}", min, max);
-
-                    let vs: [u8; 13] = value[8..21].try_into().expect("13");
-                    let unpacked = RawTempData::from_bytes(vs);
-                    let t1c = (unpacked.t1() * 5 - 2000) as f32 / 100.0;
-                    return Ok(Some(t1c))
+        fn device_info(&self) -> DeviceInfo {
+            self.device_info.clone()
+        }
+
+        /// Streams readings from the Probe Status characteristic, either as the probe notifies them
+        /// or by reading it every interval.
+        async fn readings(&self, mode: ReadMode) -> anyhow::Result<ReadingStream> {
+            let c = self.probe_status.clone().ok_or(anyhow::anyhow!("No probe status characteristic found"))?;
+            match mode {
+                ReadMode::Notify => {
+                    info!("Subscribing to probe status notifications");
+                    let values = c.notify().await?;
+                    let (recorder, addr) = (self.recorder.clone(), self.addr);
+                    Ok(Box::pin(values.map(move |value| {
+                        record(&recorder, EventKind::Status, addr, &value);
+                        decode_reading(&value)
+                    })))
+                },
+                ReadMode::Poll(period) => {
+                    info!("Polling probe status every {:?}", period);
+                    let interval = tokio::time::interval(period);
+                    let state = (c, interval, self.recorder.clone(), self.addr);
+                    let values = stream::unfold(state, |(c, mut interval, recorder, addr)| async move {
+                        interval.tick().await;
+                        let reading = match c.read().await {
+                            Ok(value) => {
+                                record(&recorder, EventKind::Status, addr, &value);
+                                decode_reading(&value)
+                            },
+                            Err(e) => Err(e.into()),
+                        };
+                        Some((reading, (c, interval, recorder, addr)))
+                    });
+                    Ok(Box::pin(values))
+                },
+            }
+        }
+
+        /// Downloads log entries `start..=end` from the probe over UART. A `start` of None begins at
+        /// the oldest entry the probe still has. Timestamps are worked out from the probe's sample
+        /// period counting back from its newest entry, which is taken to be now.
+        async fn read_logs(&self, start: Option<u32>, end: u32) -> anyhow::Result<Vec<ProbeReading>> {
+            let status = self.probe_status.as_ref().ok_or(anyhow::anyhow!("No probe status characteristic found"))?;
+            let rx = self.uart_rx.as_ref().ok_or(anyhow::anyhow!("No UART RX characteristic found"))?;
+            let tx = self.uart_tx.as_ref().ok_or(anyhow::anyhow!("No UART TX characteristic found"))?;
+
+            let status = status.read().await?;
+            record(&self.recorder, EventKind::Status, self.addr, &status);
+            let status = ProbeStatus::decode(&status)?;
+            let now = chrono::Utc::now();
+            let start = start.unwrap_or(status.log_range.min).max(status.log_range.min);
+            let end = end.min(status.log_range.max);
+            if start > end {
+                return Ok(vec![]);
+            }
+
+            let session = match self.uart_request(uart::Request::ReadSessionInfo).await? {
+                uart::Response::SessionInfo(info) => info,
+                r => anyhow::bail!("Unexpected response to session info request: {:?}", r),
+            };
+            info!("Reading logs {}..={} from session {:x} sampled every {}ms", start, end, session.id, session.sample_period_ms);
+
+            let notifications = tx.notify().await?;
+            pin_mut!(notifications);
+            let mut reassembler = uart::Reassembler::new();
+
+            rx.write(&uart::Request::ReadLogs { start, end }.encode()).await?;
+            let expected = (end - start + 1) as usize;
+            let mut entries = BTreeMap::new();
+            while entries.len() < expected {
+                let value = match timeout(UART_TIMEOUT, notifications.next()).await {
+                    Ok(Some(value)) => value,
+                    Ok(None) => break,
+                    Err(_) => {
+                        warn!("Timed out reading logs, got {} of {}", entries.len(), expected);
+                        break;
+                    }
+                };
+                record(&self.recorder, EventKind::Uart, self.addr, &value);
+                for response in reassembler.push(&value) {
+                    let entry = match response {
+                        Ok(uart::Response::Log(entry)) => entry,
+                        Ok(uart::Response::Failed(t)) => anyhow::bail!("Probe rejected {:?}", t),
+                        Ok(r) => {
+                            trace!("Ignoring UART response {:?}", r);
+                            continue;
+                        },
+                        Err(e) => {
+                            warn!("Bad UART response: {}", e);
+                            continue;
+                        },
+                    };
+                    if entry.sequence < start || entry.sequence > end {
+                        continue;
+                    }
+                    let age = (status.log_range.max - entry.sequence) as i64 * session.sample_period_ms as i64;
+                    entries.insert(entry.sequence, ProbeReading {
+                        time: now - chrono::Duration::milliseconds(age),
+                        sequence: Some(entry.sequence),
+                        ..ProbeReading::from_raw(entry.raw_temps)
+                    });
                 }
             }
-            Ok(None)
+
+            Ok(entries.into_values().collect())
+        }
+
+        /// Puts the probe into prediction mode, estimating when the core will reach `target_c`
+        async fn set_prediction(&self, target_c: f32) -> anyhow::Result<()> {
+            info!("Setting prediction to {}C", target_c);
+            self.uart_request(uart::Request::SetPrediction {
+                mode: PredictionMode::TimeToRemoval,
+                set_point_c: target_c,
+            }).await?;
+            Ok(())
+        }
+
+        async fn cancel_prediction(&self) -> anyhow::Result<()> {
+            info!("Cancelling prediction");
+            self.uart_request(uart::Request::SetPrediction {
+                mode: PredictionMode::None,
+                set_point_c: 0.0,
+            }).await?;
+            Ok(())
+        }
+
+        /// BlueZ updates the RSSI from advertisements, which probes keep sending while connected
+        async fn rssi(&self) -> anyhow::Result<Option<i16>> {
+            Ok(self.device.rssi().await?)
+        }
+
+        /// Resolves once BlueZ reports the probe disconnected
+        async fn disconnected(&self) -> anyhow::Result<Disconnected> {
+            let events = self.device.events().await?;
+            Ok(Box::pin(async move {
+                pin_mut!(events);
+                while let Some(evt) = events.next().await {
+                    if let DeviceEvent::PropertyChanged(DeviceProperty::Connected(false)) = evt {
+                        return;
+                    }
+                }
+            }))
         }
 
+        /// Drops a broken connection but keeps the device around so it can be connected again
+        async fn reset_connection(&self) {
+            if let Err(e) = self.device.disconnect().await {
+                warn!("Failed to disconnect from device: {}", e);
+            }
+        }
 
-        pub async fn disconnect(&self) -> anyhow::Result<()> {
+        async fn disconnect(&self) -> anyhow::Result<()> {
             info!("Disconnecting");
             if let Err(e) = self.device.disconnect().await {
                 warn!("Failed to disconnect from device: {}", e);
@@ -195,4 +777,218 @@ pub mod linux {
             Ok(())
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::combustion::bluez_mock::MockBluez;
+
+        const PROBE: &str = "C2:71:04:90:3B:0E";
+        const OTHER: &str = "DE:AD:BE:EF:00:01";
+        // Serial 12345678 on the counter, see extra/repr.c
+        const ADVERTISEMENT: &str = "017856341237c364748c8af1301006c2201828d50000";
+        // Log range 1..=0x1234 then the same temperatures
+        const STATUS: &str = "010000003412000037c364748c8af1301006c2201828d5";
+
+        fn hex(s: &str) -> Vec<u8> {
+            (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+        }
+
+        async fn discover() -> mpsc::Receiver<Combustion> {
+            discover_with(ProbeFilter::default(), KnownProbes::default()).await
+        }
+
+        /// Starts discovery, handing over probes until the receiver is dropped
+        async fn discover_with(filter: ProbeFilter, known: KnownProbes) -> mpsc::Receiver<Combustion> {
+            let finder = CombustionFinder::new(None, filter, known, None).await.unwrap();
+            let (found_tx, found) = mpsc::channel(1);
+            let (shutdown_tx, shutdown) = watch::channel(false);
+            tokio::spawn(async move {
+                let _shutdown_tx = shutdown_tx;
+                finder.discover(found_tx, shutdown).await
+            });
+            found
+        }
+
+        async fn next_probe(found: &mut mpsc::Receiver<Combustion>) -> Combustion {
+            let probe = timeout(Duration::from_secs(15), found.recv()).await.unwrap().unwrap();
+            assert_eq!(probe.serial(), ProbeSerial(0x12345678));
+            probe
+        }
+
+        /// Discovers the probe among other devices and connects to it
+        async fn connected_probe(bluez: &MockBluez) -> Combustion {
+            bluez.add_device(OTHER, None);
+            bluez.add_device(PROBE, Some(hex(ADVERTISEMENT)));
+            bluez.set_status(PROBE, hex(STATUS));
+
+            let mut probe = next_probe(&mut discover().await).await;
+
+            probe.connect().await.unwrap();
+            assert!(bluez.is_connected(PROBE));
+            assert_eq!(probe.device_info().manufacturer.as_deref(), Some("Combustion Inc."));
+            assert_eq!(probe.device_info().firmware_revision.as_deref(), Some("v1.4.2"));
+            assert_eq!(probe.device_info().hardware_revision, None);
+            probe
+        }
+
+        #[tokio::test]
+        async fn discovers_reads_and_drops() {
+            let bluez = MockBluez::start().await;
+            let probe = connected_probe(&bluez).await;
+            assert_eq!(bluez.connects(OTHER), 0);
+
+            let mut polled = probe.readings(ReadMode::Poll(Duration::from_millis(10))).await.unwrap();
+            let reading = polled.next().await.unwrap().unwrap();
+            assert_eq!(reading.sequence, Some(0x1234));
+            assert!((reading.t1() - 21.15).abs() < 0.001);
+
+            let mut notified = probe.readings(ReadMode::Notify).await.unwrap();
+            bluez.set_status(PROBE, hex(&STATUS.replace("3412", "3512")));
+            let reading = timeout(Duration::from_secs(5), notified.next()).await.unwrap().unwrap().unwrap();
+            assert_eq!(reading.sequence, Some(0x1235));
+
+            let disconnected = probe.disconnected().await.unwrap();
+            bluez.drop_connection(PROBE);
+            timeout(Duration::from_secs(5), disconnected).await.unwrap();
+
+            probe.disconnect().await.unwrap();
+            assert!(!bluez.has_device(PROBE));
+        }
+
+        #[tokio::test]
+        async fn logs_and_predictions_over_uart() {
+            let bluez = MockBluez::start().await;
+            let probe = connected_probe(&bluez).await;
+            bluez.set_uart(PROBE, Box::new(|request| match request {
+                uart::Request::ReadSessionInfo => vec![uart::Response::SessionInfo(uart::SessionInfo { id: 7, sample_period_ms: 1000 })],
+                uart::Request::ReadLogs { start, end } => (start..=end)
+                    .map(|sequence| uart::Response::Log(uart::LogEntry { sequence, raw_temps: [823; 8] }))
+                    .collect(),
+                uart::Request::SetPrediction { .. } => vec![uart::Response::SetPrediction],
+                r => vec![uart::Response::Failed(r.message_type())],
+            }));
+
+            let logs = probe.read_logs(Some(0x1230), 0x1240).await.unwrap();
+            assert_eq!(logs.iter().map(|r| r.sequence.unwrap()).collect::<Vec<_>>(), [0x1230, 0x1231, 0x1232, 0x1233, 0x1234]);
+            assert_eq!(logs[4].time - logs[0].time, chrono::Duration::seconds(4));
+
+            probe.set_prediction(57.0).await.unwrap();
+            let (request, _) = uart::Request::decode(bluez.uart_writes(PROBE).last().unwrap()).unwrap();
+            assert_eq!(request, uart::Request::SetPrediction { mode: PredictionMode::TimeToRemoval, set_point_c: 57.0 });
+        }
+
+        #[tokio::test]
+        async fn evicts_stale_probes() {
+            let bluez = MockBluez::start().await;
+            bluez.add_cached_probe(PROBE);
+            bluez.add_device(OTHER, None);
+
+            let mut found = discover().await;
+            assert!(!bluez.has_device(PROBE));
+            assert!(bluez.has_device(OTHER));
+
+            // Back in range and advertising
+            bluez.add_device(PROBE, Some(hex(ADVERTISEMENT)));
+            next_probe(&mut found).await;
+        }
+
+        #[tokio::test]
+        async fn reuses_remembered_probes() {
+            let bluez = MockBluez::start().await;
+            bluez.add_device(PROBE, Some(hex(ADVERTISEMENT)));
+            let mut found = discover().await;
+            next_probe(&mut found).await;
+
+            // BlueZ drops the advertisement but we know the address
+            bluez.remove_device(PROBE);
+            bluez.add_cached_probe(PROBE);
+            next_probe(&mut found).await;
+            assert!(bluez.has_device(PROBE));
+        }
+
+        #[tokio::test]
+        async fn looks_at_devices_concurrently() {
+            let bluez = MockBluez::start().await;
+            let mut found = discover().await;
+            // A crowd of devices that never show anything, then a probe whose advertisement is late
+            for i in 0..20 {
+                bluez.add_device(&format!("DE:AD:BE:EF:01:{:02X}", i), None);
+            }
+            bluez.add_device(PROBE, None);
+            sleep(Duration::from_millis(500)).await;
+            bluez.set_manufacturer_data(PROBE, hex(ADVERTISEMENT));
+
+            let started = std::time::Instant::now();
+            next_probe(&mut found).await;
+            assert!(started.elapsed() < ADVERTISEMENT_TIMEOUT);
+        }
+
+        #[tokio::test]
+        async fn only_accepts_allowed_probes() {
+            let bluez = MockBluez::start().await;
+            // Someone else's probe, serial 87654321
+            bluez.add_device(OTHER, Some(hex(&ADVERTISEMENT.replace("78563412", "21436587"))));
+            bluez.add_device(PROBE, Some(hex(ADVERTISEMENT)));
+
+            let mut found = discover_with(ProbeFilter::new(vec![ProbeSerial(0x12345678)], vec![]), KnownProbes::default()).await;
+            assert_eq!(next_probe(&mut found).await.addr, PROBE.parse().unwrap());
+            assert!(timeout(Duration::from_secs(1), found.recv()).await.is_err());
+        }
+
+        /// A state file remembering `probes`, in a directory of its own
+        fn known(name: &str, probes: &[&str]) -> (KnownProbes, std::path::PathBuf) {
+            let path = std::env::temp_dir().join(format!("rustbustion-{}-{}/probes", name, std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            let mut known = KnownProbes::load(&path).unwrap();
+            for addr in probes {
+                known.remember(addr, ProbeSerial(0x12345678));
+            }
+            (KnownProbes::load(&path).unwrap(), path)
+        }
+
+        #[tokio::test]
+        async fn connects_to_remembered_probes_and_keeps_scanning() {
+            let bluez = MockBluez::start().await;
+            // One BlueZ still has cached, one it forgot
+            bluez.add_cached_probe(PROBE);
+            bluez.set_status(PROBE, hex(STATUS));
+            bluez.put_in_range(OTHER, hex(STATUS));
+            let (known, path) = known("direct", &[PROBE, OTHER]);
+
+            let mut found = discover_with(ProbeFilter::default(), known).await;
+            let mut probes = [next_probe(&mut found).await, next_probe(&mut found).await];
+            for probe in probes.iter_mut() {
+                probe.connect().await.unwrap();
+            }
+            assert!(bluez.is_connected(PROBE));
+            assert!(bluez.is_connected(OTHER));
+
+            // A probe that's never been seen still joins, serial 87654321
+            const NEW: &str = "DE:AD:BE:EF:00:02";
+            bluez.add_device(NEW, Some(hex(&ADVERTISEMENT.replace("78563412", "21436587"))));
+            loop {
+                let probe = timeout(Duration::from_secs(15), found.recv()).await.unwrap().unwrap();
+                if probe.serial() == ProbeSerial(0x87654321) {
+                    assert_eq!(probe.addr, NEW.parse().unwrap());
+                    break;
+                }
+            }
+            assert!(bluez.scanned());
+            let _ = std::fs::remove_dir_all(path.parent().unwrap());
+        }
+
+        #[tokio::test]
+        async fn scans_when_no_remembered_probe_answers() {
+            let bluez = MockBluez::start().await;
+            bluez.add_device(PROBE, Some(hex(ADVERTISEMENT)));
+            let (known, path) = known("scan", &[OTHER]);
+
+            let mut found = discover_with(ProbeFilter::default(), known).await;
+            next_probe(&mut found).await;
+            assert!(bluez.scanned());
+            assert_eq!(KnownProbes::load(&path).unwrap().get(PROBE), Some(ProbeSerial(0x12345678)));
+            let _ = std::fs::remove_dir_all(path.parent().unwrap());
+        }
+    }
 }
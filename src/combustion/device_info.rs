@@ -0,0 +1,24 @@
+// What a probe says about itself in the standard Device Information service
+use serde_json::json;
+
+/// The Device Information strings, each None if the probe didn't have it
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub manufacturer: Option<String>,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    pub firmware_revision: Option<String>,
+    pub hardware_revision: Option<String>,
+}
+
+impl DeviceInfo {
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "manufacturer": self.manufacturer,
+            "model": self.model,
+            "serial": self.serial,
+            "firmware_revision": self.firmware_revision,
+            "hardware_revision": self.hardware_revision,
+        })
+    }
+}
@@ -0,0 +1,176 @@
+// A thermometer that random walks its temperature, for running without Bluetooth
+use futures::stream;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+
+use crate::combustion::{Advertisement, BatteryStatus, DeviceInfo, Disconnected, PredictionMode, PredictionState, PredictionStatus, PredictionType, ProbeColor, ProbeMode, ProbeReading, ProbeSerial, ProductType, ReadMode, ReadingStream, Thermometer, ThermometerSource, VirtualSensors, NUM_SENSORS};
+
+// A probe right next to the Pi
+const RSSI: i16 = -60;
+
+#[derive(Default)]
+pub struct FakeFinder {
+}
+
+impl FakeFinder {
+    pub fn new() -> FakeFinder {
+        FakeFinder::default()
+    }
+}
+
+impl ThermometerSource for FakeFinder {
+    type Thermometer = FakeCombustion;
+
+    /// Hands over a single fake probe then waits for shutdown
+    async fn discover(&self, found: mpsc::Sender<FakeCombustion>, mut shutdown: watch::Receiver<bool>) -> anyhow::Result<()> {
+        found.send(FakeCombustion::new(ProbeSerial(rand::random()), rand::random::<f32>() * 10.0 + 20.0)).await?;
+        let _ = shutdown.changed().await;
+        Ok(())
+    }
+
+    /// Advertises a single fake probe every 2 seconds until shutdown
+    async fn advertisements(&self, found: mpsc::Sender<Advertisement>, mut shutdown: watch::Receiver<bool>) -> anyhow::Result<()> {
+        let serial = ProbeSerial(rand::random());
+        let mut t = rand::random::<f32>() * 10.0 + 20.0;
+        let mut interval = tokio::time::interval(Duration::from_secs(2));
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    t += rand::random::<f32>() * 2.0 - 1.0;
+                    let mut raw_temps = [0; NUM_SENSORS];
+                    for (i, raw) in raw_temps.iter_mut().enumerate() {
+                        *raw = ((t + i as f32 * 2.0 + 20.0) / 0.05) as u16;
+                    }
+                    found.send(Advertisement {
+                        product_type: ProductType::Probe,
+                        serial,
+                        raw_temps,
+                        mode: ProbeMode::Normal,
+                        id: 1,
+                        color: ProbeColor::Yellow,
+                        battery: BatteryStatus::Ok,
+                        virtual_sensors: VirtualSensors { core: 0, surface: 3, ambient: 7 },
+                        rssi: Some(RSSI),
+                    }).await?;
+                }
+                _ = shutdown.changed() => return Ok(()),
+            }
+        }
+    }
+}
+
+pub struct FakeCombustion {
+    serial: ProbeSerial,
+    temp: Arc<Mutex<f32>>,
+    target: Arc<Mutex<Option<f32>>>,
+}
+
+impl FakeCombustion {
+    pub fn new(serial: ProbeSerial, temp: f32) -> FakeCombustion {
+        let t = Arc::new(Mutex::new(temp));
+        let t_c = t.clone();
+        std::thread::spawn(move || {
+            loop {
+                std::thread::sleep(std::time::Duration::from_secs(2));
+                {
+                    let mut t = t_c.lock().unwrap();
+                    // Generate a delta between [-1, 1]
+                    let delta = rand::random::<f32>() * 2.0 - 1.0;
+                    *t += delta;
+                }
+            }
+        });
+        FakeCombustion {
+            serial,
+            temp: t,
+            target: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+impl Thermometer for FakeCombustion {
+    fn serial(&self) -> ProbeSerial {
+        self.serial
+    }
+
+    async fn connect(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn device_info(&self) -> DeviceInfo {
+        DeviceInfo {
+            manufacturer: Some("Rustbustion".to_string()),
+            model: Some("Fake probe".to_string()),
+            serial: Some(self.serial.to_string()),
+            ..DeviceInfo::default()
+        }
+    }
+
+    /// Emits the fake temperature on the poll interval, or as often as it changes when "notifying"
+    async fn readings(&self, mode: ReadMode) -> anyhow::Result<ReadingStream> {
+        let period = match mode {
+            ReadMode::Notify => Duration::from_secs(2),
+            ReadMode::Poll(period) => period,
+        };
+        let interval = tokio::time::interval(period);
+        let state = (self.temp.clone(), self.target.clone(), interval);
+        let values = stream::unfold(state, |(temp, target, mut interval)| async move {
+            interval.tick().await;
+            let t = *temp.lock().unwrap();
+            // Fake a gradient where the tip is the coldest and the handle the hottest
+            let mut temps = [0.0; NUM_SENSORS];
+            for (i, temp) in temps.iter_mut().enumerate() {
+                *temp = t + i as f32 * 2.0;
+            }
+            // Pretend the core climbs a degree a minute towards the target
+            let prediction = target.lock().unwrap().map(|set_point_c| PredictionStatus {
+                state: PredictionState::Predicting,
+                mode: PredictionMode::TimeToRemoval,
+                kind: PredictionType::Removal,
+                set_point_c,
+                heat_start_c: t,
+                seconds_remaining: ((set_point_c - t).max(0.0) * 60.0) as u32,
+                estimated_core_c: t,
+            });
+            let reading = ProbeReading {
+                prediction,
+                battery: Some(BatteryStatus::Ok),
+                ..ProbeReading::new(temps)
+            };
+            Some((Ok(reading), (temp, target, interval)))
+        });
+        Ok(Box::pin(values))
+    }
+
+    /// The fake doesn't keep a log so there's never anything to backfill
+    async fn read_logs(&self, _start: Option<u32>, _end: u32) -> anyhow::Result<Vec<ProbeReading>> {
+        Ok(vec![])
+    }
+
+    async fn set_prediction(&self, target_c: f32) -> anyhow::Result<()> {
+        self.target.lock().unwrap().replace(target_c);
+        Ok(())
+    }
+
+    async fn cancel_prediction(&self) -> anyhow::Result<()> {
+        self.target.lock().unwrap().take();
+        Ok(())
+    }
+
+    async fn rssi(&self) -> anyhow::Result<Option<i16>> {
+        Ok(Some(RSSI))
+    }
+
+    /// The fake never drops
+    async fn disconnected(&self) -> anyhow::Result<Disconnected> {
+        Ok(Box::pin(futures::future::pending()))
+    }
+
+    async fn reset_connection(&self) {
+    }
+
+    async fn disconnect(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,102 @@
+// Which probes discovery may connect to, so it doesn't pick up someone else's at a cook-off
+//
+// The allow-list file has a probe per line, by serial or by address, e.g.
+//
+//     # Mine
+//     12345678
+//     C2:71:04:90:3B:0E
+use std::path::{Path, PathBuf};
+
+use crate::combustion::ProbeSerial;
+
+/// Probes allowed by serial or by address. Allows everything if it's empty.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ProbeFilter {
+    serials: Vec<ProbeSerial>,
+    /// Upper case, as BlueZ prints them
+    addresses: Vec<String>,
+}
+
+impl ProbeFilter {
+    pub fn new(serials: Vec<ProbeSerial>, addresses: Vec<String>) -> ProbeFilter {
+        ProbeFilter {
+            serials,
+            addresses: addresses.into_iter().map(|a| a.to_uppercase()).collect(),
+        }
+    }
+
+    /// Where the allow-list goes unless told otherwise, under the user's home directory
+    pub fn default_path() -> Option<PathBuf> {
+        std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config/rustbustion/allowed-probes"))
+    }
+
+    /// Reads the allow-list at `path`, which allows everything if it doesn't exist
+    pub fn load(path: &Path) -> anyhow::Result<ProbeFilter> {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => parse_filter(&contents).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(ProbeFilter::default()),
+            Err(e) => Err(anyhow::anyhow!("{}: {}", path.display(), e)),
+        }
+    }
+
+    /// Also allows everything `other` does
+    pub fn extend(&mut self, other: ProbeFilter) {
+        self.serials.extend(other.serials);
+        self.addresses.extend(other.addresses);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.serials.is_empty() && self.addresses.is_empty()
+    }
+
+    /// Why the probe isn't allowed, or None if it is
+    pub fn rejection(&self, address: &str, serial: ProbeSerial) -> Option<String> {
+        if self.is_empty() || self.serials.contains(&serial) || self.addresses.contains(&address.to_uppercase()) {
+            return None;
+        }
+        let serials = self.serials.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        Some(match (serials.is_empty(), self.addresses.is_empty()) {
+            (false, true) => format!("serial {} isn't one of {}", serial, serials.join(", ")),
+            (true, false) => format!("address {} isn't one of {}", address, self.addresses.join(", ")),
+            _ => format!(
+                "neither serial {} nor address {} is allowed (serials {}, addresses {})",
+                serial, address, serials.join(", "), self.addresses.join(", "),
+            ),
+        })
+    }
+}
+
+/// Parses an allow-list, skipping blank lines and `#` comments. Anything with a colon in it is
+/// an address.
+fn parse_filter(contents: &str) -> anyhow::Result<ProbeFilter> {
+    let (mut serials, mut addresses) = (vec![], vec![]);
+    for line in contents.lines().map(|l| l.trim()).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+        if line.contains(':') {
+            addresses.push(line.to_string());
+        } else {
+            serials.push(line.parse().map_err(|e| anyhow::anyhow!("Bad probe serial {:?}: {}", line, e))?);
+        }
+    }
+    Ok(ProbeFilter::new(serials, addresses))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_by_serial_or_address() {
+        let (mine, theirs) = (ProbeSerial(0x12345678), ProbeSerial(0x87654321));
+        assert_eq!(ProbeFilter::default().rejection("C2:71:04:90:3B:0E", theirs), None);
+
+        let mut filter = ProbeFilter::new(vec![mine], vec![]);
+        assert_eq!(filter.rejection("C2:71:04:90:3B:0E", mine), None);
+        assert_eq!(filter.rejection("C2:71:04:90:3B:0E", theirs).unwrap(), "serial 87654321 isn't one of 12345678");
+
+        filter.extend(parse_filter("# Borrowed\nc2:71:04:90:3b:0e\n\n").unwrap());
+        assert_eq!(filter.rejection("C2:71:04:90:3B:0E", theirs), None);
+        assert!(filter.rejection("DE:AD:BE:EF:00:01", theirs).unwrap().starts_with("neither serial 87654321 nor address DE:AD:BE:EF:00:01"));
+
+        assert!(parse_filter("zz").is_err());
+    }
+}
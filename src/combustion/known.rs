@@ -0,0 +1,118 @@
+// Probes used on earlier runs, so the daemon can connect to them straight away instead of scanning
+//
+// A small state file with one probe per line, its address and its serial, e.g.
+//
+//     C2:71:04:90:3B:0E 12345678
+use log::warn;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::combustion::ProbeSerial;
+
+/// Probe addresses and serials, saved to the state file whenever a new one is remembered
+#[derive(Debug, Default)]
+pub struct KnownProbes {
+    /// Nothing is saved without one
+    path: Option<PathBuf>,
+    probes: BTreeMap<String, ProbeSerial>,
+}
+
+impl KnownProbes {
+    /// Where the state file goes unless told otherwise, under the user's home directory
+    pub fn default_path() -> Option<PathBuf> {
+        std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state/rustbustion/probes"))
+    }
+
+    /// Reads the state file at `path`, which doesn't have to exist yet. Lines that don't parse are
+    /// skipped.
+    pub fn load(path: &Path) -> anyhow::Result<KnownProbes> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(anyhow::anyhow!("{}: {}", path.display(), e)),
+        };
+        Ok(KnownProbes {
+            path: Some(path.to_path_buf()),
+            probes: parse_known(&contents),
+        })
+    }
+
+    pub fn get(&self, address: &str) -> Option<ProbeSerial> {
+        self.probes.get(address).copied()
+    }
+
+    pub fn probes(&self) -> Vec<(String, ProbeSerial)> {
+        self.probes.iter().map(|(address, serial)| (address.clone(), *serial)).collect()
+    }
+
+    /// Remembers the probe at `address`, saving the state file if it's new
+    pub fn remember(&mut self, address: &str, serial: ProbeSerial) {
+        if self.probes.insert(address.to_string(), serial) == Some(serial) {
+            return;
+        }
+        if let Err(e) = self.save() {
+            warn!("Couldn't save known probes: {:?}", e);
+        }
+    }
+
+    /// Writes a new file and renames it over the old one, so a crash never leaves half a file
+    fn save(&self) -> anyhow::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, format_known(&self.probes))?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+fn format_known(probes: &BTreeMap<String, ProbeSerial>) -> String {
+    probes.iter().map(|(address, serial)| format!("{} {}\n", address, serial)).collect()
+}
+
+fn parse_known(contents: &str) -> BTreeMap<String, ProbeSerial> {
+    let mut probes = BTreeMap::new();
+    for line in contents.lines().map(|l| l.trim()).filter(|l| !l.is_empty()) {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        match parts.as_slice() {
+            [address, serial] => match serial.parse::<ProbeSerial>() {
+                Ok(serial) => {
+                    probes.insert(address.to_string(), serial);
+                },
+                Err(e) => warn!("Skipping known probe with a bad serial {:?}: {}", line, e),
+            },
+            _ => warn!("Skipping known probe line {:?}", line),
+        }
+    }
+    probes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remembers_across_loads() {
+        let path = std::env::temp_dir().join(format!("rustbustion-known-{}/probes", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut known = KnownProbes::load(&path).unwrap();
+        assert!(known.probes().is_empty());
+        known.remember("C2:71:04:90:3B:0E", ProbeSerial(0x12345678));
+        known.remember("DE:AD:BE:EF:00:01", ProbeSerial(0x1));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "C2:71:04:90:3B:0E 12345678\nDE:AD:BE:EF:00:01 00000001\n");
+
+        std::fs::write(&path, "C2:71:04:90:3B:0E 12345678\nnonsense\nDE:AD:BE:EF:00:01 zz\n").unwrap();
+        let known = KnownProbes::load(&path).unwrap();
+        assert_eq!(known.probes(), [("C2:71:04:90:3B:0E".to_string(), ProbeSerial(0x12345678))]);
+        assert_eq!(known.get("C2:71:04:90:3B:0E"), Some(ProbeSerial(0x12345678)));
+        assert_eq!(known.get("DE:AD:BE:EF:00:01"), None);
+
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    }
+}
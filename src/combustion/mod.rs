@@ -1,9 +1,57 @@
-// Re-exports the implementations
+// Re-exports the thermometer model and backends
 
-mod combustion_macos;
-#[cfg(target_os="macos")]
-pub use self::combustion_macos::macos::*;
+mod reading;
+pub use self::reading::*;
 
+// Without BlueZ nothing decodes what comes off the air
+#[cfg_attr(not(all(target_os="linux", feature="bluer")), allow(dead_code))]
+mod probe_status;
+pub use self::probe_status::*;
+
+#[cfg_attr(not(all(target_os="linux", feature="bluer")), allow(dead_code))]
+mod advertisement;
+pub use self::advertisement::*;
+
+#[cfg_attr(not(all(target_os="linux", feature="bluer")), allow(dead_code))]
+pub mod uart;
+
+mod device_info;
+pub use self::device_info::*;
+
+mod source;
+pub use self::source::*;
+
+mod fake;
+pub use self::fake::*;
+
+mod simulator;
+pub use self::simulator::*;
+
+#[cfg_attr(not(all(target_os="linux", feature="bluer")), allow(dead_code))]
+mod capture;
+pub use self::capture::*;
+
+mod replay;
+pub use self::replay::*;
+
+mod session;
+pub use self::session::*;
+
+// Only BlueZ connects to probes by address
+#[cfg_attr(not(all(target_os="linux", feature="bluer")), allow(dead_code))]
+mod known;
+#[cfg(all(target_os="linux", feature="bluer"))]
+pub use self::known::*;
+
+#[cfg_attr(not(all(target_os="linux", feature="bluer")), allow(dead_code))]
+mod filter;
+#[cfg(all(target_os="linux", feature="bluer"))]
+pub use self::filter::*;
+
+// BlueZ only exists on Linux, everywhere else there's just the fake
 mod combustion_linux;
-#[cfg(target_os="linux")]
+#[cfg(all(target_os="linux", feature="bluer"))]
 pub use self::combustion_linux::linux::*;
+
+#[cfg(all(test, target_os="linux", feature="bluer"))]
+mod bluez_mock;
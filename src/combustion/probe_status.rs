@@ -0,0 +1,530 @@
+// Decoder for the Probe Status characteristic
+//
+// Everything on the wire is little-endian and the bit packed fields are laid out least significant
+// bit first, the same as a packed C bitfield on a little-endian machine (see extra/repr.c).
+
+use crate::combustion::{ProbeReading, NUM_SENSORS};
+
+const LOG_RANGE_LEN: usize = 8;
+const RAW_TEMPS_LEN: usize = 13;
+const RAW_TEMPS_OFFSET: usize = LOG_RANGE_LEN;
+const MODE_ID_OFFSET: usize = RAW_TEMPS_OFFSET + RAW_TEMPS_LEN;
+const BATTERY_OFFSET: usize = MODE_ID_OFFSET + 1;
+const PREDICTION_OFFSET: usize = BATTERY_OFFSET + 1;
+const PREDICTION_LEN: usize = 7;
+const FOOD_SAFE_DATA_OFFSET: usize = PREDICTION_OFFSET + PREDICTION_LEN;
+const FOOD_SAFE_DATA_LEN: usize = 10;
+const FOOD_SAFE_STATUS_OFFSET: usize = FOOD_SAFE_DATA_OFFSET + FOOD_SAFE_DATA_LEN;
+const FOOD_SAFE_STATUS_LEN: usize = 8;
+
+/// The shortest payload we can make sense of: log range, temperatures, mode/ID and battery
+pub const MIN_PROBE_STATUS_LEN: usize = PREDICTION_OFFSET;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProbeStatusError {
+    TooShort { len: usize, expected: usize },
+    InvalidLogRange { min: u32, max: u32 },
+    InvalidVirtualCore(u8),
+}
+
+impl std::fmt::Display for ProbeStatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ProbeStatusError::TooShort { len, expected } => write!(f, "probe status too short: got {} bytes, need at least {}", len, expected),
+            ProbeStatusError::InvalidLogRange { min, max } => write!(f, "invalid log range: min {} > max {}", min, max),
+            ProbeStatusError::InvalidVirtualCore(v) => write!(f, "invalid virtual core sensor {}", v),
+        }
+    }
+}
+
+impl std::error::Error for ProbeStatusError {}
+
+/// A virtual core sensor number past T6, shared by Probe Status and advertisements
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InvalidVirtualCore(pub u8);
+
+impl From<InvalidVirtualCore> for ProbeStatusError {
+    fn from(e: InvalidVirtualCore) -> ProbeStatusError {
+        ProbeStatusError::InvalidVirtualCore(e.0)
+    }
+}
+
+/// Reads `len` bits starting at bit `offset`, least significant bit first
+pub(crate) fn read_bits(data: &[u8], offset: usize, len: usize) -> u32 {
+    let mut value = 0;
+    for i in 0..len {
+        let bit = offset + i;
+        if (data[bit / 8] >> (bit % 8)) & 1 == 1 {
+            value |= 1 << i;
+        }
+    }
+    value
+}
+
+/// Unpacks the 13 byte raw temperature block into eight 13 bit thermistor values
+pub(crate) fn decode_raw_temps(data: &[u8]) -> [u16; NUM_SENSORS] {
+    let mut raw = [0; NUM_SENSORS];
+    for (i, r) in raw.iter_mut().enumerate() {
+        *r = read_bits(data, i * 13, 13) as u16;
+    }
+    raw
+}
+
+/// Unpacks the mode/color/ID byte, with the ID numbered 1-8 like the probe's label
+pub(crate) fn decode_mode_id(v: u8) -> (ProbeMode, ProbeColor, u8) {
+    let data = [v];
+    (read_bits(&data, 0, 2).into(), read_bits(&data, 2, 3).into(), read_bits(&data, 5, 3) as u8 + 1)
+}
+
+/// Unpacks the battery status and virtual sensors byte
+pub(crate) fn decode_battery(v: u8) -> Result<(BatteryStatus, VirtualSensors), InvalidVirtualCore> {
+    let battery = if v & 1 == 1 { BatteryStatus::Low } else { BatteryStatus::Ok };
+    Ok((battery, VirtualSensors::decode(u32::from(v >> 1))?))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().expect("4"))
+}
+
+/// Range of sequence numbers held in the probe's on-board log
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LogRange {
+    pub min: u32,
+    pub max: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProbeMode {
+    Normal,
+    InstantRead,
+    Reserved,
+    Error,
+}
+
+impl From<u32> for ProbeMode {
+    fn from(v: u32) -> ProbeMode {
+        match v {
+            0 => ProbeMode::Normal,
+            1 => ProbeMode::InstantRead,
+            2 => ProbeMode::Reserved,
+            _ => ProbeMode::Error,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProbeColor {
+    Yellow,
+    Grey,
+    Red,
+    Orange,
+    Blue,
+    Green,
+    Purple,
+    Pink,
+}
+
+impl From<u32> for ProbeColor {
+    fn from(v: u32) -> ProbeColor {
+        match v {
+            0 => ProbeColor::Yellow,
+            1 => ProbeColor::Grey,
+            2 => ProbeColor::Red,
+            3 => ProbeColor::Orange,
+            4 => ProbeColor::Blue,
+            5 => ProbeColor::Green,
+            6 => ProbeColor::Purple,
+            _ => ProbeColor::Pink,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BatteryStatus {
+    Ok,
+    Low,
+}
+
+impl std::fmt::Display for BatteryStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let value = match self {
+            BatteryStatus::Ok => "ok",
+            BatteryStatus::Low => "low",
+        };
+        write!(f, "{}", value)
+    }
+}
+
+/// Which of T1-T8 the probe picked for each virtual sensor, as indexes into the temperatures
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VirtualSensors {
+    /// One of T1-T6
+    pub core: usize,
+    /// One of T4-T7
+    pub surface: usize,
+    /// One of T5-T8
+    pub ambient: usize,
+}
+
+impl VirtualSensors {
+    /// Decodes the 7 bit virtual sensor field
+    pub(crate) fn decode(v: u32) -> Result<VirtualSensors, InvalidVirtualCore> {
+        let core = v & 0x7;
+        if core > 5 {
+            return Err(InvalidVirtualCore(core as u8));
+        }
+        Ok(VirtualSensors {
+            core: core as usize,
+            surface: 3 + ((v >> 3) & 0x3) as usize,
+            ambient: 4 + ((v >> 5) & 0x3) as usize,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PredictionState {
+    ProbeNotInserted,
+    ProbeInserted,
+    Warming,
+    Predicting,
+    RemovalPredictionDone,
+    Reserved(u8),
+    Unknown,
+}
+
+impl From<u32> for PredictionState {
+    fn from(v: u32) -> PredictionState {
+        match v {
+            0 => PredictionState::ProbeNotInserted,
+            1 => PredictionState::ProbeInserted,
+            2 => PredictionState::Warming,
+            3 => PredictionState::Predicting,
+            4 => PredictionState::RemovalPredictionDone,
+            15 => PredictionState::Unknown,
+            v => PredictionState::Reserved(v as u8),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PredictionMode {
+    None,
+    TimeToRemoval,
+    RemovalAndResting,
+    Reserved,
+}
+
+impl From<u32> for PredictionMode {
+    fn from(v: u32) -> PredictionMode {
+        match v {
+            0 => PredictionMode::None,
+            1 => PredictionMode::TimeToRemoval,
+            2 => PredictionMode::RemovalAndResting,
+            _ => PredictionMode::Reserved,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PredictionType {
+    None,
+    Removal,
+    Resting,
+    Reserved,
+}
+
+impl From<u32> for PredictionType {
+    fn from(v: u32) -> PredictionType {
+        match v {
+            0 => PredictionType::None,
+            1 => PredictionType::Removal,
+            2 => PredictionType::Resting,
+            _ => PredictionType::Reserved,
+        }
+    }
+}
+
+/// The probe's own prediction of when the core reaches the set point
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PredictionStatus {
+    pub state: PredictionState,
+    pub mode: PredictionMode,
+    pub kind: PredictionType,
+    pub set_point_c: f32,
+    pub heat_start_c: f32,
+    pub seconds_remaining: u32,
+    pub estimated_core_c: f32,
+}
+
+impl PredictionStatus {
+    /// Decodes the 56 bit prediction status block
+    pub(crate) fn decode(data: &[u8]) -> PredictionStatus {
+        PredictionStatus {
+            state: read_bits(data, 0, 4).into(),
+            mode: read_bits(data, 4, 2).into(),
+            kind: read_bits(data, 6, 2).into(),
+            set_point_c: read_bits(data, 8, 10) as f32 * 0.1,
+            heat_start_c: read_bits(data, 18, 10) as f32 * 0.1,
+            seconds_remaining: read_bits(data, 28, 17),
+            estimated_core_c: read_bits(data, 45, 11) as f32 * 0.1 - 20.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FoodSafeMode {
+    Simplified,
+    Integrated,
+    Reserved(u8),
+}
+
+impl From<u32> for FoodSafeMode {
+    fn from(v: u32) -> FoodSafeMode {
+        match v {
+            0 => FoodSafeMode::Simplified,
+            1 => FoodSafeMode::Integrated,
+            v => FoodSafeMode::Reserved(v as u8),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FoodSafeState {
+    NotSafe,
+    Safe,
+    SafetyImpossible,
+    Reserved(u8),
+}
+
+impl From<u32> for FoodSafeState {
+    fn from(v: u32) -> FoodSafeState {
+        match v {
+            0 => FoodSafeState::NotSafe,
+            1 => FoodSafeState::Safe,
+            2 => FoodSafeState::SafetyImpossible,
+            v => FoodSafeState::Reserved(v as u8),
+        }
+    }
+}
+
+/// The food safety program configured on the probe
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FoodSafeData {
+    pub mode: FoodSafeMode,
+    pub product: u16,
+    pub serving: u8,
+    pub threshold_c: f32,
+    pub z_value_c: f32,
+    pub reference_c: f32,
+    pub d_value_s: f32,
+    pub target_log_reduction: f32,
+}
+
+impl FoodSafeData {
+    fn decode(data: &[u8]) -> FoodSafeData {
+        FoodSafeData {
+            mode: read_bits(data, 0, 3).into(),
+            product: read_bits(data, 3, 10) as u16,
+            serving: read_bits(data, 13, 3) as u8,
+            threshold_c: read_bits(data, 16, 13) as f32 * 0.05,
+            z_value_c: read_bits(data, 29, 13) as f32 * 0.05,
+            reference_c: read_bits(data, 42, 13) as f32 * 0.05,
+            d_value_s: read_bits(data, 55, 13) as f32 * 0.05,
+            target_log_reduction: read_bits(data, 68, 8) as f32 * 0.1,
+        }
+    }
+}
+
+/// How far along the food safety program is
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FoodSafeStatus {
+    pub state: FoodSafeState,
+    pub log_reduction: f32,
+    pub seconds_above_threshold: u16,
+    pub log_sequence: u32,
+}
+
+impl FoodSafeStatus {
+    fn decode(data: &[u8]) -> FoodSafeStatus {
+        FoodSafeStatus {
+            state: read_bits(data, 0, 3).into(),
+            log_reduction: read_bits(data, 3, 8) as f32 * 0.1,
+            seconds_above_threshold: read_bits(data, 11, 16) as u16,
+            log_sequence: read_bits(data, 27, 32),
+        }
+    }
+}
+
+/// Everything in a Probe Status payload. Older firmware stops after the battery byte or the
+/// prediction block so the trailing sections are optional.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProbeStatus {
+    pub log_range: LogRange,
+    pub raw_temps: [u16; NUM_SENSORS],
+    pub mode: ProbeMode,
+    /// Probe ID as shown on the probe, 1-8
+    pub id: u8,
+    pub color: ProbeColor,
+    pub battery: BatteryStatus,
+    pub virtual_sensors: VirtualSensors,
+    pub prediction: Option<PredictionStatus>,
+    pub food_safe: Option<(FoodSafeData, FoodSafeStatus)>,
+}
+
+impl ProbeStatus {
+    pub fn decode(data: &[u8]) -> Result<ProbeStatus, ProbeStatusError> {
+        if data.len() < MIN_PROBE_STATUS_LEN {
+            return Err(ProbeStatusError::TooShort { len: data.len(), expected: MIN_PROBE_STATUS_LEN });
+        }
+
+        let log_range = LogRange {
+            min: read_u32(data, 0),
+            max: read_u32(data, 4),
+        };
+        if log_range.min > log_range.max {
+            return Err(ProbeStatusError::InvalidLogRange { min: log_range.min, max: log_range.max });
+        }
+
+        let raw_temps = decode_raw_temps(&data[RAW_TEMPS_OFFSET..MODE_ID_OFFSET]);
+
+        let (mode, color, id) = decode_mode_id(data[MODE_ID_OFFSET]);
+        let (battery, virtual_sensors) = decode_battery(data[BATTERY_OFFSET])?;
+
+        let prediction = data
+            .get(PREDICTION_OFFSET..FOOD_SAFE_DATA_OFFSET)
+            .map(PredictionStatus::decode);
+        let food_safe = data
+            .get(FOOD_SAFE_DATA_OFFSET..FOOD_SAFE_STATUS_OFFSET + FOOD_SAFE_STATUS_LEN)
+            .map(|fs| {
+                let (fs_data, fs_status) = fs.split_at(FOOD_SAFE_DATA_LEN);
+                (FoodSafeData::decode(fs_data), FoodSafeStatus::decode(fs_status))
+            });
+
+        Ok(ProbeStatus {
+            log_range,
+            raw_temps,
+            mode,
+            color,
+            id,
+            battery,
+            virtual_sensors,
+            prediction,
+            food_safe,
+        })
+    }
+
+    /// The current temperatures, prediction, battery and virtual sensors, tagged with the newest
+    /// sequence number in the log
+    pub fn reading(&self) -> ProbeReading {
+        ProbeReading {
+            sequence: Some(self.log_range.max),
+            prediction: self.prediction,
+            battery: Some(self.battery),
+            virtual_sensors: Some(self.virtual_sensors),
+            ..ProbeReading::from_raw(self.raw_temps)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Captured from a probe sitting on the counter, see extra/repr.c
+    const RAW_TEMPS: [u8; 13] = [0x37, 0xc3, 0x64, 0x74, 0x8c, 0x8a, 0xf1, 0x30, 0x10, 0x06, 0xc2, 0x20, 0x18];
+
+    // Log range 1..=0x1234, RAW_TEMPS, normal mode, red, ID 2, low battery, core T3, surface T5,
+    // ambient T8, predicting removal at 57.0C from 21.5C with an hour left and core at 45.0C,
+    // then a food safe block
+    const FULL: [u8; 48] = [
+        0x01, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00,
+        0x37, 0xc3, 0x64, 0x74, 0x8c, 0x8a, 0xf1, 0x30, 0x10, 0x06, 0xc2, 0x20, 0x18,
+        0x28,
+        0xd5,
+        0x53, 0x3a, 0x5e, 0x03, 0xe1, 0x40, 0x51,
+        0x08, 0x00, 0x40, 0xe4, 0x0d, 0xe0, 0x15, 0x64, 0x60, 0x04,
+        0x41, 0x62, 0x09, 0x00, 0x90, 0x00, 0x00, 0x00,
+    ];
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 0.001, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn raw_temps() {
+        let raw = decode_raw_temps(&RAW_TEMPS);
+        assert_eq!(raw, [823, 806, 797, 789, 783, 776, 776, 772]);
+        let reading = ProbeReading::from_raw(raw);
+        assert_close(reading.t1(), 21.15);
+        assert_close(reading.temps_c[7], 18.6);
+    }
+
+    #[test]
+    fn full_status() {
+        let status = ProbeStatus::decode(&FULL).unwrap();
+        assert_eq!(status.log_range, LogRange { min: 1, max: 0x1234 });
+        assert_eq!(status.raw_temps, [823, 806, 797, 789, 783, 776, 776, 772]);
+        assert_eq!(status.mode, ProbeMode::Normal);
+        assert_eq!(status.color, ProbeColor::Red);
+        assert_eq!(status.id, 2);
+        assert_eq!(status.battery, BatteryStatus::Low);
+        assert_eq!(status.reading().battery, Some(BatteryStatus::Low));
+        assert_close(status.reading().core_c(), 19.85);
+        assert_close(status.reading().ambient_c(), 18.6);
+        assert_eq!(status.virtual_sensors, VirtualSensors { core: 2, surface: 4, ambient: 7 });
+
+        let prediction = status.prediction.unwrap();
+        assert_eq!(prediction.state, PredictionState::Predicting);
+        assert_eq!(prediction.mode, PredictionMode::TimeToRemoval);
+        assert_eq!(prediction.kind, PredictionType::Removal);
+        assert_close(prediction.set_point_c, 57.0);
+        assert_close(prediction.heat_start_c, 21.5);
+        assert_eq!(prediction.seconds_remaining, 3600);
+        assert_close(prediction.estimated_core_c, 45.0);
+
+        let (data, safe) = status.food_safe.unwrap();
+        assert_eq!(data.mode, FoodSafeMode::Simplified);
+        assert_eq!(data.product, 1);
+        assert_eq!(data.serving, 0);
+        assert_close(data.threshold_c, 54.4);
+        assert_close(data.z_value_c, 5.55);
+        assert_close(data.reference_c, 70.0);
+        assert_close(data.d_value_s, 10.0);
+        assert_close(data.target_log_reduction, 7.0);
+        assert_eq!(safe.state, FoodSafeState::Safe);
+        assert_close(safe.log_reduction, 7.2);
+        assert_eq!(safe.seconds_above_threshold, 300);
+        assert_eq!(safe.log_sequence, 0x1200);
+    }
+
+    #[test]
+    fn older_firmware_without_prediction() {
+        let status = ProbeStatus::decode(&FULL[..MIN_PROBE_STATUS_LEN]).unwrap();
+        assert_eq!(status.id, 2);
+        assert!(status.prediction.is_none());
+        assert!(status.food_safe.is_none());
+
+        let status = ProbeStatus::decode(&FULL[..FOOD_SAFE_DATA_OFFSET]).unwrap();
+        assert!(status.prediction.is_some());
+        assert!(status.food_safe.is_none());
+    }
+
+    #[test]
+    fn too_short() {
+        assert_eq!(ProbeStatus::decode(&[]), Err(ProbeStatusError::TooShort { len: 0, expected: 23 }));
+        assert_eq!(ProbeStatus::decode(&FULL[..21]), Err(ProbeStatusError::TooShort { len: 21, expected: 23 }));
+    }
+
+    #[test]
+    fn malformed() {
+        let mut backwards = FULL;
+        backwards[0] = 0xff;
+        backwards[4] = 0x00;
+        backwards[5] = 0x00;
+        assert_eq!(ProbeStatus::decode(&backwards), Err(ProbeStatusError::InvalidLogRange { min: 0xff, max: 0 }));
+
+        let mut bad_core = FULL;
+        bad_core[BATTERY_OFFSET] = 0x07 << 1;
+        assert_eq!(ProbeStatus::decode(&bad_core), Err(ProbeStatusError::InvalidVirtualCore(7)));
+    }
+}
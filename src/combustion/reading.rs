@@ -0,0 +1,113 @@
+// Platform independent representation of what the probe measures
+
+use chrono::prelude::*;
+use futures::Stream;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+use crate::combustion::{BatteryStatus, PredictionStatus, VirtualSensors};
+
+/// Number of thermistors on a probe, T1 at the tip through T8 at the handle
+pub const NUM_SENSORS: usize = 8;
+
+/// Converts a 13 bit raw thermistor value into degrees Celsius
+pub fn raw_to_celsius(raw: u16) -> f32 {
+    raw as f32 * 0.05 - 20.0
+}
+
+/// One sample of every sensor on the probe in degrees Celsius
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ProbeReading {
+    pub temps_c: [f32; NUM_SENSORS],
+    /// When the sample was taken, which is in the past for readings pulled out of the probe's log
+    pub time: DateTime<Utc>,
+    /// The probe's log sequence number for this sample, if it came from a real probe
+    pub sequence: Option<u32>,
+    /// The probe's prediction, when it has been given a target
+    pub prediction: Option<PredictionStatus>,
+    /// The probe's battery, when the reading came from a status or an advertisement rather than
+    /// the log
+    pub battery: Option<BatteryStatus>,
+    /// Which sensors the probe picked as core, surface and ambient, when it said
+    pub virtual_sensors: Option<VirtualSensors>,
+}
+
+impl ProbeReading {
+    pub fn new(temps_c: [f32; NUM_SENSORS]) -> ProbeReading {
+        ProbeReading {
+            temps_c,
+            time: Utc::now(),
+            sequence: None,
+            prediction: None,
+            battery: None,
+            virtual_sensors: None,
+        }
+    }
+
+    pub fn from_raw(raw: [u16; NUM_SENSORS]) -> ProbeReading {
+        ProbeReading::new(raw.map(raw_to_celsius))
+    }
+
+    /// The tip sensor
+    pub fn t1(&self) -> f32 {
+        self.temps_c[0]
+    }
+
+    /// The coldest part of the meat as picked by the probe, or the tip when it didn't say
+    pub fn core_c(&self) -> f32 {
+        self.virtual_sensors.map_or(self.t1(), |v| self.temps_c[v.core])
+    }
+
+    /// The outside of the meat as picked by the probe, or the tip when it didn't say
+    pub fn surface_c(&self) -> f32 {
+        self.virtual_sensors.map_or(self.t1(), |v| self.temps_c[v.surface])
+    }
+
+    /// The air around the meat as picked by the probe, or the tip when it didn't say
+    pub fn ambient_c(&self) -> f32 {
+        self.virtual_sensors.map_or(self.t1(), |v| self.temps_c[v.ambient])
+    }
+
+    /// The ambient sensor, or the handle end (T8) when the probe didn't say, which is the sensor
+    /// most likely to be out of the meat. For working out how the meat heats, where going with the
+    /// tip would have the air as cold as the core.
+    pub fn air_c(&self) -> f32 {
+        match self.virtual_sensors {
+            Some(_) => self.ambient_c(),
+            None => self.temps_c[NUM_SENSORS - 1],
+        }
+    }
+}
+
+/// When the cooks in the tests start
+#[cfg(test)]
+pub fn test_start() -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap()
+}
+
+#[cfg(test)]
+impl ProbeReading {
+    /// A reading `seconds` into a test cook
+    pub fn at(seconds: f32, temps_c: [f32; NUM_SENSORS]) -> ProbeReading {
+        ProbeReading {
+            time: test_start() + Duration::from_secs_f32(seconds),
+            ..ProbeReading::new(temps_c)
+        }
+    }
+}
+
+/// Decoded readings in the order the probe sent them
+pub type ReadingStream = Pin<Box<dyn Stream<Item = anyhow::Result<ProbeReading>> + Send>>;
+
+/// Resolves when the connection to the probe is lost
+pub type Disconnected = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// How readings are pulled off the probe
+#[derive(Clone, Copy, Debug)]
+pub enum ReadMode {
+    /// Subscribe to Probe Status notifications and decode each one as it arrives
+    Notify,
+    /// Read Probe Status on an interval, for adapters where notifications are flaky
+    Poll(Duration),
+}
@@ -0,0 +1,274 @@
+// Plays a capture from `--record` back through the normal decoders, at the original speed or faster
+use chrono::prelude::*;
+use futures::{stream, StreamExt};
+use log::{info, warn};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+
+use crate::combustion::{load_capture, uart, Advertisement, DeviceInfo, Disconnected, Event, EventKind, ProbeReading, ProbeSerial, ProbeStatus, ReadMode, ReadingStream, Thermometer, ThermometerSource};
+
+/// Yields the events at their recorded offsets from the first one, sped up by `speed`
+fn play(events: Vec<Event>, speed: f32) -> impl futures::Stream<Item = Event> + Send {
+    let first = events.first().map_or(Duration::ZERO, |e| e.elapsed);
+    let start = Instant::now();
+    stream::iter(events).then(move |event| async move {
+        // A hand edited capture can have events out of order, play those straight away
+        let offset = event.elapsed.saturating_sub(first).div_f32(speed);
+        tokio::time::sleep_until(start + offset).await;
+        event
+    })
+}
+
+fn decode_status(event: &Event) -> anyhow::Result<ProbeReading> {
+    Ok(ProbeStatus::decode(&event.data)?.reading())
+}
+
+/// Pulls logged readings `start..=end` out of the recorded UART traffic. Timestamps count back
+/// from `now` as the entry after `end`, using the recorded session's sample period.
+fn decode_logs(events: &[Event], start: Option<u32>, end: u32, now: DateTime<Utc>) -> anyhow::Result<Vec<ProbeReading>> {
+    let mut reassembler = uart::Reassembler::new();
+    let mut period_ms = None;
+    let mut entries = BTreeMap::new();
+    for event in events.iter().filter(|e| e.kind == EventKind::Uart) {
+        for response in reassembler.push(&event.data) {
+            match response {
+                Ok(uart::Response::SessionInfo(info)) => period_ms = Some(info.sample_period_ms),
+                Ok(uart::Response::Log(entry)) if entry.sequence >= start.unwrap_or(0) && entry.sequence <= end => {
+                    entries.insert(entry.sequence, entry);
+                },
+                Ok(_) => {},
+                Err(e) => warn!("Bad UART response in capture: {}", e),
+            }
+        }
+    }
+    if entries.is_empty() {
+        return Ok(vec![]);
+    }
+    let period_ms = period_ms.ok_or(anyhow::anyhow!("Capture has logs but no session info"))?;
+    Ok(entries.into_values().map(|entry| {
+        let age = (end + 1 - entry.sequence) as i64 * period_ms as i64;
+        ProbeReading {
+            time: now - chrono::Duration::milliseconds(age),
+            sequence: Some(entry.sequence),
+            ..ProbeReading::from_raw(entry.raw_temps)
+        }
+    }).collect())
+}
+
+pub struct ReplayFinder {
+    events: Arc<Vec<Event>>,
+    speed: f32,
+}
+
+impl ReplayFinder {
+    pub fn load(path: &Path, speed: f32) -> anyhow::Result<ReplayFinder> {
+        if !(speed > 0.0 && speed.is_finite()) {
+            anyhow::bail!("Replay speed must be positive, got {}", speed);
+        }
+        let events = load_capture(path)?;
+        info!("Loaded {} events from {}", events.len(), path.display());
+        Ok(ReplayFinder { events: Arc::new(events), speed })
+    }
+
+    /// The serial the device advertised, or one made up from its address if it never did
+    fn serial(&self, address: &str) -> ProbeSerial {
+        self.events
+            .iter()
+            .filter(|e| e.kind == EventKind::Advertisement && e.address == address)
+            .find_map(|e| Advertisement::decode(&e.data).ok())
+            .map_or_else(|| ProbeSerial(address.bytes().fold(0, |h, b| h.wrapping_mul(31).wrapping_add(b as u32))), |adv| adv.serial)
+    }
+}
+
+impl ThermometerSource for ReplayFinder {
+    type Thermometer = ReplayCombustion;
+
+    /// Hands over every device the capture has Probe Status values for
+    async fn discover(&self, found: mpsc::Sender<ReplayCombustion>, mut shutdown: watch::Receiver<bool>) -> anyhow::Result<()> {
+        let mut addresses: Vec<&str> = self.events
+            .iter()
+            .filter(|e| e.kind == EventKind::Status)
+            .map(|e| e.address.as_str())
+            .collect();
+        addresses.sort();
+        addresses.dedup();
+        for address in addresses {
+            let events = self.events.iter().filter(|e| e.address == address).cloned().collect();
+            found.send(ReplayCombustion {
+                serial: self.serial(address),
+                events: Arc::new(events),
+                speed: self.speed,
+            }).await?;
+        }
+        let _ = shutdown.changed().await;
+        Ok(())
+    }
+
+    async fn advertisements(&self, found: mpsc::Sender<Advertisement>, mut shutdown: watch::Receiver<bool>) -> anyhow::Result<()> {
+        let events = self.events.iter().filter(|e| e.kind == EventKind::Advertisement).cloned().collect();
+        let replay = play(events, self.speed);
+        futures::pin_mut!(replay);
+        loop {
+            tokio::select! {
+                event = replay.next() => {
+                    let event = match event {
+                        Some(event) => event,
+                        None => {
+                            info!("Replay finished");
+                            let _ = shutdown.changed().await;
+                            return Ok(());
+                        }
+                    };
+                    match Advertisement::decode(&event.data) {
+                        Ok(adv) => found.send(adv).await?,
+                        Err(e) => warn!("Bad advertisement from {}: {} {:x?}", event.address, e, event.data),
+                    }
+                }
+                _ = shutdown.changed() => return Ok(()),
+            }
+        }
+    }
+}
+
+pub struct ReplayCombustion {
+    serial: ProbeSerial,
+    events: Arc<Vec<Event>>,
+    speed: f32,
+}
+
+impl Thermometer for ReplayCombustion {
+    fn serial(&self) -> ProbeSerial {
+        self.serial
+    }
+
+    async fn connect(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Not part of what was recorded
+    fn device_info(&self) -> DeviceInfo {
+        DeviceInfo::default()
+    }
+
+    /// Replays the recorded Probe Status values with their recorded timing whatever the mode,
+    /// then goes quiet
+    async fn readings(&self, _mode: ReadMode) -> anyhow::Result<ReadingStream> {
+        let events = self.events.iter().filter(|e| e.kind == EventKind::Status).cloned().collect();
+        let serial = self.serial;
+        let values = play(events, self.speed)
+            .map(|event| decode_status(&event))
+            .chain(stream::once(async move {
+                info!("Replay of probe {} finished", serial);
+            }).filter_map(|_| async { None }))
+            .chain(stream::pending());
+        Ok(Box::pin(values))
+    }
+
+    async fn read_logs(&self, start: Option<u32>, end: u32) -> anyhow::Result<Vec<ProbeReading>> {
+        decode_logs(&self.events, start, end, Utc::now())
+    }
+
+    async fn set_prediction(&self, _target_c: f32) -> anyhow::Result<()> {
+        anyhow::bail!("Can't set a prediction on a replay")
+    }
+
+    async fn cancel_prediction(&self) -> anyhow::Result<()> {
+        anyhow::bail!("Can't cancel a prediction on a replay")
+    }
+
+    /// Not part of what was recorded
+    async fn rssi(&self) -> anyhow::Result<Option<i16>> {
+        Ok(None)
+    }
+
+    /// A replay never drops
+    async fn disconnected(&self) -> anyhow::Result<Disconnected> {
+        Ok(Box::pin(futures::future::pending()))
+    }
+
+    async fn reset_connection(&self) {
+    }
+
+    async fn disconnect(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::combustion::parse_capture;
+
+    // A probe sitting on the counter, see extra/repr.c
+    const CAPTURE: &str = "
+        # advertisement, then a status notification
+        0 adv C2:71:04:90:3B:0E 017856341237c364748c8af1301006c2201828d50000
+        1000000 status C2:71:04:90:3B:0E 010000003412000037c364748c8af1301006c2201828d5
+        # a status from a probe with a bad virtual core sensor
+        2000000 status C2:71:04:90:3B:0E 010000003412000037c364748c8af1301006c22018280e
+    ";
+
+    #[test]
+    fn replays_through_the_decoders() {
+        let events = parse_capture(CAPTURE).unwrap();
+        let adv = Advertisement::decode(&events[0].data).unwrap();
+        assert_eq!(adv.serial, ProbeSerial(0x12345678));
+
+        let reading = decode_status(&events[1]).unwrap();
+        assert_eq!(reading.sequence, Some(0x1234));
+        assert!((reading.t1() - 21.15).abs() < 0.001);
+
+        let err = decode_status(&events[2]).unwrap_err();
+        assert_eq!(err.to_string(), "invalid virtual core sensor 7");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn plays_out_of_order_events() {
+        let events = parse_capture("
+            1000000 adv C2:71:04:90:3B:0E 017856341237c364748c8af1301006c2201828d50000
+            0 adv C2:71:04:90:3B:0E 017856341237c364748c8af1301006c2201828d50000
+            3000000 adv C2:71:04:90:3B:0E 017856341237c364748c8af1301006c2201828d50000
+        ").unwrap();
+        let started = Instant::now();
+        let played: Vec<Event> = play(events.clone(), 2.0).collect().await;
+        assert_eq!(played, events);
+        assert_eq!(started.elapsed(), Duration::from_secs(1));
+
+        for speed in [0.0, -1.0, f32::NAN, f32::INFINITY] {
+            assert!(ReplayFinder::load(Path::new("missing.capture"), speed).is_err());
+        }
+    }
+
+    #[test]
+    fn logs_from_uart_traffic() {
+        let session = uart::Response::SessionInfo(uart::SessionInfo { id: 1, sample_period_ms: 1000 }).encode();
+        let mut logs = vec![];
+        for sequence in 5..10 {
+            logs.extend(uart::Response::Log(uart::LogEntry { sequence, raw_temps: [823; 8] }).encode());
+        }
+        // Split across notifications the way the probe sends them
+        let events: Vec<Event> = [session, logs[..20].to_vec(), logs[20..].to_vec()]
+            .into_iter()
+            .enumerate()
+            .map(|(i, data)| Event {
+                elapsed: Duration::from_millis(i as u64),
+                kind: EventKind::Uart,
+                address: "AA".to_string(),
+                data,
+            })
+            .collect();
+
+        let now = Utc::now();
+        let readings = decode_logs(&events, Some(6), 8, now).unwrap();
+        assert_eq!(readings.iter().map(|r| r.sequence.unwrap()).collect::<Vec<_>>(), [6, 7, 8]);
+        assert_eq!(readings[2].time, now - chrono::Duration::seconds(1));
+        assert_eq!(readings[0].time, now - chrono::Duration::seconds(3));
+
+        assert!(decode_logs(&events[1..], None, 8, now).is_err());
+        assert!(decode_logs(&events, Some(20), 30, now).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,393 @@
+// Plays a cook uploaded by the pusher back as if the probes were live, for demos and for tuning
+//
+// A session is either a local directory or `s3://<bucket>/<prefix>`. Its `<n>.csv` chunks are
+// either directly inside it, for a single probe, or in one subdirectory per probe serial.
+use aws_sdk_s3::Client;
+use bytes::BytesMut;
+use chrono::prelude::*;
+use futures::{stream, StreamExt};
+use log::info;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+
+use crate::combustion::{Advertisement, BatteryStatus, DeviceInfo, Disconnected, ProbeColor, ProbeMode, ProbeReading, ProbeSerial, ProductType, ReadMode, ReadingStream, Thermometer, ThermometerSource, VirtualSensors};
+use crate::push::parse_chunk;
+
+/// Where a stored session lives
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SessionSource {
+    Local(PathBuf),
+    S3 { bucket: String, prefix: String },
+}
+
+impl std::str::FromStr for SessionSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<SessionSource> {
+        match s.strip_prefix("s3://") {
+            Some(rest) => {
+                let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+                if bucket.is_empty() {
+                    anyhow::bail!("No bucket in {}", s);
+                }
+                Ok(SessionSource::S3 {
+                    bucket: bucket.to_string(),
+                    prefix: prefix.trim_end_matches('/').to_string(),
+                })
+            },
+            None => Ok(SessionSource::Local(PathBuf::from(s))),
+        }
+    }
+}
+
+/// The chunk number of a `<n>.csv` file name
+fn chunk_number(name: &str) -> Option<u32> {
+    name.strip_suffix(".csv").and_then(|n| n.parse().ok())
+}
+
+/// Names the probe after its directory, which is its serial unless the session is from before
+/// there were several probes
+fn probe_serial(dir: &str) -> ProbeSerial {
+    dir.trim_end_matches('/').rsplit('/').next().and_then(|s| s.parse().ok()).unwrap_or(ProbeSerial(0))
+}
+
+/// Puts the readings from every chunk in time order, dropping any uploaded twice
+fn merge_chunks(chunks: &[String]) -> anyhow::Result<Vec<ProbeReading>> {
+    let mut readings = vec![];
+    for chunk in chunks {
+        readings.extend(parse_chunk(chunk)?);
+    }
+    readings.sort_by_key(|r| r.time);
+    readings.dedup_by_key(|r| r.time);
+    Ok(readings)
+}
+
+/// Chunks of every probe in a local session, by directory
+fn read_local(dir: &Path) -> anyhow::Result<Vec<(String, Vec<String>)>> {
+    let mut chunks = vec![];
+    let mut subdirs = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+        if path.is_dir() {
+            subdirs.push(path);
+        } else if let Some(n) = chunk_number(name) {
+            chunks.push((n, std::fs::read_to_string(&path)?));
+        }
+    }
+    if !chunks.is_empty() {
+        chunks.sort_by_key(|(n, _)| *n);
+        let chunks = chunks.into_iter().map(|(_, c)| c).collect();
+        return Ok(vec![(dir.display().to_string(), chunks)]);
+    }
+    let mut probes = vec![];
+    subdirs.sort();
+    for subdir in subdirs {
+        probes.extend(read_local(&subdir)?);
+    }
+    Ok(probes)
+}
+
+/// The keys and directories directly under the prefix
+async fn list(client: &Client, bucket: &str, prefix: &str) -> anyhow::Result<(Vec<String>, Vec<String>)> {
+    let mut keys = vec![];
+    let mut dirs = vec![];
+    let mut response = client
+        .list_objects_v2()
+        .bucket(bucket.to_owned())
+        .prefix(prefix.to_owned())
+        .delimiter("/")
+        .into_paginator()
+        .send();
+    while let Some(result) = response.next().await {
+        let response = match result {
+            Ok(r) => r,
+            Err(e) => anyhow::bail!("Failed fetching objects from bucket: {:?}", e),
+        };
+        keys.extend(response.contents().iter().filter_map(|o| o.key.clone()));
+        dirs.extend(response.common_prefixes().iter().filter_map(|d| d.prefix.clone()));
+    }
+    Ok((keys, dirs))
+}
+
+async fn read_obj(client: &Client, bucket: &str, key: &str) -> anyhow::Result<String> {
+    let mut response = client
+        .get_object()
+        .bucket(bucket.to_owned())
+        .key(key.to_owned())
+        .send()
+        .await?;
+    let mut bs = BytesMut::new();
+    while let Some(bytes) = response.body.try_next().await? {
+        bs.extend_from_slice(&bytes)
+    }
+    Ok(String::from_utf8(bs.to_vec())?)
+}
+
+/// Chunks of every probe in a session in the bucket, by directory
+async fn read_s3(bucket: &str, prefix: &str) -> anyhow::Result<Vec<(String, Vec<String>)>> {
+    let config = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
+    let client = Client::new(&config);
+    let dir = format!("{}/", prefix);
+    let (keys, subdirs) = list(&client, bucket, &dir).await?;
+    let dirs = if keys.iter().any(|k| chunk_number(k.trim_start_matches(&dir)).is_some()) {
+        vec![(dir, keys)]
+    } else {
+        let mut dirs = vec![];
+        for subdir in subdirs {
+            let (keys, _) = list(&client, bucket, &subdir).await?;
+            dirs.push((subdir, keys));
+        }
+        dirs
+    };
+
+    let mut probes = vec![];
+    for (dir, keys) in dirs {
+        let mut keys: Vec<(u32, String)> = keys
+            .into_iter()
+            .filter_map(|k| chunk_number(k.trim_start_matches(&dir)).map(|n| (n, k)))
+            .collect();
+        if keys.is_empty() {
+            continue;
+        }
+        // Keys aren't zero padded so 10.csv sorts before 9.csv
+        keys.sort_by_key(|(n, _)| *n);
+        let mut chunks = vec![];
+        for (_, key) in keys {
+            chunks.push(read_obj(&client, bucket, &key).await?);
+        }
+        probes.push((dir, chunks));
+    }
+    Ok(probes)
+}
+
+/// Yields the readings at their recorded offsets from the first one, sped up by `speed`
+fn play(readings: Arc<Vec<ProbeReading>>, speed: f32) -> impl futures::Stream<Item = ProbeReading> + Send {
+    let first = readings.first().map_or_else(Utc::now, |r| r.time);
+    let start = Instant::now();
+    stream::iter((0..readings.len()).map(move |i| readings[i])).then(move |reading| async move {
+        let offset = (reading.time - first).to_std().unwrap_or_default().div_f32(speed);
+        tokio::time::sleep_until(start + offset).await;
+        reading
+    })
+}
+
+pub struct SessionFinder {
+    probes: Vec<(ProbeSerial, Arc<Vec<ProbeReading>>)>,
+    speed: f32,
+}
+
+impl SessionFinder {
+    pub async fn load(source: &SessionSource, speed: f32) -> anyhow::Result<SessionFinder> {
+        if !(speed > 0.0 && speed.is_finite()) {
+            anyhow::bail!("Replay speed must be positive, got {}", speed);
+        }
+        let chunks = match source {
+            SessionSource::Local(dir) => read_local(dir)?,
+            SessionSource::S3 { bucket, prefix } => read_s3(bucket, prefix).await?,
+        };
+        let mut probes = vec![];
+        for (dir, chunks) in chunks {
+            let readings = merge_chunks(&chunks).map_err(|e| anyhow::anyhow!("{}: {}", dir, e))?;
+            let serial = probe_serial(&dir);
+            info!("Loaded {} readings of probe {} from {}", readings.len(), serial, dir);
+            probes.push((serial, Arc::new(readings)));
+        }
+        if probes.is_empty() {
+            anyhow::bail!("No chunks in {:?}", source);
+        }
+        Ok(SessionFinder { probes, speed })
+    }
+}
+
+impl ThermometerSource for SessionFinder {
+    type Thermometer = SessionCombustion;
+
+    /// Hands over every probe in the session then waits for shutdown
+    async fn discover(&self, found: mpsc::Sender<SessionCombustion>, mut shutdown: watch::Receiver<bool>) -> anyhow::Result<()> {
+        for (serial, readings) in &self.probes {
+            found.send(SessionCombustion {
+                serial: *serial,
+                readings: readings.clone(),
+                speed: self.speed,
+            }).await?;
+        }
+        let _ = shutdown.changed().await;
+        Ok(())
+    }
+
+    /// Advertises every reading in the session. Only the temperatures and the probe's pick of
+    /// sensors were stored so the rest of the advertisement is made up, and readings without a pick
+    /// have T1 for all three like they would live.
+    async fn advertisements(&self, found: mpsc::Sender<Advertisement>, mut shutdown: watch::Receiver<bool>) -> anyhow::Result<()> {
+        let replays = self.probes.iter().map(|(serial, readings)| {
+            let serial = *serial;
+            play(readings.clone(), self.speed).map(move |r| (serial, r)).boxed()
+        });
+        let replay = stream::select_all(replays);
+        futures::pin_mut!(replay);
+        loop {
+            tokio::select! {
+                next = replay.next() => {
+                    let (serial, reading) = match next {
+                        Some(next) => next,
+                        None => {
+                            info!("Replay finished");
+                            let _ = shutdown.changed().await;
+                            return Ok(());
+                        }
+                    };
+                    found.send(Advertisement {
+                        product_type: ProductType::Probe,
+                        serial,
+                        raw_temps: reading.temps_c.map(|t| ((t + 20.0) / 0.05).round() as u16),
+                        mode: ProbeMode::Normal,
+                        id: 1,
+                        color: ProbeColor::Yellow,
+                        battery: reading.battery.unwrap_or(BatteryStatus::Ok),
+                        virtual_sensors: reading.virtual_sensors.unwrap_or(VirtualSensors { core: 0, surface: 0, ambient: 0 }),
+                        rssi: None,
+                    }).await?;
+                }
+                _ = shutdown.changed() => return Ok(()),
+            }
+        }
+    }
+}
+
+pub struct SessionCombustion {
+    serial: ProbeSerial,
+    readings: Arc<Vec<ProbeReading>>,
+    speed: f32,
+}
+
+impl Thermometer for SessionCombustion {
+    fn serial(&self) -> ProbeSerial {
+        self.serial
+    }
+
+    async fn connect(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Not part of what was recorded
+    fn device_info(&self) -> DeviceInfo {
+        DeviceInfo::default()
+    }
+
+    /// Replays the stored readings whatever the mode, then goes quiet. The timestamps are moved
+    /// to start now but keep their original spacing, so anything working off them sees the cook's
+    /// real rates even when it's sped up.
+    async fn readings(&self, _mode: ReadMode) -> anyhow::Result<ReadingStream> {
+        let shift = self.readings.first().map_or(chrono::Duration::zero(), |r| Utc::now() - r.time);
+        let serial = self.serial;
+        let values = play(self.readings.clone(), self.speed)
+            .map(move |reading| Ok(ProbeReading { time: reading.time + shift, ..reading }))
+            .chain(stream::once(async move {
+                info!("Replay of probe {} finished", serial);
+            }).filter_map(|_| async { None }))
+            .chain(stream::pending());
+        Ok(Box::pin(values))
+    }
+
+    /// Stored readings have no sequence numbers so nothing ever asks for a backfill
+    async fn read_logs(&self, _start: Option<u32>, _end: u32) -> anyhow::Result<Vec<ProbeReading>> {
+        Ok(vec![])
+    }
+
+    async fn set_prediction(&self, _target_c: f32) -> anyhow::Result<()> {
+        anyhow::bail!("Can't set a prediction on a replay")
+    }
+
+    async fn cancel_prediction(&self) -> anyhow::Result<()> {
+        anyhow::bail!("Can't cancel a prediction on a replay")
+    }
+
+    /// Not part of what was recorded
+    async fn rssi(&self) -> anyhow::Result<Option<i16>> {
+        Ok(None)
+    }
+
+    /// A replay never drops
+    async fn disconnected(&self) -> anyhow::Result<Disconnected> {
+        Ok(Box::pin(futures::future::pending()))
+    }
+
+    async fn reset_connection(&self) {
+    }
+
+    async fn disconnect(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sources() {
+        assert_eq!("s3://me-combustion/2024-06-01T12:00:00Z/".parse::<SessionSource>().unwrap(), SessionSource::S3 {
+            bucket: "me-combustion".to_string(),
+            prefix: "2024-06-01T12:00:00Z".to_string(),
+        });
+        assert_eq!("cooks/brisket".parse::<SessionSource>().unwrap(), SessionSource::Local(PathBuf::from("cooks/brisket")));
+        assert!("s3://".parse::<SessionSource>().is_err());
+        assert_eq!(probe_serial("2024-06-01T12:00:00Z/1000ABCD/"), ProbeSerial(0x1000ABCD));
+        assert_eq!(probe_serial("2024-06-01T12:00:00Z/"), ProbeSerial(0));
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_speeds() {
+        let source = SessionSource::Local(PathBuf::from("missing"));
+        for speed in [0.0, -1.0, f32::NAN, f32::INFINITY] {
+            let err = SessionFinder::load(&source, speed).await.err().unwrap();
+            assert!(err.to_string().starts_with("Replay speed"), "{}", err);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn advertises_the_stored_sensor_pick() {
+        let dir = std::env::temp_dir().join(format!("rustbustion-session-{}", std::process::id()));
+        for (serial, chunk) in [
+            ("1000ABCD", "42,2024-06-01T12:00:00.000Z,40,41,42,43,44,45,46,47,3,5,8"),
+            ("1000ABCE", "41.5,2024-06-01T12:00:00.000Z"),
+        ] {
+            std::fs::create_dir_all(dir.join(serial)).unwrap();
+            std::fs::write(dir.join(serial).join("0.csv"), chunk).unwrap();
+        }
+        let finder = SessionFinder::load(&SessionSource::Local(dir.clone()), 1.0).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let (found, mut advertisements) = mpsc::channel(10);
+        let (_done, done) = watch::channel(false);
+        tokio::spawn(async move { finder.advertisements(found, done).await });
+        let mut readings = vec![];
+        for _ in 0..2 {
+            let adv = advertisements.recv().await.unwrap();
+            readings.push((adv.serial, adv.virtual_sensors, adv.reading()));
+        }
+        readings.sort_by_key(|(serial, _, _)| *serial);
+
+        let (_, picked, reading) = readings[0];
+        assert_eq!(picked, VirtualSensors { core: 2, surface: 4, ambient: 7 });
+        assert!((reading.core_c() - 42.0).abs() < 0.05, "{:?}", reading);
+        assert!((reading.ambient_c() - 47.0).abs() < 0.05, "{:?}", reading);
+        let (_, picked, reading) = readings[1];
+        assert_eq!(picked, VirtualSensors { core: 0, surface: 0, ambient: 0 });
+        assert!((reading.core_c() - 41.5).abs() < 0.05, "{:?}", reading);
+    }
+
+    #[test]
+    fn merges_chunks_in_time_order() {
+        // Newest first within a chunk, and a backfilled reading repeated in the next chunk
+        let chunks = [
+            "21,2024-06-01T12:00:10.000Z\n20,2024-06-01T12:00:00.000Z".to_string(),
+            "23,2024-06-01T12:00:20.000Z\n21,2024-06-01T12:00:10.000Z\n22,2024-06-01T12:00:15.000Z".to_string(),
+        ];
+        let readings = merge_chunks(&chunks).unwrap();
+        assert_eq!(readings.iter().map(|r| r.t1()).collect::<Vec<_>>(), [20.0, 21.0, 22.0, 23.0]);
+    }
+}
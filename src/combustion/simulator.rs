@@ -0,0 +1,557 @@
+// A thermometer stuck in a simulated piece of meat
+//
+// The meat is a slab heated from both faces. Heat conducts through it by finite differences and
+// enters at the surface by convection from the pit. The probe goes in at an angle so T1 sits at
+// the center, T2-T5 step towards the surface, T6 is at the surface and T7-T8 are in the air over
+// it, which is what the probe's virtual core/surface/ambient sensors expect.
+use chrono::prelude::*;
+use futures::stream;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
+
+use crate::combustion::{Advertisement, BatteryStatus, DeviceInfo, Disconnected, PredictionMode, PredictionState, PredictionStatus, PredictionType, ProbeColor, ProbeMode, ProbeReading, ProbeSerial, ProductType, ReadMode, ReadingStream, Thermometer, ThermometerSource, VirtualSensors, NUM_SENSORS};
+
+// Thermal diffusivity and volumetric heat capacity of lean meat
+const DIFFUSIVITY_M2_S: f32 = 1.4e-7;
+const HEAT_CAPACITY_J_M3_K: f32 = 3.7e6;
+// Convective heat transfer in a pit, and in still air while resting
+const PIT_H_W_M2_K: f32 = 20.0;
+const REST_H_W_M2_K: f32 = 8.0;
+const ROOM_C: f32 = 21.0;
+const NODES: usize = 20;
+const STEP_S: f32 = 1.0;
+// Distance of each sensor from the tip, and how much of the probe is in the meat
+const SENSOR_POSITIONS_MM: [f32; NUM_SENSORS] = [0.0, 12.0, 24.0, 36.0, 48.0, 60.0, 85.0, 110.0];
+const INSERTION_MM: f32 = 60.0;
+// How quickly the air warms up away from the meat's surface
+const AIR_FALLOFF_MM: f32 = 20.0;
+// The simulated probe is always in good range
+const RSSI: i16 = -60;
+const NOISE_C: f32 = 0.05;
+// How close the core gets to the stall temperature before the stall's clock starts
+const STALL_MARGIN_C: f32 = 3.0;
+// How often the simulated probe logs a reading
+const LOG_PERIOD: Duration = Duration::from_secs(5);
+// How far ahead the prediction looks for the core to reach its target
+const PREDICTION_HORIZON: Duration = Duration::from_secs(12 * 60 * 60);
+// The model is deterministic so a prediction stays good as the cook goes on, but look again now and
+// then in case the core comes within the horizon
+const PREDICTION_REFRESH: Duration = Duration::from_secs(10 * 60);
+
+/// Evaporation from the surface holding it at `temp_c` until the surface moisture runs out
+#[derive(Clone, Copy, Debug)]
+pub struct Stall {
+    pub temp_c: f32,
+    /// How long the core sits just under `temp_c` before the surface dries out
+    pub duration: Duration,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CookParams {
+    pub thickness_mm: f32,
+    pub start_c: f32,
+    pub pit_c: f32,
+    pub stall: Option<Stall>,
+    /// How far into the cook the meat comes off the heat to rest
+    pub pull_off: Option<Duration>,
+    /// Simulated seconds per real second
+    pub time_scale: f32,
+    pub seed: u64,
+}
+
+impl CookParams {
+    /// Thinner than this the stable step gets so short that predicting gets slow
+    pub const MIN_THICKNESS_MM: f32 = 10.0;
+}
+
+impl Default for CookParams {
+    fn default() -> CookParams {
+        CookParams {
+            thickness_mm: 50.0,
+            start_c: 4.0,
+            pit_c: 120.0,
+            stall: None,
+            pull_off: None,
+            time_scale: 60.0,
+            seed: 0,
+        }
+    }
+}
+
+/// Temperatures through the meat from the center (first node) to the surface (last node)
+#[derive(Clone, Debug)]
+pub struct CookModel {
+    params: CookParams,
+    nodes: [f32; NODES],
+    dx_m: f32,
+    /// The longest step the finite differences stay stable over, steps longer than this are split
+    max_dt: f32,
+    elapsed: Duration,
+    wet_for: Duration,
+    rng: StdRng,
+}
+
+impl CookModel {
+    pub fn new(params: CookParams) -> CookModel {
+        let dx_m = params.thickness_mm / 2.0 / 1000.0 / (NODES - 1) as f32;
+        // The explicit scheme blows up once a node gives away more heat in a step than it has
+        // over its neighbours, the surface node with its convection being the first to
+        let h = PIT_H_W_M2_K.max(REST_H_W_M2_K);
+        let max_dt = 1.0 / (2.0 * DIFFUSIVITY_M2_S / (dx_m * dx_m) + 2.0 * h / (HEAT_CAPACITY_J_M3_K * dx_m));
+        CookModel {
+            params,
+            nodes: [params.start_c; NODES],
+            dx_m,
+            max_dt,
+            elapsed: Duration::ZERO,
+            wet_for: params.stall.map_or(Duration::ZERO, |s| s.duration),
+            rng: StdRng::seed_from_u64(params.seed),
+        }
+    }
+
+    /// Simulated time since the cook started
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    pub fn core_c(&self) -> f32 {
+        self.nodes[0]
+    }
+
+    pub fn surface_c(&self) -> f32 {
+        self.nodes[NODES - 1]
+    }
+
+    pub fn pulled(&self) -> bool {
+        self.params.pull_off.is_some_and(|p| self.elapsed >= p)
+    }
+
+    /// Whether the meat is off the heat, and whether the surface is still wet enough to stall
+    fn phase(&self) -> (bool, bool) {
+        (self.pulled(), !self.wet_for.is_zero())
+    }
+
+    /// The air around the meat: the pit while cooking, the room while resting
+    pub fn ambient_c(&self) -> f32 {
+        if self.pulled() { ROOM_C } else { self.params.pit_c }
+    }
+
+    /// Runs the simulation forward by `d`
+    pub fn advance(&mut self, d: Duration) {
+        let end = self.elapsed + d;
+        while self.elapsed < end {
+            let dt = (end - self.elapsed).as_secs_f32().min(STEP_S);
+            self.step(dt);
+            self.elapsed += Duration::from_secs_f32(dt);
+        }
+    }
+
+    /// Steps the simulation forward by `dt` seconds, in as many pieces as it takes to stay stable
+    fn step(&mut self, dt: f32) {
+        let n = (dt / self.max_dt).ceil().max(1.0);
+        for _ in 0..n as u32 {
+            self.substep(dt / n);
+        }
+    }
+
+    fn substep(&mut self, dt: f32) {
+        let h = if self.pulled() { REST_H_W_M2_K } else { PIT_H_W_M2_K };
+        let ambient = self.ambient_c();
+        let k = DIFFUSIVITY_M2_S * dt / (self.dx_m * self.dx_m);
+        let prev = self.nodes;
+        // The center is symmetric and the surface node is half a cell with convection on its face
+        self.nodes[0] = prev[0] + 2.0 * k * (prev[1] - prev[0]);
+        for i in 1..NODES - 1 {
+            self.nodes[i] = prev[i] + k * (prev[i - 1] - 2.0 * prev[i] + prev[i + 1]);
+        }
+        let last = NODES - 1;
+        self.nodes[last] = prev[last]
+            + 2.0 * k * (prev[last - 1] - prev[last])
+            + 2.0 * h * dt / (HEAT_CAPACITY_J_M3_K * self.dx_m) * (ambient - prev[last]);
+
+        // A wet surface can't get hotter than evaporation lets it, which starves the core of heat.
+        // The core creeps up on that temperature and plateaus until the surface dries out.
+        if let Some(stall) = self.params.stall {
+            if !self.wet_for.is_zero() && self.nodes[last] >= stall.temp_c {
+                self.nodes[last] = stall.temp_c;
+                if self.nodes[0] >= stall.temp_c - STALL_MARGIN_C {
+                    self.wet_for = self.wet_for.saturating_sub(Duration::from_secs_f32(dt));
+                }
+            }
+        }
+    }
+
+    /// Temperature `mm` from the center of the meat
+    fn meat_c(&self, mm: f32) -> f32 {
+        let x = (mm / 1000.0 / self.dx_m).clamp(0.0, (NODES - 1) as f32);
+        let i = (x as usize).min(NODES - 2);
+        let frac = x - i as f32;
+        self.nodes[i] + (self.nodes[i + 1] - self.nodes[i]) * frac
+    }
+
+    /// What each sensor reads right now, T1 at the tip to T8 at the handle
+    pub fn sensors(&mut self) -> [f32; NUM_SENSORS] {
+        let half_mm = self.params.thickness_mm / 2.0;
+        let surface = self.surface_c();
+        let ambient = self.ambient_c();
+        let mut temps = [0.0; NUM_SENSORS];
+        for (t, pos) in temps.iter_mut().zip(SENSOR_POSITIONS_MM) {
+            let exact = if pos <= INSERTION_MM {
+                self.meat_c(half_mm * pos / INSERTION_MM)
+            } else {
+                let blend = 1.0 - (-(pos - INSERTION_MM) / AIR_FALLOFF_MM).exp();
+                surface + (ambient - surface) * blend
+            };
+            *t = exact + self.rng.gen_range(-NOISE_C..=NOISE_C);
+        }
+        temps
+    }
+
+    /// How long until the core reaches `target_c`, if it does within the horizon
+    pub fn time_to_core(&self, target_c: f32) -> Option<Duration> {
+        let mut model = self.clone();
+        while model.core_c() < target_c {
+            if model.elapsed - self.elapsed >= PREDICTION_HORIZON {
+                return None;
+            }
+            model.step(STEP_S);
+            model.elapsed += Duration::from_secs_f32(STEP_S);
+        }
+        Some(model.elapsed - self.elapsed)
+    }
+}
+
+/// The time to the set point worked out at some point in the cook
+#[derive(Clone, Copy, Debug)]
+struct Prediction {
+    set_point_c: f32,
+    phase: (bool, bool),
+    /// Simulated time it was worked out at
+    at: Duration,
+    remaining: Option<Duration>,
+}
+
+/// The model plus what the probe has logged, caught up to the wall clock on every access
+struct Sim {
+    model: CookModel,
+    started: Instant,
+    started_at: DateTime<Utc>,
+    time_scale: f32,
+    log: Vec<ProbeReading>,
+    target: Option<f32>,
+    /// Running the model ahead is slow, so it's only done again when the set point or the phase
+    /// of the cook changes, or PREDICTION_REFRESH after the last time
+    prediction: Option<Prediction>,
+}
+
+impl Sim {
+    fn new(params: CookParams) -> Sim {
+        Sim {
+            model: CookModel::new(params),
+            started: Instant::now(),
+            started_at: Utc::now(),
+            time_scale: params.time_scale,
+            log: vec![],
+            target: None,
+            prediction: None,
+        }
+    }
+
+    fn catch_up(&mut self) {
+        let now = self.started.elapsed().mul_f32(self.time_scale);
+        while self.model.elapsed() + LOG_PERIOD <= now || self.log.is_empty() {
+            if !self.log.is_empty() {
+                self.model.advance(LOG_PERIOD);
+            }
+            let reading = ProbeReading {
+                time: self.started_at + self.model.elapsed(),
+                sequence: Some(self.log.len() as u32),
+                ..ProbeReading::new(self.model.sensors())
+            };
+            self.log.push(reading);
+        }
+    }
+
+    /// How long until the core reaches `set_point_c` as of now, from the cached prediction when
+    /// it's still good
+    fn remaining(&mut self, set_point_c: f32) -> Option<Duration> {
+        let (phase, now) = (self.model.phase(), self.model.elapsed());
+        let cached = self.prediction.filter(|p| p.set_point_c == set_point_c && p.phase == phase && now - p.at < PREDICTION_REFRESH);
+        let prediction = match cached {
+            Some(p) => p,
+            None => {
+                let p = Prediction { set_point_c, phase, at: now, remaining: self.model.time_to_core(set_point_c) };
+                self.prediction = Some(p);
+                p
+            },
+        };
+        prediction.remaining.map(|r| r.saturating_sub(now - prediction.at))
+    }
+
+    /// The newest logged reading along with the prediction, if one was asked for
+    fn latest(&mut self) -> ProbeReading {
+        self.catch_up();
+        let prediction = self.target.map(|set_point_c| {
+            let remaining = self.remaining(set_point_c);
+            PredictionStatus {
+                state: if remaining.is_some() { PredictionState::Predicting } else { PredictionState::Warming },
+                mode: PredictionMode::TimeToRemoval,
+                kind: PredictionType::Removal,
+                set_point_c,
+                heat_start_c: self.model.params.start_c,
+                seconds_remaining: remaining.map_or(0, |r| r.as_secs() as u32),
+                estimated_core_c: self.model.core_c(),
+            }
+        });
+        ProbeReading {
+            prediction,
+            battery: Some(BatteryStatus::Ok),
+            ..*self.log.last().expect("caught up")
+        }
+    }
+}
+
+/// Hands over one simulated probe, with a serial derived from the seed
+pub struct SimulatorFinder {
+    params: CookParams,
+}
+
+impl SimulatorFinder {
+    pub fn new(params: CookParams) -> SimulatorFinder {
+        SimulatorFinder { params }
+    }
+
+    fn serial(&self) -> ProbeSerial {
+        ProbeSerial(StdRng::seed_from_u64(self.params.seed).gen())
+    }
+}
+
+impl ThermometerSource for SimulatorFinder {
+    type Thermometer = SimulatedCombustion;
+
+    async fn discover(&self, found: mpsc::Sender<SimulatedCombustion>, mut shutdown: watch::Receiver<bool>) -> anyhow::Result<()> {
+        found.send(SimulatedCombustion::new(self.serial(), self.params)).await?;
+        let _ = shutdown.changed().await;
+        Ok(())
+    }
+
+    /// Advertises the simulated probe every 2 seconds until shutdown
+    async fn advertisements(&self, found: mpsc::Sender<Advertisement>, mut shutdown: watch::Receiver<bool>) -> anyhow::Result<()> {
+        let serial = self.serial();
+        let mut sim = Sim::new(self.params);
+        let mut interval = tokio::time::interval(Duration::from_secs(2));
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    let reading = sim.latest();
+                    found.send(Advertisement {
+                        product_type: ProductType::Probe,
+                        serial,
+                        raw_temps: reading.temps_c.map(|t| ((t + 20.0) / 0.05) as u16),
+                        mode: ProbeMode::Normal,
+                        id: 1,
+                        color: ProbeColor::Yellow,
+                        battery: BatteryStatus::Ok,
+                        virtual_sensors: VirtualSensors { core: 0, surface: 5, ambient: 7 },
+                        rssi: Some(RSSI),
+                    }).await?;
+                }
+                _ = shutdown.changed() => return Ok(()),
+            }
+        }
+    }
+}
+
+pub struct SimulatedCombustion {
+    serial: ProbeSerial,
+    sim: Arc<Mutex<Sim>>,
+}
+
+impl SimulatedCombustion {
+    pub fn new(serial: ProbeSerial, params: CookParams) -> SimulatedCombustion {
+        SimulatedCombustion {
+            serial,
+            sim: Arc::new(Mutex::new(Sim::new(params))),
+        }
+    }
+}
+
+impl Thermometer for SimulatedCombustion {
+    fn serial(&self) -> ProbeSerial {
+        self.serial
+    }
+
+    async fn connect(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn device_info(&self) -> DeviceInfo {
+        DeviceInfo {
+            manufacturer: Some("Rustbustion".to_string()),
+            model: Some("Simulated probe".to_string()),
+            serial: Some(self.serial.to_string()),
+            ..DeviceInfo::default()
+        }
+    }
+
+    /// Emits the newest logged reading on the poll interval, or every 2 seconds when "notifying"
+    async fn readings(&self, mode: ReadMode) -> anyhow::Result<ReadingStream> {
+        let period = match mode {
+            ReadMode::Notify => Duration::from_secs(2),
+            ReadMode::Poll(period) => period,
+        };
+        let interval = tokio::time::interval(period);
+        let values = stream::unfold((self.sim.clone(), interval), |(sim, mut interval)| async move {
+            interval.tick().await;
+            let reading = sim.lock().unwrap().latest();
+            Some((Ok(reading), (sim, interval)))
+        });
+        Ok(Box::pin(values))
+    }
+
+    async fn read_logs(&self, start: Option<u32>, end: u32) -> anyhow::Result<Vec<ProbeReading>> {
+        let mut sim = self.sim.lock().unwrap();
+        sim.catch_up();
+        let start = start.unwrap_or(0) as usize;
+        let end = (end as usize + 1).min(sim.log.len());
+        Ok(sim.log.get(start..end).map(|l| l.to_vec()).unwrap_or_default())
+    }
+
+    async fn set_prediction(&self, target_c: f32) -> anyhow::Result<()> {
+        self.sim.lock().unwrap().target.replace(target_c);
+        Ok(())
+    }
+
+    async fn cancel_prediction(&self) -> anyhow::Result<()> {
+        self.sim.lock().unwrap().target.take();
+        Ok(())
+    }
+
+    async fn rssi(&self) -> anyhow::Result<Option<i16>> {
+        Ok(Some(RSSI))
+    }
+
+    /// The simulator never drops
+    async fn disconnected(&self) -> anyhow::Result<Disconnected> {
+        Ok(Box::pin(futures::future::pending()))
+    }
+
+    async fn reset_connection(&self) {
+    }
+
+    async fn disconnect(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    /// Minutes until the core reaches `target_c`
+    fn minutes_to(params: CookParams, target_c: f32) -> u64 {
+        CookModel::new(params).time_to_core(target_c).unwrap().as_secs() / 60
+    }
+
+    #[test]
+    fn heats_from_the_outside_in() {
+        let mut model = CookModel::new(CookParams::default());
+        model.advance(60 * MINUTE);
+        let temps = model.sensors();
+        for pair in temps.windows(2) {
+            assert!(pair[0] < pair[1], "{:?}", temps);
+        }
+        assert!(model.core_c() > 4.0 && model.core_c() < 57.0, "{}", model.core_c());
+        assert!(temps[7] < 120.0 && temps[7] > 100.0, "{:?}", temps);
+
+        // A 2 inch steak from the fridge in a 120C oven takes an hour or so to get to medium rare
+        let minutes = minutes_to(CookParams::default(), 57.0);
+        assert!((45..180).contains(&minutes), "{}", minutes);
+        let thin = minutes_to(CookParams { thickness_mm: 25.0, ..CookParams::default() }, 57.0);
+        assert!(thin < minutes / 2, "{} {}", thin, minutes);
+    }
+
+    #[test]
+    fn thin_cuts_stay_stable() {
+        for thickness_mm in [20.0, 15.0, CookParams::MIN_THICKNESS_MM] {
+            let mut model = CookModel::new(CookParams { thickness_mm, ..CookParams::default() });
+            model.advance(30 * MINUTE);
+            let temps = model.sensors();
+            assert!(temps.iter().all(|t| (3.0..=121.0).contains(t)), "{} {:?}", thickness_mm, temps);
+            assert!(model.core_c() > 57.0, "{} {}", thickness_mm, model.core_c());
+        }
+    }
+
+    #[test]
+    fn caches_the_prediction() {
+        let mut sim = Sim::new(CookParams::default());
+        let remaining = sim.remaining(57.0).unwrap();
+        sim.model.advance(MINUTE);
+        assert_eq!(sim.remaining(57.0), Some(remaining - MINUTE));
+        assert_eq!(sim.prediction.unwrap().at, Duration::ZERO);
+
+        // A new set point, or long enough since, runs the model ahead again
+        assert!(sim.remaining(60.0).unwrap() > remaining);
+        assert_eq!(sim.prediction.unwrap().at, MINUTE);
+        sim.model.advance(PREDICTION_REFRESH);
+        sim.remaining(60.0);
+        assert_eq!(sim.prediction.unwrap().at, MINUTE + PREDICTION_REFRESH);
+    }
+
+    #[test]
+    fn seed_makes_runs_reproducible() {
+        let params = CookParams { seed: 7, ..CookParams::default() };
+        let (mut a, mut b) = (CookModel::new(params), CookModel::new(params));
+        a.advance(30 * MINUTE);
+        b.advance(30 * MINUTE);
+        assert_eq!(a.sensors(), b.sensors());
+
+        let mut c = CookModel::new(CookParams { seed: 8, ..params });
+        c.advance(30 * MINUTE);
+        assert_ne!(a.sensors(), c.sensors());
+    }
+
+    #[test]
+    fn stall_holds_the_core_back() {
+        let pit = CookParams { thickness_mm: 80.0, pit_c: 110.0, ..CookParams::default() };
+        let stall = Stall { temp_c: 70.0, duration: 120 * MINUTE };
+        let stalled = CookParams { stall: Some(stall), ..pit };
+        let plain = minutes_to(pit, 80.0);
+        let with_stall = minutes_to(stalled, 80.0);
+        assert!(with_stall > plain + 60, "{} {}", with_stall, plain);
+
+        // The core sits in the 60s for a long stretch
+        let mut model = CookModel::new(stalled);
+        let mut flat = 0;
+        let mut last = model.core_c();
+        for _ in 0..600 {
+            model.advance(MINUTE);
+            if model.core_c() > 55.0 && model.core_c() - last < 0.05 {
+                flat += 1;
+            }
+            last = model.core_c();
+        }
+        assert!(flat > 60, "{}", flat);
+    }
+
+    #[test]
+    fn carries_over_after_pulling() {
+        let pull_off = 120 * MINUTE;
+        let mut model = CookModel::new(CookParams { pull_off: Some(pull_off), ..CookParams::default() });
+        model.advance(pull_off);
+        let pulled_at = model.core_c();
+        let mut peak = pulled_at;
+        for _ in 0..60 {
+            model.advance(MINUTE);
+            peak = peak.max(model.core_c());
+        }
+        assert!(model.pulled());
+        assert!(peak > pulled_at + 1.0, "{} {}", peak, pulled_at);
+        assert!(model.core_c() < peak);
+        assert!(model.sensors()[7] < 60.0);
+    }
+}
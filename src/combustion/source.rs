@@ -0,0 +1,50 @@
+// What the daemon needs from a thermometer backend, so it can run against real probes over BlueZ
+// or against a fake on machines without Bluetooth
+use std::future::Future;
+use tokio::sync::{mpsc, watch};
+
+use crate::combustion::{Advertisement, DeviceInfo, Disconnected, ProbeReading, ProbeSerial, ReadMode, ReadingStream};
+
+/// Finds probes
+pub trait ThermometerSource: Send + Sync + 'static {
+    type Thermometer: Thermometer;
+
+    /// Scans until shutdown, handing over a Thermometer each time a probe shows up
+    fn discover(&self, found: mpsc::Sender<Self::Thermometer>, shutdown: watch::Receiver<bool>) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// Scans until shutdown without ever connecting, handing over every probe advertisement
+    fn advertisements(&self, found: mpsc::Sender<Advertisement>, shutdown: watch::Receiver<bool>) -> impl Future<Output = anyhow::Result<()>> + Send;
+}
+
+/// One probe that can be connected to
+pub trait Thermometer: Send + Sync + 'static {
+    fn serial(&self) -> ProbeSerial;
+
+    fn connect(&mut self) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// What the probe said about itself when it was last connected
+    fn device_info(&self) -> DeviceInfo;
+
+    /// Streams readings as the probe notifies them or by reading it every interval
+    fn readings(&self, mode: ReadMode) -> impl Future<Output = anyhow::Result<ReadingStream>> + Send;
+
+    /// Pulls logged readings `start..=end` out of the probe, from the oldest it has when `start`
+    /// is None
+    fn read_logs(&self, start: Option<u32>, end: u32) -> impl Future<Output = anyhow::Result<Vec<ProbeReading>>> + Send;
+
+    fn set_prediction(&self, target_c: f32) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    fn cancel_prediction(&self) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// Signal strength the probe was last heard at in dBm, if the backend knows
+    fn rssi(&self) -> impl Future<Output = anyhow::Result<Option<i16>>> + Send;
+
+    /// Resolves once the connection is lost
+    fn disconnected(&self) -> impl Future<Output = anyhow::Result<Disconnected>> + Send;
+
+    /// Drops a broken connection but keeps the probe around so it can be connected again
+    fn reset_connection(&self) -> impl Future<Output = ()> + Send;
+
+    /// Disconnects for good at shutdown
+    fn disconnect(&self) -> impl Future<Output = anyhow::Result<()>> + Send;
+}
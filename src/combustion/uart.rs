@@ -0,0 +1,440 @@
+// Codec for messages sent over the probe's Nordic UART service
+//
+// Requests are   [0xCA 0xFE] [CRC16 LE] [type] [length] [payload]
+// Responses are  [0xCA 0xFE] [CRC16 LE] [type] [success] [length] [payload]
+//
+// The CRC is CRC-16/CCITT-FALSE over everything after the CRC. BLE notifications are limited by
+// the MTU so a response can be split over several notifications, or several responses packed into
+// one, which is what the Reassembler is for.
+
+use crate::combustion::{decode_raw_temps, PredictionMode, ProbeColor, NUM_SENSORS};
+#[cfg(test)]
+use crate::combustion::read_bits;
+
+const SYNC: [u8; 2] = [0xCA, 0xFE];
+// Only needed to decode requests, which the probe's end does
+#[cfg(test)]
+const REQUEST_HEADER_LEN: usize = 6;
+const RESPONSE_HEADER_LEN: usize = 7;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UartError {
+    BadSync,
+    Truncated { len: usize, expected: usize },
+    BadCrc { expected: u16, actual: u16 },
+    UnknownType(u8),
+    BadPayload { message_type: MessageType, len: usize },
+}
+
+impl std::fmt::Display for UartError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            UartError::BadSync => write!(f, "frame doesn't start with sync bytes"),
+            UartError::Truncated { len, expected } => write!(f, "truncated frame: got {} bytes, expected {}", len, expected),
+            UartError::BadCrc { expected, actual } => write!(f, "bad CRC: expected {:04x}, got {:04x}", expected, actual),
+            UartError::UnknownType(t) => write!(f, "unknown message type {:02x}", t),
+            UartError::BadPayload { message_type, len } => write!(f, "bad {:?} payload of {} bytes", message_type, len),
+        }
+    }
+}
+
+impl std::error::Error for UartError {}
+
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for b in data {
+        crc ^= (*b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageType {
+    SetProbeId = 0x01,
+    SetProbeColor = 0x02,
+    ReadSessionInfo = 0x03,
+    ReadLogs = 0x04,
+    SetPrediction = 0x05,
+}
+
+impl TryFrom<u8> for MessageType {
+    type Error = UartError;
+
+    fn try_from(v: u8) -> Result<MessageType, UartError> {
+        match v {
+            0x01 => Ok(MessageType::SetProbeId),
+            0x02 => Ok(MessageType::SetProbeColor),
+            0x03 => Ok(MessageType::ReadSessionInfo),
+            0x04 => Ok(MessageType::ReadLogs),
+            0x05 => Ok(MessageType::SetPrediction),
+            _ => Err(UartError::UnknownType(v)),
+        }
+    }
+}
+
+/// Wraps a body (everything after the CRC) in sync bytes and its CRC
+fn frame(body: &[u8]) -> Vec<u8> {
+    let mut frame = SYNC.to_vec();
+    frame.extend_from_slice(&crc16(body).to_le_bytes());
+    frame.extend_from_slice(body);
+    frame
+}
+
+/// Checks the sync bytes, length and CRC of the frame at the start of `data`. Returns the body
+/// (everything after the CRC) and the length of the whole frame.
+fn unframe(data: &[u8], header_len: usize) -> Result<(&[u8], usize), UartError> {
+    if data.len() >= SYNC.len() && data[0..2] != SYNC {
+        return Err(UartError::BadSync);
+    }
+    if data.len() < header_len {
+        return Err(UartError::Truncated { len: data.len(), expected: header_len });
+    }
+    let len = header_len + data[header_len - 1] as usize;
+    if data.len() < len {
+        return Err(UartError::Truncated { len: data.len(), expected: len });
+    }
+    let expected = u16::from_le_bytes([data[2], data[3]]);
+    let actual = crc16(&data[4..len]);
+    if expected != actual {
+        return Err(UartError::BadCrc { expected, actual });
+    }
+    Ok((&data[4..len], len))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().expect("4"))
+}
+
+// The set point is 10 bits in 0.1C
+pub const MAX_SET_POINT_C: f32 = 0x3FF as f32 * 0.1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Request {
+    /// Probe ID 1-8. The daemon doesn't set the ID or color yet.
+    #[allow(dead_code)]
+    SetProbeId(u8),
+    #[allow(dead_code)]
+    SetProbeColor(ProbeColor),
+    ReadSessionInfo,
+    /// Every log entry from `start` to `end` inclusive
+    ReadLogs { start: u32, end: u32 },
+    /// A mode of None cancels the prediction. The set point is clamped to 0-MAX_SET_POINT_C, so
+    /// check it first.
+    SetPrediction { mode: PredictionMode, set_point_c: f32 },
+}
+
+impl Request {
+    pub fn message_type(&self) -> MessageType {
+        match self {
+            Request::SetProbeId(_) => MessageType::SetProbeId,
+            Request::SetProbeColor(_) => MessageType::SetProbeColor,
+            Request::ReadSessionInfo => MessageType::ReadSessionInfo,
+            Request::ReadLogs { .. } => MessageType::ReadLogs,
+            Request::SetPrediction { .. } => MessageType::SetPrediction,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let payload = match *self {
+            Request::SetProbeId(id) => vec![id.clamp(1, 8) - 1],
+            Request::SetProbeColor(color) => vec![color as u8],
+            Request::ReadSessionInfo => vec![],
+            Request::ReadLogs { start, end } => {
+                let mut payload = start.to_le_bytes().to_vec();
+                payload.extend_from_slice(&end.to_le_bytes());
+                payload
+            },
+            Request::SetPrediction { mode, set_point_c } => {
+                // 10 bits of set point in 0.1C then 2 bits of mode
+                let set_point = ((set_point_c * 10.0).round() as u16).min(0x3FF);
+                (set_point | (mode as u16) << 10).to_le_bytes().to_vec()
+            },
+        };
+
+        let mut body = vec![self.message_type() as u8, payload.len() as u8];
+        body.extend_from_slice(&payload);
+        frame(&body)
+    }
+
+    /// Decodes the request at the start of `data`, returning it and how many bytes it took up. The
+    /// probe's end, for the tests and the mock BlueZ.
+    #[cfg(test)]
+    pub fn decode(data: &[u8]) -> Result<(Request, usize), UartError> {
+        let (body, len) = unframe(data, REQUEST_HEADER_LEN)?;
+        let message_type = MessageType::try_from(body[0])?;
+        let payload = &body[2..];
+        let bad_payload = UartError::BadPayload { message_type, len: payload.len() };
+
+        let request = match message_type {
+            MessageType::SetProbeId if payload.len() == 1 => Request::SetProbeId((payload[0] & 0x7) + 1),
+            MessageType::SetProbeColor if payload.len() == 1 => Request::SetProbeColor((payload[0] as u32).into()),
+            MessageType::ReadSessionInfo if payload.is_empty() => Request::ReadSessionInfo,
+            MessageType::ReadLogs if payload.len() == 8 => Request::ReadLogs {
+                start: read_u32(payload, 0),
+                end: read_u32(payload, 4),
+            },
+            MessageType::SetPrediction if payload.len() == 2 => Request::SetPrediction {
+                mode: read_bits(payload, 10, 2).into(),
+                set_point_c: read_bits(payload, 0, 10) as f32 * 0.1,
+            },
+            _ => return Err(bad_payload),
+        };
+        Ok((request, len))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SessionInfo {
+    pub id: u32,
+    pub sample_period_ms: u16,
+}
+
+/// A single record out of the probe's temperature log
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LogEntry {
+    pub sequence: u32,
+    pub raw_temps: [u16; NUM_SENSORS],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Response {
+    /// The probe didn't accept a request of this type
+    Failed(MessageType),
+    SetProbeId,
+    SetProbeColor,
+    SessionInfo(SessionInfo),
+    Log(LogEntry),
+    SetPrediction,
+}
+
+impl Response {
+    pub fn message_type(&self) -> MessageType {
+        match self {
+            Response::Failed(t) => *t,
+            Response::SetProbeId => MessageType::SetProbeId,
+            Response::SetProbeColor => MessageType::SetProbeColor,
+            Response::SessionInfo(_) => MessageType::ReadSessionInfo,
+            Response::Log(_) => MessageType::ReadLogs,
+            Response::SetPrediction => MessageType::SetPrediction,
+        }
+    }
+
+    /// The probe's end, for the tests and the mock BlueZ
+    #[cfg(test)]
+    pub fn encode(&self) -> Vec<u8> {
+        let payload = match self {
+            Response::Failed(_) | Response::SetProbeId | Response::SetProbeColor | Response::SetPrediction => vec![],
+            Response::SessionInfo(info) => {
+                let mut payload = info.id.to_le_bytes().to_vec();
+                payload.extend_from_slice(&info.sample_period_ms.to_le_bytes());
+                payload
+            },
+            Response::Log(entry) => {
+                let mut payload = entry.sequence.to_le_bytes().to_vec();
+                // Pack the 13 bit temperatures back up least significant bit first
+                let mut temps = [0u8; 13];
+                for (i, raw) in entry.raw_temps.iter().enumerate() {
+                    for bit in 0..13 {
+                        if (raw >> bit) & 1 == 1 {
+                            let pos = i * 13 + bit;
+                            temps[pos / 8] |= 1 << (pos % 8);
+                        }
+                    }
+                }
+                payload.extend_from_slice(&temps);
+                payload
+            },
+        };
+
+        let success = !matches!(self, Response::Failed(_));
+        let mut body = vec![self.message_type() as u8, success as u8, payload.len() as u8];
+        body.extend_from_slice(&payload);
+        frame(&body)
+    }
+
+    /// Decodes the response at the start of `data`, returning it and how many bytes it took up
+    pub fn decode(data: &[u8]) -> Result<(Response, usize), UartError> {
+        let (body, len) = unframe(data, RESPONSE_HEADER_LEN)?;
+        let message_type = MessageType::try_from(body[0])?;
+        if body[1] != 1 {
+            return Ok((Response::Failed(message_type), len));
+        }
+        let payload = &body[3..];
+        let bad_payload = UartError::BadPayload { message_type, len: payload.len() };
+
+        let response = match message_type {
+            MessageType::SetProbeId => Response::SetProbeId,
+            MessageType::SetProbeColor => Response::SetProbeColor,
+            MessageType::SetPrediction => Response::SetPrediction,
+            MessageType::ReadSessionInfo if payload.len() >= 6 => Response::SessionInfo(SessionInfo {
+                id: read_u32(payload, 0),
+                sample_period_ms: u16::from_le_bytes([payload[4], payload[5]]),
+            }),
+            // Newer firmware appends the prediction log after the temperatures
+            MessageType::ReadLogs if payload.len() >= 17 => Response::Log(LogEntry {
+                sequence: read_u32(payload, 0),
+                raw_temps: decode_raw_temps(&payload[4..17]),
+            }),
+            _ => return Err(bad_payload),
+        };
+        Ok((response, len))
+    }
+}
+
+/// Buffers UART notifications and pulls complete responses out of them
+#[derive(Debug, Default)]
+pub struct Reassembler {
+    buf: Vec<u8>,
+}
+
+impl Reassembler {
+    pub fn new() -> Reassembler {
+        Reassembler::default()
+    }
+
+    /// Adds a notification and returns every response that is now complete. Errors are returned
+    /// in line and the reassembler skips past the bad bytes, so one corrupt frame doesn't wedge it.
+    pub fn push(&mut self, data: &[u8]) -> Vec<Result<Response, UartError>> {
+        self.buf.extend_from_slice(data);
+
+        let mut out = vec![];
+        loop {
+            // Drop anything in front of the next sync bytes
+            let start = self.buf
+                .windows(SYNC.len())
+                .position(|w| w == SYNC)
+                .unwrap_or(self.buf.len().saturating_sub(1));
+            self.buf.drain(..start);
+
+            match Response::decode(&self.buf) {
+                Ok((response, len)) => {
+                    out.push(Ok(response));
+                    self.buf.drain(..len);
+                },
+                // Wait for the rest of it
+                Err(UartError::Truncated { .. }) => break,
+                Err(e @ (UartError::UnknownType(_) | UartError::BadPayload { .. })) => {
+                    // The CRC checked out so the length is good, skip the whole frame
+                    out.push(Err(e));
+                    let len = RESPONSE_HEADER_LEN + self.buf[RESPONSE_HEADER_LEN - 1] as usize;
+                    self.buf.drain(..len);
+                },
+                Err(e) => {
+                    // Can't trust the length, resync from the next byte
+                    out.push(Err(e));
+                    self.buf.drain(..1);
+                },
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Read logs 0x10..=0x20 as sent to a probe
+    const READ_LOGS: [u8; 14] = [0xca, 0xfe, 0x09, 0x6f, 0x04, 0x08, 0x10, 0x00, 0x00, 0x00, 0x20, 0x00, 0x00, 0x00];
+    // Session 0xdeadbeef sampling every 5000ms
+    const SESSION_INFO: [u8; 13] = [0xca, 0xfe, 0x6b, 0x61, 0x03, 0x01, 0x06, 0xef, 0xbe, 0xad, 0xde, 0x88, 0x13];
+    // Log entry 0x12 with the temperatures from extra/repr.c
+    const LOG_ENTRY: [u8; 24] = [
+        0xca, 0xfe, 0x37, 0xad, 0x04, 0x01, 0x11, 0x12, 0x00, 0x00, 0x00,
+        0x37, 0xc3, 0x64, 0x74, 0x8c, 0x8a, 0xf1, 0x30, 0x10, 0x06, 0xc2, 0x20, 0x18,
+    ];
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    fn requests() {
+        assert_eq!(Request::ReadLogs { start: 0x10, end: 0x20 }.encode(), READ_LOGS);
+        assert_eq!(Request::decode(&READ_LOGS), Ok((Request::ReadLogs { start: 0x10, end: 0x20 }, 14)));
+
+        for request in [
+            Request::SetProbeId(3),
+            Request::SetProbeColor(ProbeColor::Blue),
+            Request::ReadSessionInfo,
+            Request::SetPrediction { mode: PredictionMode::TimeToRemoval, set_point_c: 57.0 },
+        ] {
+            let (decoded, len) = Request::decode(&request.encode()).unwrap();
+            assert_eq!(decoded, request);
+            assert_eq!(len, request.encode().len());
+        }
+
+        // 570 = 0x23a in the low 10 bits and mode 1 above it
+        let set_prediction = Request::SetPrediction { mode: PredictionMode::TimeToRemoval, set_point_c: 57.0 }.encode();
+        assert_eq!(&set_prediction[4..], &[0x05, 0x02, 0x3a, 0x06]);
+    }
+
+    #[test]
+    fn responses() {
+        let info = SessionInfo { id: 0xdeadbeef, sample_period_ms: 5000 };
+        assert_eq!(Response::decode(&SESSION_INFO), Ok((Response::SessionInfo(info), 13)));
+        assert_eq!(Response::SessionInfo(info).encode(), SESSION_INFO);
+
+        let entry = LogEntry { sequence: 0x12, raw_temps: [823, 806, 797, 789, 783, 776, 776, 772] };
+        assert_eq!(Response::decode(&LOG_ENTRY), Ok((Response::Log(entry), 24)));
+        assert_eq!(Response::Log(entry).encode(), LOG_ENTRY);
+
+        let failed = Response::Failed(MessageType::SetPrediction).encode();
+        assert_eq!(Response::decode(&failed), Ok((Response::Failed(MessageType::SetPrediction), 7)));
+    }
+
+    #[test]
+    fn errors() {
+        let mut bad_crc = SESSION_INFO;
+        bad_crc[8] ^= 0xff;
+        assert!(matches!(Response::decode(&bad_crc), Err(UartError::BadCrc { .. })));
+
+        assert_eq!(Response::decode(&SESSION_INFO[..4]), Err(UartError::Truncated { len: 4, expected: 7 }));
+        assert_eq!(Response::decode(&SESSION_INFO[..10]), Err(UartError::Truncated { len: 10, expected: 13 }));
+        assert_eq!(Response::decode(&[0x00, 0x01, 0x02]), Err(UartError::BadSync));
+
+        let unknown = frame(&[0x7f, 0x01, 0x00]);
+        assert_eq!(Response::decode(&unknown), Err(UartError::UnknownType(0x7f)));
+
+        let short = frame(&[0x03, 0x01, 0x02, 0x00, 0x00]);
+        assert_eq!(Response::decode(&short), Err(UartError::BadPayload { message_type: MessageType::ReadSessionInfo, len: 2 }));
+    }
+
+    #[test]
+    fn reassembly() {
+        let mut r = Reassembler::new();
+
+        // Split across notifications
+        assert!(r.push(&LOG_ENTRY[..5]).is_empty());
+        assert!(r.push(&LOG_ENTRY[5..20]).is_empty());
+        let out = r.push(&LOG_ENTRY[20..]);
+        assert_eq!(out.len(), 1);
+        assert!(matches!(out[0], Ok(Response::Log(LogEntry { sequence: 0x12, .. }))));
+
+        // Several in one notification with junk in front and a partial one on the end
+        let mut data = vec![0x00, 0x11];
+        data.extend_from_slice(&SESSION_INFO);
+        data.extend_from_slice(&LOG_ENTRY);
+        data.extend_from_slice(&SESSION_INFO[..3]);
+        let out = r.push(&data);
+        assert_eq!(out.len(), 2);
+        assert!(matches!(out[0], Ok(Response::SessionInfo(_))));
+        assert!(matches!(out[1], Ok(Response::Log(_))));
+        let out = r.push(&SESSION_INFO[3..]);
+        assert_eq!(out.len(), 1);
+
+        // A corrupt frame is reported and the next one still comes through
+        let mut bad_crc = LOG_ENTRY;
+        bad_crc[12] ^= 0xff;
+        let mut data = bad_crc.to_vec();
+        data.extend_from_slice(&SESSION_INFO);
+        let out = r.push(&data);
+        assert_eq!(out.len(), 2);
+        assert!(matches!(out[0], Err(UartError::BadCrc { .. })));
+        assert!(matches!(out[1], Ok(Response::SessionInfo(_))));
+    }
+}
@@ -0,0 +1,177 @@
+// When the core will reach its target, worked out from the readings so it doesn't depend on the
+// probe's own prediction
+//
+// Meat heats roughly by Newton's law, dT/dt = k (ambient - T), so ln(ambient - T) falls in a
+// straight line over time. A least squares fit of that line over the recent readings gives k, and
+// its standard error gives a range on k and so on the time left.
+use chrono::prelude::*;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use crate::combustion::ProbeReading;
+use crate::fit::Line;
+
+// Only fit the recent readings, the rate changes over a cook
+const WINDOW: Duration = Duration::from_secs(20 * 60);
+// Don't guess from less than this
+const MIN_SAMPLES: usize = 10;
+const MIN_SPAN: Duration = Duration::from_secs(5 * 60);
+// The fit falls apart as the core closes in on the ambient temperature
+const MIN_GAP_C: f32 = 1.0;
+// For a 95% confidence interval
+const Z_95: f32 = 1.96;
+
+/// How long until the core reaches the target, as of the newest reading
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Estimate {
+    pub remaining: Duration,
+    /// Bounds of the 95% confidence interval on `remaining`. The top is None when the fit can't
+    /// rule out the core never getting there.
+    pub remaining_low: Duration,
+    pub remaining_high: Option<Duration>,
+    /// When the newest reading was taken
+    pub as_of: DateTime<Utc>,
+}
+
+impl Estimate {
+    pub fn done_at(&self) -> DateTime<Utc> {
+        self.as_of + self.remaining
+    }
+
+    pub fn to_json(self) -> serde_json::Value {
+        let at = |d: Duration| (self.as_of + d).to_rfc3339_opts(SecondsFormat::Secs, true);
+        serde_json::json!({
+            "seconds_remaining": self.remaining.as_secs(),
+            "seconds_remaining_low": self.remaining_low.as_secs(),
+            "seconds_remaining_high": self.remaining_high.map(|d| d.as_secs()),
+            "done_at": self.done_at().to_rfc3339_opts(SecondsFormat::Secs, true),
+            "done_at_earliest": at(self.remaining_low),
+            "done_at_latest": self.remaining_high.map(at),
+        })
+    }
+}
+
+/// The core and ambient temperatures over the last WINDOW of readings
+#[derive(Debug, Default)]
+pub struct EtaEstimator {
+    /// (time, core, ambient) in time order
+    samples: VecDeque<(DateTime<Utc>, f32, f32)>,
+}
+
+impl EtaEstimator {
+    /// Adds a reading, which can be older than the others when it was backfilled from the log
+    pub fn add(&mut self, reading: &ProbeReading) {
+        let idx = self.samples.partition_point(|(t, _, _)| *t <= reading.time);
+        self.samples.insert(idx, (reading.time, reading.core_c(), reading.air_c()));
+        if let Some(&(newest, _, _)) = self.samples.back() {
+            while self.samples.front().is_some_and(|(t, _, _)| (newest - *t).to_std().is_ok_and(|age| age > WINDOW)) {
+                self.samples.pop_front();
+            }
+        }
+    }
+
+    /// Forgets everything, for when the readings so far no longer say how the core heats
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+
+    /// When the core should reach `target_c`, or None when there isn't enough to go on or the
+    /// core isn't heating towards it
+    pub fn estimate(&self, target_c: f32) -> Option<Estimate> {
+        let (&(first, _, _), &(as_of, core, _)) = (self.samples.front()?, self.samples.back()?);
+        if core >= target_c {
+            return Some(Estimate { remaining: Duration::ZERO, remaining_low: Duration::ZERO, remaining_high: Some(Duration::ZERO), as_of });
+        }
+        if self.samples.len() < MIN_SAMPLES || (as_of - first).to_std().ok()? < MIN_SPAN {
+            return None;
+        }
+
+        let ambient = self.samples.iter().map(|(_, _, a)| *a).sum::<f32>() / self.samples.len() as f32;
+        if ambient - target_c < MIN_GAP_C || self.samples.iter().any(|(_, c, _)| ambient - c < MIN_GAP_C) {
+            return None;
+        }
+
+        // Fit ln(ambient - core) = a - k t, with t in seconds before the newest reading
+        let points: Vec<(f32, f32)> = self.samples
+            .iter()
+            .map(|(t, c, _)| (-((as_of - *t).num_milliseconds() as f32 / 1000.0), (ambient - c).ln()))
+            .collect();
+        let line = Line::fit(&points)?;
+        let (k, se) = (-line.slope, line.slope_se);
+        if k <= 0.0 {
+            return None;
+        }
+
+        // Time for the fitted gap now to close down to the gap at the target
+        let log_ratio = line.intercept - (ambient - target_c).ln();
+        let remaining = |k: f32| Duration::from_secs_f32((log_ratio / k).max(0.0));
+        let k_low = k - Z_95 * se;
+        Some(Estimate {
+            remaining: remaining(k),
+            remaining_low: remaining(k + Z_95 * se),
+            remaining_high: (k_low > 0.0).then(|| remaining(k_low)),
+            as_of,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::combustion::{test_start, VirtualSensors, NUM_SENSORS};
+
+    const AMBIENT_C: f32 = 120.0;
+    const START_C: f32 = 4.0;
+    // Per second, about an hour to close most of the gap
+    const K: f32 = 1.0 / 3600.0;
+
+    fn reading(s: u32, noise: f32) -> ProbeReading {
+        let core = AMBIENT_C - (AMBIENT_C - START_C) * (-K * s as f32).exp() + noise;
+        let mut temps = [AMBIENT_C; NUM_SENSORS];
+        temps[2] = core;
+        ProbeReading {
+            virtual_sensors: Some(VirtualSensors { core: 2, surface: 4, ambient: 7 }),
+            ..ProbeReading::at(s as f32, temps)
+        }
+    }
+
+    #[test]
+    fn fits_newton_heating() {
+        let start = test_start();
+        let mut eta = EtaEstimator::default();
+        assert_eq!(eta.estimate(57.0), None);
+
+        // Ten minutes at five second intervals with a little sensor noise
+        for (i, s) in (0..=600).step_by(5).enumerate() {
+            eta.add(&reading(s, if i % 2 == 0 { 0.05 } else { -0.05 }));
+            if s == 120 {
+                assert_eq!(eta.estimate(57.0), None, "too early to say");
+            }
+        }
+        let estimate = eta.estimate(57.0).unwrap();
+        let expected = ((AMBIENT_C - START_C) / (AMBIENT_C - 57.0)).ln() / K - 600.0;
+        assert!((estimate.remaining.as_secs_f32() - expected).abs() < 30.0, "{:?} vs {}", estimate, expected);
+        assert!(estimate.remaining_low <= estimate.remaining && Some(estimate.remaining) <= estimate.remaining_high);
+        assert_eq!(estimate.as_of, start + Duration::from_secs(600));
+        assert_eq!(estimate.done_at(), estimate.as_of + estimate.remaining);
+
+        // Never gets hotter than the air, and is already past a low target
+        assert_eq!(eta.estimate(AMBIENT_C + 5.0), None);
+        assert_eq!(eta.estimate(20.0).unwrap().remaining, Duration::ZERO);
+
+        // A backfilled reading lands in order and old ones drop out of the window
+        eta.add(&reading(2, 0.0));
+        assert_eq!(eta.samples[1].0, start + Duration::from_secs(2));
+        eta.add(&reading(600 + WINDOW.as_secs() as u32, 0.0));
+        assert_eq!(eta.samples.len(), 2);
+    }
+
+    #[test]
+    fn needs_the_core_to_be_heating() {
+        let mut eta = EtaEstimator::default();
+        for s in (0..=600).step_by(5) {
+            eta.add(&ProbeReading::at(s as f32, [40.0, 40.0, 40.0, 40.0, 60.0, 80.0, 100.0, AMBIENT_C]));
+        }
+        assert_eq!(eta.estimate(57.0), None);
+    }
+}
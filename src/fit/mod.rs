@@ -0,0 +1,55 @@
+// Least squares line fits, which the ETA, stall and carryover all make of the recent readings
+
+/// The least squares line through some (t, y) points
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Line {
+    pub slope: f32,
+    pub intercept: f32,
+    /// The mean t of the points, where the line is best pinned down
+    pub mean_t: f32,
+    /// Standard error of the slope, NaN with only two points
+    pub slope_se: f32,
+}
+
+impl Line {
+    /// Fits a line to the points, or None when they don't pin one down: fewer than two, or all at
+    /// the same t
+    pub fn fit(points: &[(f32, f32)]) -> Option<Line> {
+        if points.len() < 2 {
+            return None;
+        }
+        let n = points.len() as f32;
+        let mean_t = points.iter().map(|(t, _)| t).sum::<f32>() / n;
+        let mean_y = points.iter().map(|(_, y)| y).sum::<f32>() / n;
+        let stt = points.iter().map(|(t, _)| (t - mean_t).powi(2)).sum::<f32>();
+        if stt == 0.0 {
+            return None;
+        }
+        let slope = points.iter().map(|(t, y)| (t - mean_t) * (y - mean_y)).sum::<f32>() / stt;
+        let intercept = mean_y - slope * mean_t;
+        let residuals = points.iter().map(|(t, y)| (y - intercept - slope * t).powi(2)).sum::<f32>();
+        Some(Line { slope, intercept, mean_t, slope_se: (residuals / (n - 2.0) / stt).sqrt() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fits_a_line() {
+        let exact: Vec<(f32, f32)> = (0..10).map(|t| (t as f32, 3.0 - 0.5 * t as f32)).collect();
+        let line = Line::fit(&exact).unwrap();
+        assert!((line.slope + 0.5).abs() < 1e-5 && (line.intercept - 3.0).abs() < 1e-5, "{:?}", line);
+        assert_eq!(line.mean_t, 4.5);
+        assert!(line.slope_se.abs() < 1e-3);
+
+        // Noise leaves the slope about where it was but makes it less certain
+        let noisy: Vec<(f32, f32)> = exact.iter().enumerate().map(|(i, (t, y))| (*t, y + if i % 2 == 0 { 0.1 } else { -0.1 })).collect();
+        let line = Line::fit(&noisy).unwrap();
+        assert!((line.slope + 0.5).abs() < 0.05 && line.slope_se > 0.001, "{:?}", line);
+
+        assert_eq!(Line::fit(&[(1.0, 2.0)]), None);
+        assert_eq!(Line::fit(&[(1.0, 2.0), (1.0, 3.0)]), None);
+    }
+}
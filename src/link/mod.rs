@@ -0,0 +1,123 @@
+// How well the Pi hears each probe, so it can say when to move closer before a cook goes unlogged
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// RSSI and reads older than this don't count
+const WINDOW: Duration = Duration::from_secs(5 * 60);
+// An average RSSI below this is a weak signal
+const WEAK_RSSI_DBM: f32 = -85.0;
+// So is more than this share of reads failing
+const WEAK_FAILURE_RATE: f32 = 0.2;
+// Only warn once the signal has been weak for this long, a person walking past doesn't count
+const WEAK_FOR: Duration = Duration::from_secs(2 * 60);
+
+/// The link as reported in the status JSON
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LinkSummary {
+    pub rssi: Option<i16>,
+    pub min_rssi: Option<i16>,
+    pub avg_rssi: Option<f32>,
+    /// Share of reads that failed, None without any reads
+    pub failure_rate: Option<f32>,
+    /// Weak for at least WEAK_FOR
+    pub weak: bool,
+}
+
+impl LinkSummary {
+    pub fn to_json(self) -> serde_json::Value {
+        serde_json::json!({
+            "rssi": self.rssi,
+            "min_rssi": self.min_rssi,
+            "avg_rssi": self.avg_rssi,
+            "failure_rate": self.failure_rate,
+            "weak": self.weak,
+        })
+    }
+}
+
+/// RSSI samples and read outcomes over the last WINDOW
+#[derive(Debug, Default)]
+pub struct LinkStats {
+    rssi: VecDeque<(Instant, i16)>,
+    reads: VecDeque<(Instant, bool)>,
+    weak_since: Option<Instant>,
+}
+
+impl LinkStats {
+    pub fn new() -> LinkStats {
+        LinkStats::default()
+    }
+
+    pub fn rssi(&mut self, now: Instant, rssi: i16) {
+        self.rssi.push_back((now, rssi));
+        self.update(now);
+    }
+
+    /// Records whether a read from the probe worked
+    pub fn read(&mut self, now: Instant, ok: bool) {
+        self.reads.push_back((now, ok));
+        self.update(now);
+    }
+
+    fn update(&mut self, now: Instant) {
+        let cutoff = now.checked_sub(WINDOW);
+        self.rssi.retain(|(t, _)| cutoff.is_none_or(|c| *t >= c));
+        self.reads.retain(|(t, _)| cutoff.is_none_or(|c| *t >= c));
+        let summary = self.summary(now);
+        let weak = summary.avg_rssi.is_some_and(|r| r < WEAK_RSSI_DBM)
+            || summary.failure_rate.is_some_and(|r| r > WEAK_FAILURE_RATE);
+        self.weak_since = match (weak, self.weak_since) {
+            (true, None) => Some(now),
+            (true, since) => since,
+            (false, _) => None,
+        };
+    }
+
+    pub fn summary(&self, now: Instant) -> LinkSummary {
+        let rssi = self.rssi.iter().map(|(_, r)| *r);
+        let failures = self.reads.iter().filter(|(_, ok)| !ok).count();
+        LinkSummary {
+            rssi: self.rssi.back().map(|(_, r)| *r),
+            min_rssi: rssi.clone().min(),
+            avg_rssi: (!self.rssi.is_empty()).then(|| rssi.map(f32::from).sum::<f32>() / self.rssi.len() as f32),
+            failure_rate: (!self.reads.is_empty()).then(|| failures as f32 / self.reads.len() as f32),
+            weak: self.weak_since.is_some_and(|since| now.duration_since(since) >= WEAK_FOR),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn warns_once_weak_for_a_while() {
+        let start = Instant::now();
+        let mut link = LinkStats::new();
+        assert_eq!(link.summary(start), LinkSummary::default());
+
+        link.rssi(start, -60);
+        link.read(start, true);
+        link.rssi(start + Duration::from_secs(10), -70);
+        let summary = link.summary(start + Duration::from_secs(10));
+        assert_eq!((summary.rssi, summary.min_rssi, summary.avg_rssi), (Some(-70), Some(-70), Some(-65.0)));
+        assert_eq!(summary.failure_rate, Some(0.0));
+        assert!(!summary.weak);
+
+        // Reads start failing, which only warns after WEAK_FOR
+        let t = start + Duration::from_secs(20);
+        link.read(t, false);
+        assert_eq!(link.summary(t).failure_rate, Some(0.5));
+        assert!(!link.summary(t).weak);
+        assert!(link.summary(t + WEAK_FOR).weak);
+
+        // Long after, only the good reads in the window count
+        let later = t + WINDOW + Duration::from_secs(1);
+        link.read(later, true);
+        link.rssi(later, -95);
+        let summary = link.summary(later);
+        assert_eq!((summary.failure_rate, summary.avg_rssi), (Some(0.0), Some(-95.0)));
+        assert!(!summary.weak);
+        assert!(link.summary(later + WEAK_FOR).weak);
+    }
+}
@@ -1,129 +1,612 @@
 use chrono::prelude::*;
+use futures::future::OptionFuture;
+use futures::StreamExt;
 use log::{error, info, warn};
-use std::sync::{Arc, Mutex};
-
-use bytes::Bytes;
-use http_body_util::Full;
-use hyper::{body::Incoming as IncomingBody};
-use hyper::server::conn::http1;
-use hyper::service::{Service as HyperService};
-use hyper::{Request, Response};
+use std::collections::{HashMap, VecDeque};
 use std::future::Future;
+use std::path::PathBuf;
 use std::pin::Pin;
+use std::time::{Duration, Instant};
+
+use hyper::server::conn::http1;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinSet;
+
+mod alert;
+use alert::{Alert, AlertKind};
+
+mod carryover;
+use carryover::{CarryoverDetector, CarryoverEvent};
 
 mod combustion;
-use combustion::CombustionFinder;
+use combustion::{Advertisement, BatteryStatus, CookParams, FakeFinder, ProbeReading, ProbeSerial, ReadMode, ReplayFinder, SessionFinder, SessionSource, SimulatorFinder, Stall, Thermometer, ThermometerSource};
+#[cfg(all(target_os = "linux", feature = "bluer"))]
+use combustion::{CombustionFinder, KnownProbes, ProbeFilter, Recorder};
+
+mod eta;
+use eta::EtaEstimator;
+
+mod fit;
+
+mod link;
+use link::LinkStats;
+
+mod stall;
+use stall::{StallDetector, StallEvent};
 
 mod push;
 use push::Pusher;
 
+mod supervisor;
+use supervisor::Backoff;
+
+mod svc;
+use svc::{check_target_c, Command, CommandReceiver, QueuedCommand, S3Status, Svc, SvcStatus};
+
+// Probes advertise several times a second, far more often than is worth uploading
+const PASSIVE_PUSH_PERIOD: Duration = Duration::from_secs(5);
+// Treat the probe as dropped after this many failed reads in a row
+const MAX_READ_ERRORS: u32 = 5;
+// At most this many of the newest missing log entries are downloaded per gap, so a probe with a
+// long log doesn't tie up the UART on first contact
+const MAX_BACKFILL: u32 = 1000;
+// Wait for discovery instead of retrying the same device after this many failed connects, which
+// takes the backoff up to its one minute cap for the last two
+const REDISCOVER_AFTER_ATTEMPTS: u32 = 8;
+// How often to sample a connected probe's RSSI
+const RSSI_PERIOD: Duration = Duration::from_secs(10);
+// How often metadata.json is refreshed with the latest link quality, ETA and carryover. Alerts and
+// other changes that matter upload it straight away.
+const METADATA_PERIOD: Duration = Duration::from_secs(2 * 60);
+
 fn as_farenheit(c: f32) -> f32 {
     c * 1.8 + 32.0
 }
 
-#[derive(Clone, Copy, Debug)]
-enum SvcStatus {
-    DISCOVERING,
-    CONNECTING,
-    CONNECTED,
-    RUNNING,
+/// What a probe's pusher task uploads
+enum Upload {
+    Readings(Vec<ProbeReading>),
+    /// Replaces the probe's metadata.json
+    Metadata(serde_json::Value),
 }
 
-impl std::fmt::Display for SvcStatus {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        let value = match self {
-            SvcStatus::DISCOVERING => "discovering".to_string(),
-            SvcStatus::CONNECTING => "connecting".to_string(),
-            SvcStatus::CONNECTED => "connected".to_string(),
-            SvcStatus::RUNNING => "running".to_string(),
-        };
-        write!(f, "{}", value)
+/// Starts a task uploading the probe's readings and metadata under `<session>/<serial>` in the
+/// bucket
+fn spawn_pusher(svc: Svc, serial: ProbeSerial, bucket: Option<String>, session: String) -> mpsc::Sender<Upload> {
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<Upload>(100);
+    tokio::spawn(async move {
+        let mut pusher = Pusher::new();
+        if let Some(bucket) = bucket {
+            let prefix = format!("{}/{}", session, serial);
+            info!("Starting S3 pusher to {}/{}", bucket, prefix);
+            pusher.init(bucket, prefix).await;
+        }
+
+        let mut i = 0;
+        let mut last_metadata = None;
+        while let Some(upload) = rx.recv().await {
+            let readings = match upload {
+                Upload::Readings(readings) => readings,
+                // Nothing to do if nothing changed since the last upload
+                Upload::Metadata(metadata) if last_metadata.as_ref() == Some(&metadata) => continue,
+                Upload::Metadata(metadata) => {
+                    match pusher.put_metadata(&metadata).await {
+                        Ok(()) => last_metadata = Some(metadata),
+                        Err(e) => error!("Failed to push metadata for probe {}: {}", serial, e),
+                    }
+                    continue;
+                },
+            };
+            let n = readings.len();
+            if let Err(e) = pusher.push(readings).await {
+                error!("Failed to push {} readings i={}: {}", n, i, e);
+                svc.set_s3_status(serial, S3Status::ERROR);
+            } else {
+                svc.set_s3_status(serial, S3Status::WRITING);
+            }
+            i += 1;
+        }
+    });
+    tx
+}
+
+/// Queues the probe's metadata.json for upload
+async fn upload_metadata(svc: &Svc, serial: ProbeSerial, tx: &mpsc::Sender<Upload>) {
+    if let Some(metadata) = svc.metadata_json(serial) {
+        if let Err(e) = tx.send(Upload::Metadata(metadata)).await {
+            error!("Failed to send metadata to pusher: {}", e);
+        }
     }
 }
 
-#[derive(Clone, Copy, Debug)]
-enum S3Status {
-    UNINIT,
-    WRITING,
-    ERROR,
+/// Publishes the probe's link quality, warning and uploading the metadata again when the signal
+/// turns weak or recovers. Returns the status a probe that's streaming should have.
+async fn report_link(svc: &Svc, serial: ProbeSerial, link: &LinkStats, tx: &mpsc::Sender<Upload>) -> SvcStatus {
+    let summary = link.summary(Instant::now());
+    let was_weak = svc.set_link(serial, summary).is_some_and(|l| l.weak);
+    if summary.weak != was_weak {
+        if summary.weak {
+            warn!("Probe {} has had a weak signal for a while, move the Pi closer: {:?}", serial, summary);
+        } else {
+            info!("Probe {} signal recovered: {:?}", serial, summary);
+        }
+        upload_metadata(svc, serial, tx).await;
+    }
+    if summary.weak { SvcStatus::WEAK_SIGNAL } else { SvcStatus::RUNNING }
 }
 
-#[derive(Debug)]
-struct SvcInner {
-    raw_temp: f32,
-    status: SvcStatus,
-    s3_status: S3Status,
+/// Logs an alert, keeps it with the probe and uploads the metadata so the webapp shows it
+async fn raise(svc: &Svc, serial: ProbeSerial, alert: Alert, tx: &mpsc::Sender<Upload>) {
+    warn!("Probe {}: {}", serial, alert.message);
+    svc.raise(serial, alert);
+    upload_metadata(svc, serial, tx).await;
 }
 
-impl std::fmt::Display for S3Status {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        let value = match self {
-            S3Status::UNINIT => "uninitialized".to_string(),
-            S3Status::WRITING => "writing".to_string(),
-            S3Status::ERROR => "error".to_string(),
-        };
-        write!(f, "{}", value)
+/// Publishes the probe's battery, raising an alert when it turns low unless it only just recovered
+async fn report_battery(svc: &Svc, serial: ProbeSerial, battery: Option<BatteryStatus>, tx: &mpsc::Sender<Upload>) {
+    let battery = match battery {
+        Some(battery) => battery,
+        None => return,
+    };
+    let update = svc.set_battery(serial, battery, Instant::now());
+    if !update.changed {
+        return;
+    }
+    if update.alert {
+        let message = "Battery is low, the probe may stop reporting before the cook is done".to_string();
+        raise(svc, serial, Alert::new(AlertKind::LowBattery, message), tx).await;
+    } else {
+        info!("Probe {} battery is {}", serial, battery);
+        upload_metadata(svc, serial, tx).await;
     }
 }
 
-#[derive(Debug, Clone)]
-struct Svc {
-    inner: Arc<Mutex<SvcInner>>,
+/// Raises an alert when a stall starts or ends. The ETA starts over after a stall since the
+/// readings from during it say nothing about how fast the core heats now.
+async fn report_stall(svc: &Svc, serial: ProbeSerial, event: StallEvent, eta: &mut EtaEstimator, tx: &mpsc::Sender<Upload>) {
+    let alert = match event {
+        StallEvent::Started { started_at, temp_c } => {
+            let message = format!("Core stalled at {:.1}°C ({:.1}°F)", temp_c, as_farenheit(temp_c));
+            Alert::at(started_at, AlertKind::StallStarted, message)
+        },
+        StallEvent::Ended { ended_at, .. } => {
+            eta.clear();
+            let duration = event.duration().unwrap_or_default();
+            let message = format!("Stall over after {}h{:02}m", duration.as_secs() / 3600, duration.as_secs() / 60 % 60);
+            Alert { duration: Some(duration), ..Alert::at(ended_at, AlertKind::StallEnded, message) }
+        },
+    };
+    raise(svc, serial, alert, tx).await;
+}
+
+/// Raises an alert when the meat comes off the heat and when the core peaks after, which is when
+/// resting is done
+async fn report_carryover(svc: &Svc, serial: ProbeSerial, event: CarryoverEvent, removed_at: Option<DateTime<Utc>>, tx: &mpsc::Sender<Upload>) {
+    let alert = match event {
+        CarryoverEvent::Removed { at, core_c } => {
+            let message = format!("Off the heat with the core at {:.1}°C ({:.1}°F)", core_c, as_farenheit(core_c));
+            Alert::at(at, AlertKind::RemovedFromHeat, message)
+        },
+        CarryoverEvent::Peaked { at, core_c } => {
+            let message = format!("Core peaked at {:.1}°C ({:.1}°F), resting is done", core_c, as_farenheit(core_c));
+            Alert {
+                duration: removed_at.and_then(|r| (at - r).to_std().ok()),
+                ..Alert::at(at, AlertKind::CarryoverPeaked, message)
+            }
+        },
+    };
+    raise(svc, serial, alert, tx).await;
+}
+
+/// What's worked out from a probe's readings as they come in
+#[derive(Default)]
+struct Analysis {
+    stall: StallDetector,
+    carryover: CarryoverDetector,
+    eta: EtaEstimator,
 }
 
-impl Svc {
-    pub fn new() -> Svc {
-        Svc{
-            inner: Arc::new(Mutex::new(SvcInner{
-                raw_temp: 0.0,
-                status: SvcStatus::DISCOVERING,
-                s3_status: S3Status::UNINIT,
-            })),
+/// Runs readings through the stall and carryover detectors and the ETA estimator and publishes
+/// what they make of them
+async fn analyze(svc: &Svc, serial: ProbeSerial, readings: &[ProbeReading], analysis: &mut Analysis, tx: &mpsc::Sender<Upload>) {
+    let Analysis { stall, carryover, eta } = analysis;
+    for reading in readings {
+        if let Some(event) = stall.add(reading) {
+            report_stall(svc, serial, event, eta, tx).await;
         }
+        if let Some(event) = carryover.add(reading) {
+            report_carryover(svc, serial, event, carryover.summary().removed_at, tx).await;
+        }
+        eta.add(reading);
     }
-    pub fn get_status(&self) -> SvcStatus {
-        self.inner.lock().unwrap().status
-    }
+    svc.set_stall(serial, stall.summary());
+    svc.set_carryover(serial, carryover.summary());
+    // A fit over a stall has the core taking days, which it won't, and once the meat is off the
+    // heat the carryover says where the core ends up
+    let estimate = match svc.target(serial) {
+        Some(target_c) if !stall.summary().stalled() && !carryover.summary().resting() => eta.estimate(target_c),
+        _ => None,
+    };
+    svc.set_eta(serial, estimate);
+}
 
-    pub fn get_raw_temp(&self) -> f32 {
-        self.inner.lock().unwrap().raw_temp
+/// Follows one probe through its advertisements alone, uploading a reading at most once per
+/// PASSIVE_PUSH_PERIOD
+async fn run_passive_probe(
+    serial: ProbeSerial,
+    svc: Svc,
+    bucket: Option<String>,
+    session: String,
+    mut advertisements: mpsc::Receiver<Advertisement>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let mut commands = svc.add_probe(serial);
+    let tx = spawn_pusher(svc.clone(), serial, bucket, session);
+    let mut last_push: Option<Instant> = None;
+    let mut last_metadata: Option<Instant> = None;
+    let mut link = LinkStats::new();
+    let mut analysis = Analysis::default();
+    loop {
+        tokio::select! {
+            adv = advertisements.recv() => {
+                let adv = match adv {
+                    Some(adv) => adv,
+                    None => break,
+                };
+                let reading = adv.reading();
+                if let Some(rssi) = adv.rssi {
+                    link.rssi(Instant::now(), rssi);
+                }
+                svc.set_status(serial, report_link(&svc, serial, &link, &tx).await);
+                report_battery(&svc, serial, reading.battery, &tx).await;
+                svc.set_reading(serial, reading);
+                analyze(&svc, serial, &[reading], &mut analysis, &tx).await;
+                if last_push.is_some_and(|t| t.elapsed() < PASSIVE_PUSH_PERIOD) {
+                    continue;
+                }
+                info!("Probe {} advertised core deg C={} degF={} sensors={:?} battery={:?}", serial, reading.core_c(), as_farenheit(reading.core_c()), reading.temps_c, reading.battery);
+                last_push = Some(Instant::now());
+                if let Err(e) = tx.send(Upload::Readings(vec![reading])).await {
+                    error!("Failed to send reading={:?} to pusher: {}", reading, e);
+                }
+                // Keeps the link quality, ETA and carryover the webapp shows fresh
+                if last_metadata.is_none_or(|t| t.elapsed() >= METADATA_PERIOD) {
+                    last_metadata = Some(Instant::now());
+                    upload_metadata(&svc, serial, &tx).await;
+                }
+            }
+            Some(queued) = commands.recv() => {
+                let _ = queued.reply.send(Err(anyhow::anyhow!("Can't {:?} on probe {} in passive mode", queued.command, serial)));
+            }
+            _ = shutdown.changed() => {
+                break;
+            }
+        }
     }
+}
 
-    pub fn get_s3_status(&self) -> S3Status {
-        self.inner.lock().unwrap().s3_status
-    }
+/// What's kept about a connected probe across reconnects
+#[derive(Default)]
+struct Tracking {
+    /// The newest log sequence number seen, to backfill anything skipped after it
+    last_sequence: Option<u32>,
+    /// Log ranges still to download, oldest first. The front one is being downloaded.
+    gaps: VecDeque<Gap>,
+    link: LinkStats,
+    analysis: Analysis,
+    /// Reset by the first good reading after connecting, since a probe that connects and drops
+    /// straight away is as good as one that won't connect
+    backoff: Backoff,
+}
+
+/// Why a connected session with a probe ended
+enum SessionEnd {
+    Shutdown,
+    Dropped(String),
+}
+
+/// Keeps one probe connected until shutdown, streaming its readings into its own S3 prefix. When
+/// the probe drops it reconnects with backoff, and waits for discovery to hand over a fresh device
+/// if BlueZ forgot about it.
+async fn run_probe<T: Thermometer>(
+    mut combustion: T,
+    svc: Svc,
+    mode: ReadMode,
+    bucket: Option<String>,
+    session: String,
+    mut rediscovered: mpsc::Receiver<T>,
+    mut shutdown: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let serial = combustion.serial();
+    let mut commands = svc.add_probe(serial);
+    let tx = spawn_pusher(svc.clone(), serial, bucket, session);
+
+    let mut connected_before = false;
+    let mut device_info = None;
+    let mut tracking = Tracking::default();
+    loop {
+        svc.set_status(serial, if connected_before { SvcStatus::RECONNECTING } else { SvcStatus::CONNECTING });
+        info!("Connecting to probe {} attempt={}", serial, tracking.backoff.attempts() + 1);
+        let end = match combustion.connect().await {
+            Ok(()) => {
+                connected_before = true;
+                svc.set_status(serial, SvcStatus::CONNECTED);
+                let info = combustion.device_info();
+                if device_info.as_ref() != Some(&info) {
+                    info!("Probe {} is {:?}", serial, info);
+                    svc.set_device_info(serial, info.clone());
+                    upload_metadata(&svc, serial, &tx).await;
+                    device_info = Some(info);
+                }
+                stream_probe(&combustion, &svc, mode, &tx, &mut commands, &mut tracking, &mut shutdown).await
+            },
+            Err(e) => Ok(SessionEnd::Dropped(format!("{:?}", e))),
+        };
+        match end {
+            Ok(SessionEnd::Shutdown) => break,
+            Ok(SessionEnd::Dropped(reason)) => warn!("Lost probe {}: {}", serial, reason),
+            Err(e) => warn!("Lost probe {}: {:?}", serial, e),
+        }
+        combustion.reset_connection().await;
+        svc.set_status(serial, SvcStatus::DISCONNECTED);
+
+        // After enough failures the device has probably gone out of range long enough for BlueZ to
+        // drop it, so wait for discovery to find it again
+        if tracking.backoff.attempts() >= REDISCOVER_AFTER_ATTEMPTS {
+            info!("Waiting to rediscover probe {}", serial);
+            svc.set_status(serial, SvcStatus::DISCOVERING);
+            tokio::select! {
+                found = rediscovered.recv() => match found {
+                    Some(c) => combustion = c,
+                    None => break,
+                },
+                _ = shutdown.changed() => break,
+            }
+            tracking.backoff.reset();
+            continue;
+        }
 
-    pub fn set_status(&self, status: SvcStatus) {
-        self.inner.lock().unwrap().status = status;
+        let delay = tracking.backoff.next_delay();
+        info!("Reconnecting to probe {} in {:?}", serial, delay);
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {},
+            Some(c) = rediscovered.recv() => combustion = c,
+            _ = shutdown.changed() => break,
+        }
     }
 
-    pub fn set_raw_temp(&self, raw_temp: f32) {
-        self.inner.lock().unwrap().raw_temp = raw_temp;
+    if let Err(e) = combustion.disconnect().await {
+        error!("Failed to disconnect probe {}: {:?}", serial, e);
     }
+    Ok(())
+}
 
-    pub fn set_s3_status(&self, status: S3Status) {
-        self.inner.lock().unwrap().s3_status = status;
+/// A range of log sequence numbers to download, where a start of None is the oldest entry the
+/// probe has
+type Gap = (Option<u32>, u32);
+
+/// A log download running alongside the live readings
+type Backfill<'a> = Pin<Box<dyn Future<Output = anyhow::Result<Vec<ProbeReading>>> + Send + 'a>>;
+
+/// The newest MAX_BACKFILL of the log entries `start..=end`. An open start is left open when
+/// everything up to `end` is within MAX_BACKFILL anyway.
+fn cap_gap(start: Option<u32>, end: u32) -> Gap {
+    let oldest = end.saturating_sub(MAX_BACKFILL - 1);
+    match start {
+        Some(start) => (Some(start.max(oldest)), end),
+        None if end < MAX_BACKFILL => (None, end),
+        None => (Some(oldest), end),
     }
 }
 
-impl HyperService<Request<IncomingBody>> for Svc {
-    type Response = Response<Full<Bytes>>;
-    type Error = hyper::Error;
-    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+/// Streams readings from a connected probe until shutdown or the connection is lost. Gaps in the
+/// readings are downloaded from the probe's log alongside, one at a time.
+async fn stream_probe<T: Thermometer>(
+    combustion: &T,
+    svc: &Svc,
+    mode: ReadMode,
+    tx: &mpsc::Sender<Upload>,
+    commands: &mut CommandReceiver,
+    tracking: &mut Tracking,
+    shutdown: &mut watch::Receiver<bool>,
+) -> anyhow::Result<SessionEnd> {
+    let serial = combustion.serial();
+    let Tracking { last_sequence, gaps, link, analysis, backoff } = tracking;
+    let mut disconnected = combustion.disconnected().await?;
+    let mut readings = combustion.readings(mode).await?;
+    let mut read_errors = 0;
+    let mut rssi_interval = tokio::time::interval(RSSI_PERIOD);
+    let mut metadata_interval = tokio::time::interval(METADATA_PERIOD);
+    let mut backfill: Option<Backfill<'_>> = None;
+    // The log doesn't say which sensors were core, surface and ambient, so go with what the probe
+    // picks now
+    let mut virtual_sensors = None;
+    loop {
+        if let (None, Some(&(start, end))) = (&backfill, gaps.front()) {
+            backfill = Some(Box::pin(combustion.read_logs(start, end)));
+        }
+        tokio::select! {
+            _ = rssi_interval.tick() => {
+                match combustion.rssi().await {
+                    Ok(Some(rssi)) => link.rssi(Instant::now(), rssi),
+                    Ok(None) => {},
+                    Err(e) => warn!("Couldn't read RSSI of probe {}: {:?}", serial, e),
+                }
+                report_link(svc, serial, link, tx).await;
+            }
+            // Keeps the link quality, ETA and carryover the webapp shows fresh
+            _ = metadata_interval.tick() => {
+                upload_metadata(svc, serial, tx).await;
+            }
+            reading = readings.next() => {
+                match reading {
+                    Some(Ok(reading)) => {
+                        read_errors = 0;
+                        backoff.reset();
+                        info!("Probe {} core deg C={} degF={} sensors={:?}", serial, reading.core_c(), as_farenheit(reading.core_c()), reading.temps_c);
+                        link.read(Instant::now(), true);
+                        svc.set_status(serial, report_link(svc, serial, link, tx).await);
+                        report_battery(svc, serial, reading.battery, tx).await;
+                        svc.set_reading(serial, reading);
+                        analyze(svc, serial, &[reading], analysis, tx).await;
+                        virtual_sensors = reading.virtual_sensors.or(virtual_sensors);
+                        if let Err(e) = tx.send(Upload::Readings(vec![reading])).await {
+                            error!("Failed to send reading={:?} to pusher: {}", reading, e);
+                        }
 
-    fn call(&self, req: Request<IncomingBody>) -> Self::Future {
-        fn mk_response(s: String) -> Result<Response<Full<Bytes>>, hyper::Error> {
-            Ok(Response::builder().body(Full::new(Bytes::from(s))).unwrap())
+                        // Queue up whatever we missed from the probe's log: everything it has when we
+                        // first hear from it, and any sequence numbers we skipped after that,
+                        // including while we were reconnecting
+                        if let Some(seq) = reading.sequence {
+                            let start = match *last_sequence {
+                                None => Some(None),
+                                Some(last) if seq > last + 1 => Some(Some(last + 1)),
+                                Some(_) => None,
+                            };
+                            if let (Some(start), true) = (start, seq > 0) {
+                                gaps.push_back(cap_gap(start, seq - 1));
+                            }
+                            *last_sequence = Some(last_sequence.map_or(seq, |last| last.max(seq)));
+                        }
+                    },
+                    Some(Err(e)) => {
+                        warn!("Couldn't fetch temp from probe {}: {:?}", serial, e);
+                        link.read(Instant::now(), false);
+                        report_link(svc, serial, link, tx).await;
+                        read_errors += 1;
+                        if read_errors >= MAX_READ_ERRORS {
+                            return Ok(SessionEnd::Dropped(format!("{} reads in a row failed", read_errors)));
+                        }
+                    },
+                    None => return Ok(SessionEnd::Dropped("probe stopped sending readings".into())),
+                }
+            }
+            Some(result) = OptionFuture::from(backfill.as_mut()) => {
+                backfill = None;
+                let (start, end) = gaps.pop_front().expect("downloading a gap");
+                match result {
+                    Ok(mut logs) => {
+                        for log in logs.iter_mut() {
+                            log.virtual_sensors = log.virtual_sensors.or(virtual_sensors);
+                        }
+                        analyze(svc, serial, &logs, analysis, tx).await;
+                        info!("Backfilled {} readings up to sequence {}", logs.len(), end);
+                        if let Err(e) = tx.send(Upload::Readings(logs)).await {
+                            error!("Failed to send backfill to pusher: {}", e);
+                        }
+                    },
+                    Err(e) => warn!("Couldn't backfill logs {:?}..={}: {:?}", start, end, e),
+                }
+            }
+            _ = &mut disconnected => {
+                return Ok(SessionEnd::Dropped("probe disconnected".into()));
+            }
+            Some(queued) = commands.recv() => {
+                // Queued before the probe dropped, and the caller has given up on it since
+                if queued.expired() {
+                    warn!("Dropping expired command {:?} for probe {}", queued.command, serial);
+                    continue;
+                }
+                let QueuedCommand { command, reply, .. } = queued;
+                let result = match command {
+                    Command::SetPrediction(target_c) => combustion.set_prediction(target_c).await,
+                    Command::CancelPrediction => combustion.cancel_prediction().await,
+                };
+                if let Err(e) = &result {
+                    warn!("Command {:?} for probe {} failed: {:?}", command, serial, e);
+                }
+                let _ = reply.send(result);
+            }
+            _ = shutdown.changed() => {
+                return Ok(SessionEnd::Shutdown);
+            }
         }
+    }
+}
 
-        let res = match req.uri().path() {
-            "/" => {
-                mk_response(format!("{{ \"temp\": {}, \"status\": \"{}\", \"s3\": \"{}\" }}", self.get_raw_temp(), self.get_status(), self.get_s3_status()))
-            },
-            _ => return Box::pin(async { mk_response("Whoopsie".into()) }),
-        };
-        Box::pin(async { res })
+/// Runs every probe the source finds until shutdown
+async fn run<S: ThermometerSource>(
+    finder: S,
+    svc: Svc,
+    mode: ReadMode,
+    passive: bool,
+    bucket: Option<String>,
+    mut done: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let (found_tx, mut found) = mpsc::channel(10);
+    let (adv_tx, mut advertisements) = mpsc::channel(100);
+    let mut discovery = tokio::spawn({
+        let done = done.clone();
+        async move {
+            if passive {
+                finder.advertisements(adv_tx, done).await
+            } else {
+                finder.discover(found_tx, done).await
+            }
+        }
+    });
+
+    // Every probe in the session shares the session's prefix and gets its own directory under it
+    let session = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+    let mut probes = JoinSet::new();
+    let mut connected_probes: HashMap<ProbeSerial, mpsc::Sender<S::Thermometer>> = HashMap::new();
+    let mut passive_probes: HashMap<ProbeSerial, mpsc::Sender<Advertisement>> = HashMap::new();
+    loop {
+        tokio::select! {
+            Some(combustion) = found.recv() => {
+                // A probe BlueZ forgot and found again goes back to its existing task
+                let serial = combustion.serial();
+                if let Some(probe) = connected_probes.get(&serial) {
+                    info!("Rediscovered probe {}", serial);
+                    let _ = probe.try_send(combustion);
+                    continue;
+                }
+                info!("Starting probe {}", serial);
+                let (tx, rx) = mpsc::channel(1);
+                connected_probes.insert(serial, tx);
+                probes.spawn(run_probe(combustion, svc.clone(), mode, bucket.clone(), session.clone(), rx, done.clone()));
+            }
+            Some(adv) = advertisements.recv() => {
+                let probe = passive_probes.entry(adv.serial).or_insert_with(|| {
+                    info!("Starting passive probe {}", adv.serial);
+                    let (tx, rx) = mpsc::channel(10);
+                    probes.spawn({
+                        let run = run_passive_probe(adv.serial, svc.clone(), bucket.clone(), session.clone(), rx, done.clone());
+                        async move {
+                            run.await;
+                            Ok(())
+                        }
+                    });
+                    tx
+                });
+                // A busy probe task just misses a few advertisements
+                let _ = probe.try_send(adv);
+            }
+            Some(result) = probes.join_next() => {
+                if let Ok(Err(e)) = result {
+                    error!("Probe task failed: {:?}", e);
+                }
+            }
+            result = &mut discovery => {
+                match result {
+                    Ok(Err(e)) => {
+                        error!("Could not discover combustion devices: {:?}", e);
+                        return Err(e);
+                    },
+                    _ => break,
+                }
+            }
+            _ = done.changed() => {
+                info!("Done!");
+                break;
+            }
+        }
     }
+
+    while probes.join_next().await.is_some() {}
+
+    info!("Done");
+    Ok(())
 }
 
 #[tokio::main(flavor = "current_thread")]
@@ -134,19 +617,73 @@ async fn main() -> anyhow::Result<()> {
     env_logger::init();
 
     let flags = xflags::parse_or_exit! {
+        /// Read the probe every 5 seconds instead of subscribing to notifications
+        optional --poll
+        /// Never connect, read temperatures from the probes' advertisements instead
+        optional --passive
+        /// Use a fake thermometer instead of Bluetooth, always the case without BlueZ support
+        optional --fake
+        /// Use a simulated cook instead of Bluetooth
+        optional --simulate
+        /// Thickness of the simulated meat (default 50)
+        optional --sim-thickness-mm mm: f32
+        /// Starting temperature of the simulated meat (default 4)
+        optional --sim-start-c c: f32
+        /// Simulated pit temperature (default 120)
+        optional --sim-pit-c c: f32
+        /// Temperature the surface stalls at while it dries out
+        optional --sim-stall-c c: f32
+        /// How long the stall lasts (default 120)
+        optional --sim-stall-minutes minutes: u64
+        /// When the simulated meat comes off the heat
+        optional --sim-pull-minutes minutes: u64
+        /// Simulated seconds per real second (default 60)
+        optional --sim-time-scale scale: f32
+        /// Seed for the simulator's sensor noise (default 0)
+        optional --sim-seed seed: u64
+        /// Write every advertisement and characteristic value received to a capture file
+        optional --record file: PathBuf
+        /// Bluetooth adapter to use, e.g. hci1 (default the system's default adapter)
+        optional --adapter name: String
+        /// Only connect to the probe with this serial, can be given more than once
+        repeated --probe-serial serial: ProbeSerial
+        /// Only connect to the probe at this address, can be given more than once
+        repeated --probe-address address: String
+        /// File listing the probes to connect to by serial or address, one per line, on top of
+        /// --probe-serial and --probe-address (default ~/.config/rustbustion/allowed-probes)
+        optional --allow-list file: PathBuf
+        /// Where to remember the probes connected to, so they're connected to directly next time
+        /// (default ~/.local/state/rustbustion/probes)
+        optional --state-file file: PathBuf
+        /// Play a capture from --record back instead of using Bluetooth
+        optional --replay file: PathBuf
+        /// Play a cook uploaded to S3 back instead of using Bluetooth, from a local copy of the
+        /// session directory or s3://<bucket>/<session>
+        optional --replay-session source: String
+        /// How many times faster than real time to replay (default 1)
+        optional --replay-speed speed: f32
+        /// Core temperature to estimate the time to for every probe, also settable per probe over
+        /// HTTP
+        optional --target-c c: f32
         /// Bucket to upload data into
         optional bucket: String
     };
     info!("Using bucket {:?}", flags.bucket);
+    let mode = if flags.poll {
+        ReadMode::Poll(Duration::from_millis(5000))
+    } else {
+        ReadMode::Notify
+    };
 
     // Listen for Ctrl-C
-    let (done_tx, mut done) = tokio::sync::oneshot::channel();
+    let (done_tx, done) = watch::channel(false);
     tokio::spawn(async move {
         tokio::signal::ctrl_c().await.expect("ctrlc");
         done_tx.send(true).expect("Send ctrlc");
     });
 
     let svc = Svc::new();
+    svc.set_default_target(flags.target_c.map(check_target_c).transpose()?);
 
     // Start an HTTP server to serve requests for current temp data
     let addr: std::net::SocketAddr = ([127, 0, 0, 1], 3000).into();
@@ -168,84 +705,185 @@ async fn main() -> anyhow::Result<()> {
         }
     });
 
+    // Keep scanning for the whole session so probes can join at any time
     info!("Discovering devices");
-    let finder = CombustionFinder::new().await?;
-    let mut combustion = match finder.discover(&mut done).await {
-        Ok(d) => d,
-        Err(e) => {
-            error!("Could not find combustion device: {:?}", e);
-            return Err(e);
+    if flags.simulate {
+        let defaults = CookParams::default();
+        let params = CookParams {
+            thickness_mm: flags.sim_thickness_mm.unwrap_or(defaults.thickness_mm),
+            start_c: flags.sim_start_c.unwrap_or(defaults.start_c),
+            pit_c: flags.sim_pit_c.unwrap_or(defaults.pit_c),
+            stall: flags.sim_stall_c.map(|temp_c| Stall {
+                temp_c,
+                duration: Duration::from_secs(flags.sim_stall_minutes.unwrap_or(120) * 60),
+            }),
+            pull_off: flags.sim_pull_minutes.map(|m| Duration::from_secs(m * 60)),
+            time_scale: flags.sim_time_scale.unwrap_or(defaults.time_scale),
+            seed: flags.sim_seed.unwrap_or(defaults.seed),
+        };
+        if !(params.thickness_mm >= CookParams::MIN_THICKNESS_MM && params.thickness_mm.is_finite()) {
+            anyhow::bail!("Simulated meat must be at least {} mm thick, got {}", CookParams::MIN_THICKNESS_MM, params.thickness_mm);
         }
-    };
-
-    svc.set_status(SvcStatus::CONNECTING);
+        if !(params.time_scale > 0.0 && params.time_scale.is_finite()) {
+            anyhow::bail!("Simulator time scale must be positive, got {}", params.time_scale);
+        }
+        info!("Simulating a cook: {:?}", params);
+        return run(SimulatorFinder::new(params), svc, mode, flags.passive, flags.bucket, done).await;
+    }
+    if let Some(path) = flags.replay {
+        let finder = ReplayFinder::load(&path, flags.replay_speed.unwrap_or(1.0))?;
+        return run(finder, svc, mode, flags.passive, flags.bucket, done).await;
+    }
+    if let Some(source) = flags.replay_session {
+        let finder = SessionFinder::load(&source.parse::<SessionSource>()?, flags.replay_speed.unwrap_or(1.0)).await?;
+        return run(finder, svc, mode, flags.passive, flags.bucket, done).await;
+    }
+    #[cfg(all(target_os = "linux", feature = "bluer"))]
+    if !flags.fake {
+        let recorder = flags.record.as_deref().map(Recorder::create).transpose()?;
+        let known = match flags.state_file.or_else(KnownProbes::default_path) {
+            Some(path) => KnownProbes::load(&path)?,
+            None => KnownProbes::default(),
+        };
+        let mut filter = ProbeFilter::new(flags.probe_serial, flags.probe_address);
+        if let Some(path) = flags.allow_list.or_else(ProbeFilter::default_path) {
+            filter.extend(ProbeFilter::load(&path)?);
+        }
+        if !filter.is_empty() {
+            info!("Only connecting to {:?}", filter);
+        }
+        let finder = CombustionFinder::new(flags.adapter.as_deref(), filter, known, recorder).await?;
+        return run(finder, svc, mode, flags.passive, flags.bucket, done).await;
+    }
+    if flags.record.is_some() {
+        warn!("Only Bluetooth can be recorded, ignoring --record");
+    }
+    info!("Using the fake thermometer");
+    run(FakeFinder::new(), svc, mode, flags.passive, flags.bucket, done).await
+}
 
-    info!("Connecting to device");
-    if let Err(e) = combustion.connect().await {
-        error!("Could not connect to device: {:?}", e);
-        return Err(e);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use combustion::{DeviceInfo, Disconnected, ReadingStream};
+    use std::sync::{Arc, Mutex};
+
+    /// A probe that connects every time and does whatever a test sets up, recording the connects and
+    /// log downloads
+    #[derive(Default)]
+    struct TestProbe {
+        connects: Arc<Mutex<Vec<tokio::time::Instant>>>,
+        downloads: Arc<Mutex<Vec<Gap>>>,
+        /// Sends a reading this often starting at sequence 5000, or drops straight away when None
+        reading_every: Option<Duration>,
+        /// How long downloading the log takes
+        log_delay: Duration,
     }
 
-    svc.set_status(SvcStatus::CONNECTED);
+    impl Thermometer for TestProbe {
+        fn serial(&self) -> ProbeSerial {
+            ProbeSerial(1)
+        }
 
-    // Create the Pusher
-    let (tx, mut rx) = tokio::sync::mpsc::channel(100);
-    tokio::spawn({
-        let svc = svc.clone();
-        async move {
-            let mut pusher = Pusher::new();
-            if let Some(bucket) = flags.bucket {
-                let prefix = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
-                info!("Starting S3 pusher to {}/{}", bucket, prefix);
-                pusher.init(bucket, prefix).await;
-            }
-
-            let mut i = 0;
-            while let Some(t) = rx.recv().await {
-                // Do something
-                if let Err(e) = pusher.push(t).await {
-                    error!("Failed to push t={} i={}: {}", t, i, e);
-                    svc.set_s3_status(S3Status::ERROR);
-                } else {
-                    svc.set_s3_status(S3Status::WRITING);
-                }
-                i += 1;
-            }
+        async fn connect(&mut self) -> anyhow::Result<()> {
+            self.connects.lock().unwrap().push(tokio::time::Instant::now());
+            Ok(())
         }
-    });
 
-    // Poll the thermometer and push the temps into the S3 Pusher
-    let mut interval = tokio::time::interval(tokio::time::Duration::from_millis(5000));
-    let mut i = 0;
-    loop {
-        tokio::select! {
-            _ = interval.tick() => {
-                match combustion.get_raw_temp().await? {
-                    Some(raw_temp_c) => {
-                        info!("Raw temp deg C={} degF={}", raw_temp_c, as_farenheit(raw_temp_c));
-                        svc.set_status(SvcStatus::RUNNING);
-                        svc.set_raw_temp(as_farenheit(raw_temp_c));
-                        if let Err(e) = tx.send(raw_temp_c).await {
-                            error!("Failed to send raw temp={} entry={} to pusher: {}", raw_temp_c, i, e);
-                        }
-                        i += 1;
-                    },
-                    None => {
-                        warn!("Couldn't fetch temp");
-                    }
-                }
-            }
-            _d = &mut done => {
-                info!("Done!");
-                break;
-            }
+        fn device_info(&self) -> DeviceInfo {
+            DeviceInfo::default()
+        }
+
+        async fn readings(&self, _mode: ReadMode) -> anyhow::Result<ReadingStream> {
+            let every = match self.reading_every {
+                Some(every) => every,
+                None => return Ok(Box::pin(futures::stream::empty())),
+            };
+            Ok(Box::pin(futures::stream::unfold(0, move |n| async move {
+                tokio::time::sleep(every).await;
+                let mut reading = ProbeReading::new([n as f32; combustion::NUM_SENSORS]);
+                reading.sequence = Some(5000 + n);
+                Some((Ok(reading), n + 1))
+            })))
+        }
+
+        async fn read_logs(&self, start: Option<u32>, end: u32) -> anyhow::Result<Vec<ProbeReading>> {
+            self.downloads.lock().unwrap().push((start, end));
+            tokio::time::sleep(self.log_delay).await;
+            Ok(vec![])
+        }
+
+        async fn set_prediction(&self, _target_c: f32) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn cancel_prediction(&self) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn rssi(&self) -> anyhow::Result<Option<i16>> {
+            Ok(None)
+        }
+
+        async fn disconnected(&self) -> anyhow::Result<Disconnected> {
+            Ok(Box::pin(futures::future::pending()))
+        }
+
+        async fn reset_connection(&self) {
+        }
+
+        async fn disconnect(&self) -> anyhow::Result<()> {
+            Ok(())
         }
     }
 
-    if let Err(e) = combustion.disconnect().await {
-        error!("Failed to disconnect device: {:?}", e);
+    #[tokio::test(start_paused = true)]
+    async fn backfills_alongside_live_readings() {
+        let probe = TestProbe {
+            reading_every: Some(Duration::from_secs(1)),
+            log_delay: Duration::from_secs(60 * 60),
+            ..TestProbe::default()
+        };
+        let downloads = probe.downloads.clone();
+        let svc = Svc::new();
+        let (_rediscover, rediscovered) = mpsc::channel(1);
+        let (done_tx, done) = watch::channel(false);
+        let task = tokio::spawn(run_probe(probe, svc.clone(), ReadMode::Notify, None, "session".into(), rediscovered, done));
+
+        tokio::time::sleep(Duration::from_millis(30_500)).await;
+        assert_eq!(*downloads.lock().unwrap(), [(Some(4000), 4999)]);
+        assert_eq!(svc.probe_json(ProbeSerial(1)).unwrap()["temps"][0], as_farenheit(29.0));
+
+        done_tx.send(true).unwrap();
+        task.await.unwrap().unwrap();
     }
 
-    info!("Done");
-    Ok(())
+    #[test]
+    fn caps_the_backfill() {
+        assert_eq!(cap_gap(None, 99), (None, 99));
+        assert_eq!(cap_gap(None, 999), (None, 999));
+        assert_eq!(cap_gap(None, 1000), (Some(1), 1000));
+        assert_eq!(cap_gap(None, 5000), (Some(4001), 5000));
+        assert_eq!(cap_gap(Some(10), 5000), (Some(4001), 5000));
+        assert_eq!(cap_gap(Some(4500), 5000), (Some(4500), 5000));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn backs_off_a_probe_that_drops_right_after_connecting() {
+        let probe = TestProbe::default();
+        let connects = probe.connects.clone();
+        let svc = Svc::new();
+        let (_rediscover, rediscovered) = mpsc::channel(1);
+        let (done_tx, done) = watch::channel(false);
+        let task = tokio::spawn(run_probe(probe, svc.clone(), ReadMode::Notify, None, "session".into(), rediscovered, done));
+
+        tokio::time::sleep(Duration::from_secs(10 * 60)).await;
+        let connects = connects.lock().unwrap().clone();
+        let gaps: Vec<u64> = connects.windows(2).map(|w| (w[1] - w[0]).as_secs()).collect();
+        assert_eq!(gaps, [1, 2, 4, 8, 16, 32, 60, 60]);
+        assert_eq!(svc.probe_json(ProbeSerial(1)).unwrap()["status"], "discovering");
+
+        done_tx.send(true).unwrap();
+        task.await.unwrap().unwrap();
+    }
 }
@@ -3,19 +3,16 @@ use aws_smithy_types::byte_stream::ByteStream;
 use bytes::Bytes;
 use chrono::prelude::*;
 
-const BATCH_SIZE: usize = 1000;
+use crate::combustion::{ProbeReading, VirtualSensors, NUM_SENSORS};
 
-struct Update {
-    temp: f32,
-    time: DateTime<Utc>,
-}
+const BATCH_SIZE: usize = 1000;
 
 pub struct Pusher {
     client: Option<Client>,
     bucket: String,
     prefix: String,
     key: u32,
-    window: std::vec::Vec<Update>,
+    window: std::vec::Vec<ProbeReading>,
 }
 
 impl Pusher {
@@ -38,20 +35,37 @@ impl Pusher {
         self.client = Some(client);
     }
 
-    pub async fn push(&mut self, t: f32) -> anyhow::Result<()> {
+    /// Adds the readings to the window in time order and uploads it. Readings backfilled from the
+    /// probe's log can be older than what's already in the window.
+    pub async fn push(&mut self, readings: Vec<ProbeReading>) -> anyhow::Result<()> {
         if self.client.is_none() {
             return Ok(());
         }
 
-        let client = self.client.as_ref().unwrap();
-        // If we've exceeded the window size then clear it and increment the key
-        if self.window.len() > BATCH_SIZE {
-            self.window.clear();
-            self.key += 1;
+        let mut dirty = false;
+        for reading in readings {
+            // If we've exceeded the window size then upload anything pending, clear it and increment the key
+            if self.window.len() > BATCH_SIZE {
+                if dirty {
+                    self.upload().await?;
+                }
+                self.window.clear();
+                self.key += 1;
+            }
+
+            let idx = self.window.partition_point(|r| r.time <= reading.time);
+            self.window.insert(idx, reading);
+            dirty = true;
+        }
+
+        if dirty {
+            self.upload().await?;
         }
+        Ok(())
+    }
 
-        // Push the new temp into the window and serialize it
-        self.window.push(Update{temp: t, time: Utc::now()});
+    async fn upload(&self) -> anyhow::Result<()> {
+        let client = self.client.as_ref().unwrap();
         let obj = self.serialize();
 
         // Upload to S3
@@ -68,15 +82,109 @@ impl Pusher {
         Ok(())
     }
 
+    /// Uploads what's known about the probe as `<prefix>/metadata.json`, next to its readings
+    pub async fn put_metadata(&self, metadata: &serde_json::Value) -> anyhow::Result<()> {
+        let client = match self.client.as_ref() {
+            Some(client) => client,
+            None => return Ok(()),
+        };
+        client
+            .put_object()
+            .bucket(self.bucket.clone())
+            .key(format!("{}/metadata.json", self.prefix))
+            .content_type("application/json")
+            .body(ByteStream::from(Bytes::from(metadata.to_string())))
+            .send()
+            .await?;
+        Ok(())
+    }
+
     fn serialize(&self) -> String {
-        // Format is "temp,datetime" joined by new lines where the latest value is the first
+        // Format is "temp,datetime,t1,...,t8[,core,surface,ambient]" joined by new lines where the
+        // latest value is the first. temp is the core, or the tip (T1) when the probe didn't say
+        // which sensor that is. core, surface and ambient are the numbers of the sensors the probe
+        // picked, e.g. 3 for T3, and are left off without a pick. Readers from before the sensors
+        // were added only take "temp,datetime" lines and reject these.
         self.window
             .iter()
             .rev()
             .map(|v| {
-                format!("{},{}", v.temp, v.time.to_rfc3339_opts(SecondsFormat::Millis, true))
+                let mut fields: Vec<String> = v.temps_c.iter().map(|t| t.to_string()).collect();
+                if let Some(vs) = v.virtual_sensors {
+                    fields.extend([vs.core, vs.surface, vs.ambient].map(|i| (i + 1).to_string()));
+                }
+                format!("{},{},{}", v.core_c(), v.time.to_rfc3339_opts(SecondsFormat::Millis, true), fields.join(","))
             })
             .collect::<Vec<String>>()
             .join("\n")
     }
 }
+
+/// Parses a chunk written by `Pusher::serialize` back into readings, newest first like the chunk.
+/// Chunks from before all the sensors were pushed only have the tip, which is used for every sensor.
+pub fn parse_chunk(contents: &str) -> anyhow::Result<Vec<ProbeReading>> {
+    contents
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|line| {
+            let parts: Vec<&str> = line.trim().split(',').collect();
+            if ![2, NUM_SENSORS + 2, NUM_SENSORS + 5].contains(&parts.len()) {
+                anyhow::bail!("expected 2, {} or {} parts got {}: {}", NUM_SENSORS + 2, NUM_SENSORS + 5, parts.len(), line);
+            }
+            let temp = parts[0].parse::<f32>()?;
+            let mut temps_c = [temp; NUM_SENSORS];
+            for (t, p) in temps_c.iter_mut().zip(&parts[2..]) {
+                *t = p.parse()?;
+            }
+            let virtual_sensors = match parts.get(NUM_SENSORS + 2..) {
+                Some([core, surface, ambient]) => {
+                    let index = |n: &str| match n.parse::<usize>() {
+                        Ok(n @ 1..=NUM_SENSORS) => Ok(n - 1),
+                        _ => Err(anyhow::anyhow!("bad sensor number {:?}: {}", n, line)),
+                    };
+                    Some(VirtualSensors { core: index(core)?, surface: index(surface)?, ambient: index(ambient)? })
+                },
+                _ => None,
+            };
+            Ok(ProbeReading {
+                time: DateTime::parse_from_rfc3339(parts[1])?.with_timezone(&Utc),
+                virtual_sensors,
+                ..ProbeReading::new(temps_c)
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_round_trip() {
+        let mut pusher = Pusher::new();
+        pusher.window = (0..3)
+            .map(|i| ProbeReading::at(i as f32 * 5.0, [20.5 + i as f32; NUM_SENSORS]))
+            .collect();
+        let mut readings = parse_chunk(&pusher.serialize()).unwrap();
+        readings.reverse();
+        assert_eq!(readings, pusher.window);
+
+        // With a virtual sensor pick the core comes first and the pick goes at the end
+        let mut temps = [0.0; NUM_SENSORS];
+        for (i, t) in temps.iter_mut().enumerate() {
+            *t = 40.0 + i as f32;
+        }
+        pusher.window = vec![ProbeReading {
+            virtual_sensors: Some(VirtualSensors { core: 2, surface: 4, ambient: 7 }),
+            ..ProbeReading::at(0.0, temps)
+        }];
+        let chunk = pusher.serialize();
+        assert_eq!(chunk, "42,2024-06-01T12:00:00.000Z,40,41,42,43,44,45,46,47,3,5,8");
+        assert_eq!(parse_chunk(&chunk).unwrap(), pusher.window);
+        assert!(parse_chunk("42,2024-06-01T12:00:00.000Z,40,41,42,43,44,45,46,47,0,5,8").is_err());
+
+        let old = parse_chunk("41.5,2024-06-01T12:00:00.000Z\n").unwrap();
+        assert_eq!(old[0].temps_c, [41.5; NUM_SENSORS]);
+        assert!(parse_chunk("41.5,2024-06-01T12:00:00.000Z,1,2").is_err());
+    }
+}
@@ -0,0 +1,198 @@
+// The stall on low and slow cooks, where evaporation off the surface holds the core flat for hours
+// somewhere around 65-75°C
+//
+// A stall starts once the core has barely moved for a while inside that range, and ends once the
+// core gets a few degrees away from where it stalled, usually up after wrapping or once the
+// surface dries out, or down when the meat comes off the heat.
+use chrono::prelude::*;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use crate::combustion::ProbeReading;
+use crate::fit::Line;
+
+// Where the core stalls, with some room either side
+const STALL_MIN_C: f32 = 60.0;
+const STALL_MAX_C: f32 = 80.0;
+// How long the core has to be flat, and how flat
+const WINDOW: Duration = Duration::from_secs(15 * 60);
+const MIN_SPAN: Duration = Duration::from_secs(12 * 60);
+const FLAT_C_PER_HOUR: f32 = 2.0;
+// How far the core moves from the stall temperature for it to be over
+const EXIT_C: f32 = 3.0;
+
+/// A stall starting or ending, with the time of the reading it was spotted in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StallEvent {
+    /// `started_at` is when the core went flat, which is up to WINDOW before it was spotted
+    Started { started_at: DateTime<Utc>, temp_c: f32 },
+    Ended { started_at: DateTime<Utc>, ended_at: DateTime<Utc> },
+}
+
+impl StallEvent {
+    pub fn duration(&self) -> Option<Duration> {
+        match self {
+            StallEvent::Started { .. } => None,
+            StallEvent::Ended { started_at, ended_at } => (*ended_at - *started_at).to_std().ok(),
+        }
+    }
+}
+
+/// The stall going on now, if any, and the one before it
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StallSummary {
+    /// When the current stall started and the core temperature it stalled at
+    pub current: Option<(DateTime<Utc>, f32)>,
+    /// When the last stall started and ended
+    pub last: Option<(DateTime<Utc>, DateTime<Utc>)>,
+    /// When the newest reading was taken
+    pub as_of: Option<DateTime<Utc>>,
+}
+
+impl StallSummary {
+    pub fn stalled(&self) -> bool {
+        self.current.is_some()
+    }
+
+    pub fn to_json(self) -> serde_json::Value {
+        let seconds = |from: DateTime<Utc>, to: DateTime<Utc>| (to - from).num_seconds().max(0);
+        let time = |t: DateTime<Utc>| t.to_rfc3339_opts(SecondsFormat::Secs, true);
+        serde_json::json!({
+            "stalled": self.stalled(),
+            "started_at": self.current.map(|(t, _)| time(t)),
+            "temp": self.current.map(|(_, c)| crate::as_farenheit(c)),
+            "seconds": self.current.zip(self.as_of).map(|((t, _), now)| seconds(t, now)),
+            "last": self.last.map(|(start, end)| serde_json::json!({
+                "started_at": time(start),
+                "ended_at": time(end),
+                "seconds": seconds(start, end),
+            })),
+        })
+    }
+}
+
+/// Watches the core over the last WINDOW of readings for a stall
+#[derive(Debug, Default)]
+pub struct StallDetector {
+    /// (time, core) in time order
+    samples: VecDeque<(DateTime<Utc>, f32)>,
+    summary: StallSummary,
+}
+
+impl StallDetector {
+    pub fn summary(&self) -> StallSummary {
+        self.summary
+    }
+
+    /// Adds a reading, returning whether a stall started or ended with it. Readings backfilled
+    /// from the log fill in the history but only new readings can start or end a stall.
+    pub fn add(&mut self, reading: &ProbeReading) -> Option<StallEvent> {
+        let (time, core) = (reading.time, reading.core_c());
+        let newest = self.summary.as_of.is_none_or(|t| time > t);
+        let idx = self.samples.partition_point(|(t, _)| *t <= time);
+        self.samples.insert(idx, (time, core));
+        if !newest {
+            return None;
+        }
+        self.summary.as_of = Some(time);
+        while self.samples.front().is_some_and(|(t, _)| (time - *t).to_std().is_ok_and(|age| age > WINDOW)) {
+            self.samples.pop_front();
+        }
+
+        match self.summary.current {
+            Some((started_at, temp_c)) => {
+                if (core - temp_c).abs() < EXIT_C {
+                    return None;
+                }
+                self.summary.current = None;
+                self.summary.last = Some((started_at, time));
+                Some(StallEvent::Ended { started_at, ended_at: time })
+            },
+            None => {
+                let &(first, _) = self.samples.front()?;
+                let flat = (time - first).to_std().is_ok_and(|span| span >= MIN_SPAN)
+                    && self.samples.iter().all(|(_, c)| (STALL_MIN_C..=STALL_MAX_C).contains(c))
+                    && slope_per_hour(&self.samples).abs() < FLAT_C_PER_HOUR;
+                if !flat {
+                    return None;
+                }
+                let temp_c = self.samples.iter().map(|(_, c)| c).sum::<f32>() / self.samples.len() as f32;
+                self.summary.current = Some((first, temp_c));
+                Some(StallEvent::Started { started_at: first, temp_c })
+            },
+        }
+    }
+}
+
+/// Least squares slope of the samples in degrees per hour
+fn slope_per_hour(samples: &VecDeque<(DateTime<Utc>, f32)>) -> f32 {
+    let &(first, _) = match samples.front() {
+        Some(s) => s,
+        None => return 0.0,
+    };
+    let points: Vec<(f32, f32)> = samples
+        .iter()
+        .map(|(t, c)| ((*t - first).num_milliseconds() as f32 / 3_600_000.0, *c))
+        .collect();
+    Line::fit(&points).map_or(0.0, |line| line.slope)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::combustion::{test_start, NUM_SENSORS};
+
+    fn reading(minutes: f32, core: f32) -> ProbeReading {
+        ProbeReading::at(minutes * 60.0, [core; NUM_SENSORS])
+    }
+
+    #[test]
+    fn starts_and_ends() {
+        let start = test_start();
+        let mut stall = StallDetector::default();
+        let mut events = vec![];
+
+        // Climbs at 20°C an hour from 40°C to 68°C, sits there for two hours, then climbs again
+        let mut minutes: f32 = 0.0;
+        while minutes <= 300.0 {
+            let core = match minutes {
+                m if m < 84.0 => 40.0 + m / 3.0,
+                m if m < 204.0 => 68.0 + (m * 7.0).sin() * 0.2,
+                m => 68.0 + (m - 204.0) / 3.0,
+            };
+            events.extend(stall.add(&reading(minutes, core)));
+            if minutes == 100.0 {
+                assert!(stall.summary().stalled());
+            }
+            minutes += 0.5;
+        }
+
+        let at = |m: i64| start + chrono::Duration::minutes(m);
+        assert_eq!(events.len(), 2, "{:?}", events);
+        match events[0] {
+            StallEvent::Started { started_at, temp_c } => {
+                // The core flattened at 84 minutes, the start of the window it's spotted in is
+                // a little before
+                assert!(started_at >= at(80) && started_at <= at(86), "{}", started_at);
+                assert!((temp_c - 68.0).abs() < 0.5);
+            },
+            e => panic!("{:?}", e),
+        }
+        // Ends once the core is EXIT_C over, about 9 minutes after it starts climbing
+        assert!(matches!(events[1], StallEvent::Ended { ended_at, .. } if ended_at >= at(212) && ended_at <= at(214)));
+        assert!(events[1].duration().unwrap() > Duration::from_secs(2 * 60 * 60));
+        assert!(!stall.summary().stalled());
+        assert!(stall.summary().last.is_some());
+    }
+
+    #[test]
+    fn ignores_flat_outside_the_range() {
+        let mut stall = StallDetector::default();
+        // Sitting in the fridge, then a backfilled reading in the range
+        for i in 0..120 {
+            assert_eq!(stall.add(&reading(i as f32, 4.0)), None);
+        }
+        assert_eq!(stall.add(&reading(10.5, 70.0)), None);
+        assert!(!stall.summary().stalled());
+    }
+}
@@ -0,0 +1,53 @@
+// Pacing for reconnecting to a probe that dropped
+use std::time::Duration;
+
+const INITIAL_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Capped exponential backoff: 1s, 2s, 4s, ... up to a minute between attempts
+#[derive(Debug, Default)]
+pub struct Backoff {
+    attempts: u32,
+}
+
+impl Backoff {
+    /// How many attempts have failed since the last reset
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Records a failed attempt and returns how long to wait before the next one
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = INITIAL_DELAY
+            .checked_mul(1 << self.attempts.min(16))
+            .map_or(MAX_DELAY, |d| d.min(MAX_DELAY));
+        self.attempts += 1;
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn doubles_up_to_the_cap() {
+        let mut backoff = Backoff::default();
+        let delays: Vec<u64> = (0..9).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 32, 60, 60, 60]);
+        assert_eq!(backoff.attempts(), 9);
+
+        for _ in 0..100 {
+            backoff.next_delay();
+        }
+        assert_eq!(backoff.next_delay(), MAX_DELAY);
+
+        backoff.reset();
+        assert_eq!(backoff.attempts(), 0);
+        assert_eq!(backoff.next_delay(), INITIAL_DELAY);
+    }
+}
@@ -0,0 +1,520 @@
+// State shared between the probe tasks and the HTTP server, one entry per probe
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{body::Incoming as IncomingBody};
+use hyper::service::{Service as HyperService};
+use hyper::{Method, Request, Response, StatusCode};
+use serde_json::json;
+use std::future::Future;
+use std::pin::Pin;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::alert::{Alert, Alerts};
+use crate::as_farenheit;
+use crate::carryover::CarryoverSummary;
+use crate::combustion::{uart, BatteryStatus, DeviceInfo, ProbeReading, ProbeSerial};
+use crate::eta::Estimate;
+use crate::stall::StallSummary;
+use crate::link::LinkSummary;
+
+// How long a command waits, queued and then carried out, before the caller gives up on it
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+// How long the battery has to stay Ok before turning low again is worth another alert, so one
+// hovering at the threshold doesn't flood them
+const BATTERY_SETTLE: Duration = Duration::from_secs(10 * 60);
+
+#[allow(clippy::upper_case_acronyms, non_camel_case_types)]
+#[derive(Clone, Copy, Debug)]
+pub enum SvcStatus {
+    DISCOVERING,
+    CONNECTING,
+    CONNECTED,
+    RUNNING,
+    /// Lost the probe, waiting before trying again
+    DISCONNECTED,
+    RECONNECTING,
+    /// Running, but the signal has been bad for a while and the Pi should move closer
+    WEAK_SIGNAL,
+}
+
+impl std::fmt::Display for SvcStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let value = match self {
+            SvcStatus::DISCOVERING => "discovering".to_string(),
+            SvcStatus::CONNECTING => "connecting".to_string(),
+            SvcStatus::CONNECTED => "connected".to_string(),
+            SvcStatus::RUNNING => "running".to_string(),
+            SvcStatus::DISCONNECTED => "disconnected".to_string(),
+            SvcStatus::RECONNECTING => "reconnecting".to_string(),
+            SvcStatus::WEAK_SIGNAL => "weak_signal".to_string(),
+        };
+        write!(f, "{}", value)
+    }
+}
+
+impl SvcStatus {
+    /// Whether the probe's task is streaming and so taking commands
+    pub fn connected(&self) -> bool {
+        matches!(self, SvcStatus::CONNECTED | SvcStatus::RUNNING | SvcStatus::WEAK_SIGNAL)
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug)]
+pub enum S3Status {
+    UNINIT,
+    WRITING,
+    ERROR,
+}
+
+impl std::fmt::Display for S3Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let value = match self {
+            S3Status::UNINIT => "uninitialized".to_string(),
+            S3Status::WRITING => "writing".to_string(),
+            S3Status::ERROR => "error".to_string(),
+        };
+        write!(f, "{}", value)
+    }
+}
+
+/// Things the HTTP server asks a probe's task to do with the probe
+#[derive(Clone, Copy, Debug)]
+pub enum Command {
+    SetPrediction(f32),
+    CancelPrediction,
+}
+
+/// A command waiting for the probe's task, which drops it once the deadline has passed since the
+/// caller has given up on it by then
+#[derive(Debug)]
+pub struct QueuedCommand {
+    pub command: Command,
+    pub deadline: Instant,
+    pub reply: oneshot::Sender<anyhow::Result<()>>,
+}
+
+impl QueuedCommand {
+    pub fn expired(&self) -> bool {
+        Instant::now() >= self.deadline
+    }
+}
+
+pub type CommandSender = mpsc::Sender<QueuedCommand>;
+pub type CommandReceiver = mpsc::Receiver<QueuedCommand>;
+
+/// Why a command wasn't carried out
+#[derive(Debug)]
+pub enum CommandError {
+    UnknownProbe(ProbeSerial),
+    /// The probe isn't connected, or is still busy with earlier commands
+    Unavailable(String),
+    Failed(anyhow::Error),
+}
+
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CommandError::UnknownProbe(serial) => write!(f, "Unknown probe {}", serial),
+            CommandError::Unavailable(why) => write!(f, "Probe unavailable: {}", why),
+            CommandError::Failed(e) => write!(f, "{:?}", e),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+/// Checks a target is one the probe can be set to, which covers anything sensible for the ETA
+pub fn check_target_c(target_c: f32) -> anyhow::Result<f32> {
+    if !(0.0..=uart::MAX_SET_POINT_C).contains(&target_c) {
+        anyhow::bail!("target_c must be between 0 and {:.1}°C, got {}", uart::MAX_SET_POINT_C, target_c);
+    }
+    Ok(target_c)
+}
+
+/// The target_c in a query string like `target_c=57`
+fn parse_target_c(query: &str) -> anyhow::Result<f32> {
+    let target_c = query
+        .split('&')
+        .find_map(|kv| kv.strip_prefix("target_c="))
+        .ok_or_else(|| anyhow::anyhow!("Missing target_c"))?;
+    check_target_c(target_c.parse().map_err(|e| anyhow::anyhow!("Bad target_c {}: {}", target_c, e))?)
+}
+
+/// What `Svc::set_battery` made of a battery update
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BatteryUpdate {
+    pub changed: bool,
+    /// Raise a low battery alert
+    pub alert: bool,
+}
+
+#[derive(Debug)]
+struct ProbeState {
+    reading: ProbeReading,
+    status: SvcStatus,
+    s3_status: S3Status,
+    device_info: DeviceInfo,
+    link: LinkSummary,
+    /// Kept apart from the reading since readings backfilled from the log don't carry it
+    battery: Option<BatteryStatus>,
+    /// When the battery last turned Ok
+    battery_ok_since: Option<Instant>,
+    low_battery_alerted: bool,
+    alerts: Alerts,
+    /// What the core should reach, for the ETA
+    target_c: Option<f32>,
+    eta: Option<Estimate>,
+    stall: StallSummary,
+    carryover: CarryoverSummary,
+    commands: CommandSender,
+}
+
+#[derive(Debug, Default)]
+struct SvcInner {
+    probes: BTreeMap<ProbeSerial, ProbeState>,
+    /// Target for probes as they're added
+    default_target_c: Option<f32>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Svc {
+    inner: Arc<Mutex<SvcInner>>,
+}
+
+impl Svc {
+    pub fn new() -> Svc {
+        Svc::default()
+    }
+
+    /// Starts tracking a probe and returns the receiving end of its command channel
+    pub fn add_probe(&self, serial: ProbeSerial) -> CommandReceiver {
+        let (commands, rx) = mpsc::channel(10);
+        let mut inner = self.inner.lock().unwrap();
+        let target_c = inner.default_target_c;
+        inner.probes.insert(serial, ProbeState {
+            reading: ProbeReading::default(),
+            status: SvcStatus::DISCOVERING,
+            s3_status: S3Status::UNINIT,
+            device_info: DeviceInfo::default(),
+            link: LinkSummary::default(),
+            battery: None,
+            battery_ok_since: None,
+            low_battery_alerted: false,
+            alerts: Alerts::default(),
+            target_c,
+            eta: None,
+            stall: StallSummary::default(),
+            carryover: CarryoverSummary::default(),
+            commands,
+        });
+        rx
+    }
+
+    /// Sets the target of every probe added from now on
+    pub fn set_default_target(&self, target_c: Option<f32>) {
+        self.inner.lock().unwrap().default_target_c = target_c;
+    }
+
+    pub fn probes(&self) -> Vec<ProbeSerial> {
+        self.inner.lock().unwrap().probes.keys().copied().collect()
+    }
+
+    fn with_probe<T>(&self, serial: ProbeSerial, f: impl FnOnce(&mut ProbeState) -> T) -> Option<T> {
+        self.inner.lock().unwrap().probes.get_mut(&serial).map(f)
+    }
+
+    pub fn set_status(&self, serial: ProbeSerial, status: SvcStatus) {
+        self.with_probe(serial, |p| p.status = status);
+    }
+
+    pub fn set_reading(&self, serial: ProbeSerial, reading: ProbeReading) {
+        self.with_probe(serial, |p| p.reading = reading);
+    }
+
+    pub fn set_device_info(&self, serial: ProbeSerial, info: DeviceInfo) {
+        self.with_probe(serial, |p| p.device_info = info);
+    }
+
+    /// Updates the probe's link quality, returning what it was before
+    pub fn set_link(&self, serial: ProbeSerial, link: LinkSummary) -> Option<LinkSummary> {
+        self.with_probe(serial, |p| std::mem::replace(&mut p.link, link))
+    }
+
+    /// Updates the probe's battery, returning whether it changed and whether it turned low in a
+    /// way worth an alert: the first time, or after being Ok for BATTERY_SETTLE since
+    pub fn set_battery(&self, serial: ProbeSerial, battery: BatteryStatus, now: Instant) -> BatteryUpdate {
+        self.with_probe(serial, |p| {
+            let changed = p.battery.replace(battery) != Some(battery);
+            let settled = p.battery_ok_since.is_some_and(|since| now.saturating_duration_since(since) >= BATTERY_SETTLE);
+            let alert = changed && battery == BatteryStatus::Low && (!p.low_battery_alerted || settled);
+            p.low_battery_alerted |= alert;
+            if changed && battery == BatteryStatus::Ok {
+                p.battery_ok_since = Some(now);
+            }
+            BatteryUpdate { changed, alert }
+        }).unwrap_or_default()
+    }
+
+    pub fn raise(&self, serial: ProbeSerial, alert: Alert) {
+        self.with_probe(serial, |p| p.alerts.push(alert));
+    }
+
+    pub fn target(&self, serial: ProbeSerial) -> Option<f32> {
+        self.with_probe(serial, |p| p.target_c).flatten()
+    }
+
+    /// Sets or clears what the core should reach, dropping the ETA for the old target
+    pub fn set_target(&self, serial: ProbeSerial, target_c: Option<f32>) -> bool {
+        self.with_probe(serial, |p| {
+            p.target_c = target_c;
+            p.eta = None;
+        }).is_some()
+    }
+
+    pub fn set_eta(&self, serial: ProbeSerial, eta: Option<Estimate>) {
+        self.with_probe(serial, |p| p.eta = eta);
+    }
+
+    pub fn set_stall(&self, serial: ProbeSerial, stall: StallSummary) {
+        self.with_probe(serial, |p| p.stall = stall);
+    }
+
+    pub fn set_carryover(&self, serial: ProbeSerial, carryover: CarryoverSummary) {
+        self.with_probe(serial, |p| p.carryover = carryover);
+    }
+
+    pub fn set_s3_status(&self, serial: ProbeSerial, status: S3Status) {
+        self.with_probe(serial, |p| p.s3_status = status);
+    }
+
+    /// Hands a command to the probe's task and waits for it to be carried out. Fails straight away
+    /// rather than queueing the command when the probe isn't connected or its queue is full.
+    pub async fn command(&self, serial: ProbeSerial, command: Command) -> Result<(), CommandError> {
+        let (commands, status) = self.with_probe(serial, |p| (p.commands.clone(), p.status))
+            .ok_or(CommandError::UnknownProbe(serial))?;
+        if !status.connected() {
+            return Err(CommandError::Unavailable(format!("probe is {}", status)));
+        }
+        let (reply, rx) = oneshot::channel();
+        let queued = QueuedCommand { command, deadline: Instant::now() + COMMAND_TIMEOUT, reply };
+        commands.try_send(queued).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => CommandError::Unavailable("too many commands waiting".into()),
+            mpsc::error::TrySendError::Closed(_) => CommandError::Unavailable("probe task stopped".into()),
+        })?;
+        match tokio::time::timeout(COMMAND_TIMEOUT, rx).await {
+            Ok(Ok(result)) => result.map_err(CommandError::Failed),
+            Ok(Err(_)) => Err(CommandError::Unavailable("probe dropped the command".into())),
+            Err(_) => Err(CommandError::Failed(anyhow::anyhow!("Timed out after {:?}", COMMAND_TIMEOUT))),
+        }
+    }
+
+    pub fn probe_json(&self, serial: ProbeSerial) -> Option<serde_json::Value> {
+        self.with_probe(serial, |p| {
+            let reading = p.reading;
+            let prediction = reading.prediction.map(|p| json!({
+                "state": format!("{:?}", p.state),
+                "mode": format!("{:?}", p.mode),
+                "set_point": as_farenheit(p.set_point_c),
+                "estimated_core": as_farenheit(p.estimated_core_c),
+                "seconds_remaining": p.seconds_remaining,
+            }));
+            json!({
+                "temp": as_farenheit(reading.core_c()),
+                "core": as_farenheit(reading.core_c()),
+                "surface": as_farenheit(reading.surface_c()),
+                "ambient": as_farenheit(reading.ambient_c()),
+                "temps": reading.temps_c.map(as_farenheit),
+                "status": p.status.to_string(),
+                "s3": p.s3_status.to_string(),
+                "prediction": prediction,
+                "device": p.device_info.to_json(),
+                "link": p.link.to_json(),
+                "battery": p.battery.map(|b| b.to_string()),
+                "alerts": p.alerts.to_json(),
+                "target": p.target_c.map(as_farenheit),
+                "eta": p.eta.map(Estimate::to_json),
+                "stall": p.stall.to_json(),
+                "carryover": p.carryover.to_json(),
+            })
+        })
+    }
+
+    /// What's uploaded to S3 next to the probe's readings
+    pub fn metadata_json(&self, serial: ProbeSerial) -> Option<serde_json::Value> {
+        self.with_probe(serial, |p| json!({
+            "serial": serial.to_string(),
+            "device": p.device_info.to_json(),
+            "link": p.link.to_json(),
+            "battery": p.battery.map(|b| b.to_string()),
+            "alerts": p.alerts.to_json(),
+            "target": p.target_c.map(as_farenheit),
+            "eta": p.eta.map(Estimate::to_json),
+            "stall": p.stall.to_json(),
+            "carryover": p.carryover.to_json(),
+        }))
+    }
+
+    pub fn status_json(&self) -> serde_json::Value {
+        let probes: serde_json::Map<String, serde_json::Value> = self.probes()
+            .into_iter()
+            .filter_map(|serial| self.probe_json(serial).map(|p| (serial.to_string(), p)))
+            .collect();
+        json!({ "probes": probes })
+    }
+}
+
+impl HyperService<Request<IncomingBody>> for Svc {
+    type Response = Response<Full<Bytes>>;
+    type Error = hyper::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn call(&self, req: Request<IncomingBody>) -> Self::Future {
+        fn mk_response(s: String) -> Result<Response<Full<Bytes>>, hyper::Error> {
+            mk_status_response(StatusCode::OK, s)
+        }
+        fn mk_status_response(status: StatusCode, s: String) -> Result<Response<Full<Bytes>>, hyper::Error> {
+            Ok(Response::builder().status(status).body(Full::new(Bytes::from(s))).unwrap())
+        }
+        fn target_c(req: &Request<IncomingBody>) -> anyhow::Result<f32> {
+            parse_target_c(req.uri().query().unwrap_or_default())
+        }
+
+        // Everything per probe lives under /probes/<serial>
+        let path = req.uri().path().to_string();
+        let probe = path.strip_prefix("/probes/").map(|rest| {
+            let (serial, sub) = rest.split_once('/').unwrap_or((rest, ""));
+            (serial.parse::<ProbeSerial>().ok(), sub)
+        });
+
+        let res = match (req.method(), path.as_str(), probe) {
+            (_, "/", _) => {
+                mk_response(self.status_json().to_string())
+            },
+            (_, _, Some((None, _))) => {
+                mk_status_response(StatusCode::BAD_REQUEST, "Bad probe serial".into())
+            },
+            (&Method::GET, _, Some((Some(serial), ""))) => {
+                match self.probe_json(serial) {
+                    Some(p) => mk_response(p.to_string()),
+                    None => mk_status_response(StatusCode::NOT_FOUND, format!("Unknown probe {}", serial)),
+                }
+            },
+            // POST /probes/<serial>/prediction?target_c=57 to set a target on the probe, DELETE to
+            // cancel it. The target is also used for the ETA.
+            (&Method::POST, _, Some((Some(serial), "prediction"))) | (&Method::DELETE, _, Some((Some(serial), "prediction"))) => {
+                let command = if req.method() == Method::DELETE {
+                    Command::CancelPrediction
+                } else {
+                    match target_c(&req) {
+                        Ok(t) => Command::SetPrediction(t),
+                        Err(e) => return Box::pin(async move { mk_status_response(StatusCode::BAD_REQUEST, e.to_string()) }),
+                    }
+                };
+                let svc = self.clone();
+                return Box::pin(async move {
+                    match svc.command(serial, command).await {
+                        Ok(()) => {
+                            if let Command::SetPrediction(t) = command {
+                                svc.set_target(serial, Some(t));
+                            }
+                            mk_response("ok".into())
+                        },
+                        Err(e @ CommandError::UnknownProbe(_)) => mk_status_response(StatusCode::NOT_FOUND, e.to_string()),
+                        Err(e @ CommandError::Unavailable(_)) => mk_status_response(StatusCode::SERVICE_UNAVAILABLE, e.to_string()),
+                        Err(e @ CommandError::Failed(_)) => mk_status_response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+                    }
+                });
+            },
+            // POST /probes/<serial>/target?target_c=57 to only set the target for the ETA, DELETE
+            // to clear it
+            (&Method::POST, _, Some((Some(serial), "target"))) | (&Method::DELETE, _, Some((Some(serial), "target"))) => {
+                let target = if req.method() == Method::DELETE {
+                    None
+                } else {
+                    match target_c(&req) {
+                        Ok(t) => Some(t),
+                        Err(e) => return Box::pin(async move { mk_status_response(StatusCode::BAD_REQUEST, e.to_string()) }),
+                    }
+                };
+                if self.set_target(serial, target) {
+                    mk_response("ok".into())
+                } else {
+                    mk_status_response(StatusCode::NOT_FOUND, format!("Unknown probe {}", serial))
+                }
+            },
+            _ => return Box::pin(async { mk_response("Whoopsie".into()) }),
+        };
+        Box::pin(async { res })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn target_must_fit_the_probe() {
+        assert_eq!(parse_target_c("target_c=57").unwrap(), 57.0);
+        assert_eq!(parse_target_c("x=1&target_c=102.3").unwrap(), 102.3);
+        assert_eq!(parse_target_c("target_c=0").unwrap(), 0.0);
+        for query in ["", "target=57", "target_c=", "target_c=hot", "target_c=-1", "target_c=102.4", "target_c=nan", "target_c=inf"] {
+            assert!(parse_target_c(query).is_err(), "{}", query);
+        }
+    }
+
+    #[tokio::test]
+    async fn commands_need_a_connected_probe_with_room_in_its_queue() {
+        let svc = Svc::new();
+        let serial = ProbeSerial(1);
+        let mut commands = svc.add_probe(serial);
+        assert!(matches!(svc.command(ProbeSerial(2), Command::CancelPrediction).await, Err(CommandError::UnknownProbe(_))));
+        assert!(matches!(svc.command(serial, Command::CancelPrediction).await, Err(CommandError::Unavailable(_))));
+        assert!(commands.try_recv().is_err(), "nothing queued while disconnected");
+
+        svc.set_status(serial, SvcStatus::RUNNING);
+        let task = tokio::spawn({
+            let svc = svc.clone();
+            async move { svc.command(serial, Command::SetPrediction(57.0)).await }
+        });
+        let queued = commands.recv().await.unwrap();
+        assert!(matches!(queued.command, Command::SetPrediction(t) if t == 57.0));
+        assert!(!queued.expired());
+        queued.reply.send(Ok(())).unwrap();
+        task.await.unwrap().unwrap();
+
+        // Nobody taking them off the queue
+        for _ in 0..10 {
+            let svc = svc.clone();
+            tokio::spawn(async move { svc.command(serial, Command::CancelPrediction).await });
+        }
+        tokio::task::yield_now().await;
+        assert!(matches!(svc.command(serial, Command::CancelPrediction).await, Err(CommandError::Unavailable(_))));
+    }
+
+    #[test]
+    fn alerts_once_for_a_battery_hovering_at_low() {
+        let svc = Svc::new();
+        let serial = ProbeSerial(1);
+        let _commands = svc.add_probe(serial);
+        let start = Instant::now();
+        let at = |minutes: u64| start + Duration::from_secs(minutes * 60);
+        let set = |battery, minutes| svc.set_battery(serial, battery, at(minutes));
+
+        assert_eq!(set(BatteryStatus::Ok, 0), BatteryUpdate { changed: true, alert: false });
+        assert_eq!(set(BatteryStatus::Ok, 1), BatteryUpdate { changed: false, alert: false });
+        assert_eq!(set(BatteryStatus::Low, 2), BatteryUpdate { changed: true, alert: true });
+        // Flipping back and forth at the threshold
+        for minutes in 3..10 {
+            let battery = if minutes % 2 == 0 { BatteryStatus::Low } else { BatteryStatus::Ok };
+            assert_eq!(set(battery, minutes), BatteryUpdate { changed: true, alert: false });
+        }
+        // Ok for a good while, as after a charge
+        assert_eq!(set(BatteryStatus::Low, 9 + BATTERY_SETTLE.as_secs() / 60), BatteryUpdate { changed: true, alert: true });
+        assert_eq!(svc.probe_json(serial).unwrap()["battery"], "low");
+    }
+}